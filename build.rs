@@ -1,3 +1,5 @@
 fn main() {
-    println!("cargo:rustc-link-lib=framework=Vision");
+    if std::env::var("CARGO_CFG_TARGET_OS").unwrap() == "macos" {
+        println!("cargo:rustc-link-lib=framework=Vision");
+    }
 }
@@ -8,6 +8,7 @@ pkgs.mkShell {
     protobuf
     rustc
     rustfmt
+    tesseract
   ] ++ (with darwin.apple_sdk; [
     frameworks.AppKit
     frameworks.Vision
@@ -0,0 +1,255 @@
+use cocoa::appkit::{
+    NSApp, NSApplication, NSApplicationActivateIgnoringOtherApps,
+    NSApplicationActivationPolicyAccessory, NSBackingStoreBuffered, NSImage, NSMenu, NSMenuItem,
+    NSRunningApplication, NSSquareStatusItemLength, NSStatusBar, NSStatusItem, NSWindow,
+    NSWindowStyleMask,
+};
+use cocoa::base::{id, nil, NO};
+use cocoa::delegate;
+use cocoa::foundation::{NSAutoreleasePool, NSData, NSPoint, NSRect, NSSize, NSString};
+use core_graphics::access::ScreenCaptureAccess;
+
+use anyhow::Result;
+use objc::runtime::{Object, Sel};
+use std::cell::Cell;
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::capture::CaptureBackend;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::embedder::Embedder;
+use crate::objc_ffi::NSTextView;
+use crate::ocr::OcrEngine;
+use crate::types::{State, Status};
+use crate::worker::record_state_loop;
+
+/// How often the status item checks on the worker.
+const REFRESH_SECONDS: f64 = 2.0;
+
+extern "C" fn should_close(_: &Object, _: Sel, _: id) -> bool {
+    return false;
+}
+
+extern "C" fn close(this: &Object, _: Sel, _: id) {
+    unsafe {
+        // Am I bad a person for dropping the Arc? Yes I am.
+        let state_ptr = *this.get_ivar::<*mut c_void>("state") as *mut Mutex<State>;
+        let mut state = (*(state_ptr as *mut Mutex<State>)).lock().unwrap();
+        state.window_open = false;
+    }
+}
+
+extern "C" fn open(this: &Object, _: Sel, _: id) {
+    unsafe {
+        // Am I bad a person for dropping the Arc? Yes I am.
+        let state_ptr = *this.get_ivar::<*mut c_void>("state") as *mut Mutex<State>;
+        let mut state = (*(state_ptr as *mut Mutex<State>)).lock().unwrap();
+        let window_delegate: id = *this.get_ivar("window_delegate");
+        if !state.window_open {
+            open_window(&mut state, window_delegate);
+        }
+    }
+}
+
+/// What quitting needs to stop the worker.
+struct Worker {
+    cancel: CancellationToken,
+    /// Gives back the exit code once recording stops.
+    thread: Mutex<Option<JoinHandle<i32>>>,
+}
+
+extern "C" fn will_terminate(this: &Object, _: Sel, _: id) {
+    // The process ends as soon as this returns, so wait for the worker to write out what it has,
+    // and exit with however it got on rather than the 0 AppKit would.
+    unsafe {
+        let worker = &*(*this.get_ivar::<*mut c_void>("worker") as *const Worker);
+        worker.cancel.cancel();
+        let code = match worker.thread.lock().unwrap().take() {
+            Some(thread) => thread.join().unwrap_or(1),
+            None => 0,
+        };
+        std::process::exit(code);
+    }
+}
+
+/// The status bar button, and what it was last showing.
+struct StatusItem {
+    button: id,
+    shown: Cell<Option<Status>>,
+}
+
+extern "C" fn refresh(this: &Object, _: Sel, _: id) {
+    unsafe {
+        let state_ptr = *this.get_ivar::<*mut c_void>("state") as *mut Mutex<State>;
+        let health = (*state_ptr).lock().unwrap().health.clone();
+        let item = &*(*this.get_ivar::<*mut c_void>("status_item") as *const StatusItem);
+        if item.shown.replace(Some(health.status)) == Some(health.status) {
+            return;
+        }
+        // Dimmed whenever it isn't recording, and hovering over it says why
+        let _: () = msg_send![item.button, setAppearsDisabled: health.status != Status::Recording];
+        let summary = health.to_string();
+        let summary = summary.lines().next().unwrap_or_default();
+        let _: () = msg_send![
+            item.button,
+            setToolTip: NSString::alloc(nil).init_str(summary).autorelease()
+        ];
+    }
+}
+
+/// Returns the button, to keep in step with how recording's going.
+fn add_to_status_bar() -> id {
+    unsafe {
+        let menu = NSMenu::new(nil).autorelease();
+        let open = NSMenuItem::new(nil)
+            .initWithTitle_action_keyEquivalent_(
+                NSString::alloc(nil).init_str("Open"),
+                sel!(open:),
+                NSString::alloc(nil).init_str(""),
+            )
+            .autorelease();
+        menu.addItem_(open);
+        let quit = NSMenuItem::new(nil)
+            .initWithTitle_action_keyEquivalent_(
+                NSString::alloc(nil).init_str("Quit"),
+                sel!(terminate:),
+                NSString::alloc(nil).init_str(""),
+            )
+            .autorelease();
+        menu.addItem_(quit);
+
+        let icon = include_bytes!("icon.svg");
+        let icon_data =
+            NSData::dataWithBytes_length_(nil, icon.as_ptr() as *mut c_void, icon.len() as u64);
+        let icon_image = NSImage::initWithData_(NSImage::alloc(nil), icon_data);
+        let _r: bool = msg_send![icon_image, setTemplate: true];
+        let status_bar = NSStatusBar::systemStatusBar(nil);
+        let status_item = status_bar.statusItemWithLength_(NSSquareStatusItemLength);
+        let status_button = status_item.button();
+        cocoa::appkit::NSButton::setImage_(status_button, icon_image);
+        status_item.setMenu_(menu);
+        status_button
+    }
+}
+
+fn open_window(state: &mut State, window_delegate: id) {
+    unsafe {
+        let window = NSWindow::alloc(nil).initWithContentRect_styleMask_backing_defer_(
+            NSRect::new(NSPoint::new(0., 0.), NSSize::new(200., 200.)),
+            NSWindowStyleMask::NSTitledWindowMask
+                | NSWindowStyleMask::NSClosableWindowMask
+                | NSWindowStyleMask::NSMiniaturizableWindowMask,
+            NSBackingStoreBuffered,
+            NO,
+        );
+        window.cascadeTopLeftFromPoint_(NSPoint::new(20., 20.));
+        window.center();
+        let title = NSString::alloc(nil).init_str("Hello World!").autorelease();
+        window.setTitle_(title);
+        window.setDelegate_(window_delegate);
+
+        let text = format!("{} windows\n\n{}", state.windows.len(), state.health);
+        let content = NSTextView::alloc(nil).initWithFrame_(window.contentView().frame());
+        content.setEditable_(NO);
+        content.setString_(NSString::alloc(nil).init_str(&text).autorelease());
+        window.setContentView_(content);
+
+        window.makeKeyAndOrderFront_(nil);
+
+        let current_app = NSRunningApplication::currentApplication(nil);
+        current_app.activateWithOptions_(NSApplicationActivateIgnoringOtherApps);
+        state.window_open = true;
+    }
+}
+
+pub fn run(
+    state: Arc<Mutex<State>>,
+    data_dir: DataDir,
+    config: watch::Receiver<Config>,
+    new_backend: fn() -> Result<Box<dyn CaptureBackend>>,
+    embedder: Box<dyn Embedder>,
+    ocr: Option<Box<dyn OcrEngine>>,
+    cancel: CancellationToken,
+) {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+
+        if ScreenCaptureAccess::default().preflight() == false {
+            println!("Don't have permission to take screenshots, exiting");
+            return;
+        }
+
+        let cloned = Arc::clone(&state);
+        let worker_cancel = cancel.clone();
+        let thread = thread::spawn(move || {
+            let code = match record_state_loop(
+                cloned,
+                data_dir,
+                config,
+                new_backend,
+                embedder,
+                ocr,
+                worker_cancel,
+            ) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    1
+                }
+            };
+            // Recording is the point, so there's nothing to stick around for once it stops.
+            // Quitting goes through the main thread, where `will_terminate` picks up the code.
+            let _: () = msg_send![
+                NSApp(),
+                performSelectorOnMainThread: sel!(terminate:)
+                withObject: nil
+                waitUntilDone: NO
+            ];
+            code
+        });
+        // Lives as long as the app does
+        let worker = Box::into_raw(Box::new(Worker {
+            cancel,
+            thread: Mutex::new(Some(thread)),
+        }));
+
+        let window_delegate = delegate!("WindowDelegate", {
+            state: *mut c_void = Arc::<Mutex<State>>::as_ptr(&state) as *const c_void,
+            (windowWillClose:) => close as extern "C" fn(&Object, Sel, id)
+        });
+
+        // Also lives as long as the app does
+        let status_item = Box::into_raw(Box::new(StatusItem {
+            button: add_to_status_bar(),
+            shown: Cell::new(None),
+        }));
+
+        let app = NSApp();
+        app.setActivationPolicy_(NSApplicationActivationPolicyAccessory);
+        let app_delegate = delegate!("AppDelegate", {
+            state: *mut c_void = Arc::<Mutex<State>>::as_ptr(&state) as *const c_void,
+            window_delegate: id = window_delegate,
+            worker: *mut c_void = worker as *mut c_void,
+            status_item: *mut c_void = status_item as *mut c_void,
+            (open:) => open as extern "C" fn(&Object, Sel, id),
+            (refresh:) => refresh as extern "C" fn(&Object, Sel, id),
+            (applicationShouldTerminateAfterLastWindowClosed:) => should_close as extern "C" fn(&Object, Sel, id) -> bool,
+            (applicationWillTerminate:) => will_terminate as extern "C" fn(&Object, Sel, id)
+        });
+        app.setDelegate_(app_delegate);
+        let _: id = msg_send![
+            class!(NSTimer),
+            scheduledTimerWithTimeInterval: REFRESH_SECONDS
+            target: app_delegate
+            selector: sel!(refresh:)
+            userInfo: nil
+            repeats: true
+        ];
+        app.run();
+        pool.drain();
+    }
+}
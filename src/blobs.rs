@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use arrow_array::StringArray;
+use futures::TryStreamExt;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::{self, Cipher};
+use crate::data_dir::DataDir;
+use crate::frames;
+use crate::queue;
+
+/// Where blobs live, relative to the data directory.
+pub const BLOBS: &str = "blobs";
+const EXTENSION: &str = "jpg";
+
+/// Screenshots filed under the BLAKE3 hash of their bytes, so identical ones are only kept once.
+/// Each is sharded by the first two bytes of its hash:
+///
+/// ```text
+/// blobs/3f/a2/3fa2...e1.jpg
+/// ```
+///
+/// Frames refer to blobs through their `image_hash` column, and anything no frame refers to can
+/// be removed with `gc`, though not while they're being stored alongside what refers to them. With
+/// a cipher, blobs are sealed on the way in and opened on the way out, though they're still named
+/// after the hash of what's inside.
+pub struct BlobStore {
+    /// The data directory, since paths handed out are relative to it.
+    root: PathBuf,
+    cipher: Option<Cipher>,
+    claims: Claims,
+}
+
+/// Hashes of blobs stored since `gc` started taking stock, which it keeps since it can't have
+/// seen what refers to them. `None` unless it's taking stock. Locked while storing a blob along
+/// with its reference, and while collecting, so the two never interleave.
+pub type Claims = Arc<Mutex<Option<HashSet<String>>>>;
+
+/// What `gc` found.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Blobs still referenced, and by how many frames altogether.
+    pub kept: usize,
+    pub references: usize,
+    pub removed: usize,
+    pub removed_bytes: u64,
+}
+
+impl BlobStore {
+    pub fn new(root: PathBuf, cipher: Option<Cipher>) -> Self {
+        Self::sharing(root, cipher, Claims::default())
+    }
+
+    /// Like `new`, but aware of blobs being stored by the other stores sharing `claims`.
+    pub fn sharing(root: PathBuf, cipher: Option<Cipher>, claims: Claims) -> Self {
+        Self {
+            root,
+            cipher,
+            claims,
+        }
+    }
+
+    /// The 64 character hex hash `bytes` would be stored under.
+    pub fn hash(bytes: &[u8]) -> String {
+        blake3::hash(bytes).to_hex().to_string()
+    }
+
+    /// Where the blob with `hash` is kept, relative to the data directory.
+    pub fn path(hash: &str) -> PathBuf {
+        Path::new(BLOBS)
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(format!("{}.{}", hash, EXTENSION))
+    }
+
+    /// Stores `bytes` unless they're already there, returning their hash.
+    pub fn put(&self, bytes: &[u8]) -> Result<String> {
+        let hash = Self::hash(bytes);
+        let path = self.root.join(Self::path(&hash));
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            // It may have been stored before encryption was turned on
+            let sealed = Cipher::sealed_len(bytes.len()) as u64;
+            if metadata.len() != bytes.len() as u64 && metadata.len() != sealed {
+                return Err(anyhow!(
+                    "{} is {} bytes but should be {}, it may be corrupt",
+                    path.display(),
+                    metadata.len(),
+                    bytes.len()
+                ));
+            }
+            return Ok(hash);
+        }
+
+        match &self.cipher {
+            Some(cipher) => self.write(&hash, &cipher.seal(bytes))?,
+            None => self.write(&hash, bytes)?,
+        }
+        Ok(hash)
+    }
+
+    /// Like `put`, but has `reference` record what refers to the blob first, so `gc` never finds
+    /// it without one, even part way through.
+    pub fn put_referenced(
+        &self,
+        bytes: &[u8],
+        reference: impl FnOnce() -> Result<()>,
+    ) -> Result<String> {
+        let mut claims = self.claims.lock().unwrap();
+        if let Some(claimed) = claims.as_mut() {
+            claimed.insert(Self::hash(bytes));
+        }
+        reference()?;
+        self.put(bytes)
+    }
+
+    pub fn get(&self, hash: &str) -> Result<Vec<u8>> {
+        let path = self.root.join(Self::path(hash));
+        let bytes = std::fs::read(&path)
+            .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+        if !crypto::is_sealed(&bytes) {
+            return Ok(bytes);
+        }
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} is encrypted but wasn't unlocked", path.display()))?;
+        cipher
+            .open(&bytes)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    /// Seals the blob with `hash` under this store's cipher, whether it's in the clear or under
+    /// `previous`. Returns whether anything needed doing.
+    pub fn reseal(&self, hash: &str, previous: Option<&Cipher>) -> Result<bool> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| anyhow!("Resealing needs a cipher"))?;
+        let path = self.root.join(Self::path(hash));
+        let bytes = std::fs::read(&path)?;
+        let plaintext = match (crypto::is_sealed(&bytes), previous) {
+            (false, _) => bytes,
+            (true, _) if cipher.open(&bytes).is_ok() => return Ok(false),
+            (true, Some(previous)) => previous
+                .open(&bytes)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+            (true, None) => return Err(anyhow!("{} is under an unknown key", path.display())),
+        };
+        self.write(hash, &cipher.seal(&plaintext))?;
+        Ok(true)
+    }
+
+    /// Written to a temporary file first, so a crash never leaves half of one behind.
+    fn write(&self, hash: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.root.join(Self::path(hash));
+        let directory = path.parent().expect("blob paths are sharded");
+        std::fs::create_dir_all(directory)?;
+        let temporary = directory.join(format!(".{}.{}.tmp", hash, std::process::id()));
+        std::fs::write(&temporary, bytes)?;
+        std::fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+
+    /// From now until `gc`, has the stores sharing this one's claims note what they store.
+    pub fn take_stock(&self) {
+        self.claims.lock().unwrap().get_or_insert_with(HashSet::new);
+    }
+
+    /// Removes every blob that isn't in `references`, which maps hashes to how many frames use
+    /// them, along with leftover temporary files and empty shards. Blobs stored since
+    /// `take_stock` are kept too, since `references` can be missing them.
+    pub fn gc(&self, references: &HashMap<String, usize>, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        let mut claims = self.claims.lock().unwrap();
+        let claimed = claims.take().unwrap_or_default();
+        let blobs = self.root.join(BLOBS);
+        if !blobs.exists() {
+            return Ok(report);
+        }
+        for first in std::fs::read_dir(&blobs)? {
+            let first = first?.path();
+            if !first.is_dir() {
+                continue;
+            }
+            for second in std::fs::read_dir(&first)? {
+                let second = second?.path();
+                if !second.is_dir() {
+                    continue;
+                }
+                for entry in std::fs::read_dir(&second)? {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    let hash = name.strip_suffix(&format!(".{}", EXTENSION));
+                    match hash.map(|hash| (references.get(hash), claimed.contains(hash))) {
+                        Some((Some(count), _)) => {
+                            report.kept += 1;
+                            report.references += count;
+                        }
+                        Some((None, true)) => report.kept += 1,
+                        _ => {
+                            report.removed += 1;
+                            report.removed_bytes += entry.metadata()?.len();
+                            if !dry_run {
+                                std::fs::remove_file(entry.path())?;
+                            }
+                        }
+                    }
+                }
+                if !dry_run {
+                    // Only succeeds when the shard is empty
+                    let _ = std::fs::remove_dir(&second);
+                }
+            }
+            if !dry_run {
+                let _ = std::fs::remove_dir(&first);
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Counts how many frames in `table`, or waiting to go in it, use each blob, and takes stock so
+/// `gc` keeps any stored meanwhile.
+pub async fn references(
+    table: &lancedb::Table,
+    data_dir: &DataDir,
+) -> Result<HashMap<String, usize>> {
+    data_dir.blob_store().take_stock();
+    // Frames only leave the queue once they're in the table, so it's read first
+    let mut references = HashMap::new();
+    for hash in queue::image_hashes(data_dir)? {
+        *references.entry(hash).or_insert(0) += 1;
+    }
+    let mut stream = table
+        .query()
+        .select(&["image_hash"])
+        .execute_stream()
+        .await?;
+    while let Some(batch) = stream.try_next().await? {
+        let hashes = batch
+            .column_by_name("image_hash")
+            .and_then(|c| c.as_any().downcast_ref::<StringArray>())
+            .ok_or_else(|| anyhow!("{} is missing the image_hash column", frames::TABLE))?;
+        for hash in hashes.iter().flatten() {
+            *references.entry(hash.to_string()).or_insert(0) += 1;
+        }
+    }
+    Ok(references)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_each_image_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::new(dir.path().to_path_buf(), None);
+        let hash = store.put(b"screenshot").unwrap();
+        assert_eq!(store.put(b"screenshot").unwrap(), hash);
+        assert_eq!(store.get(&hash).unwrap(), b"screenshot");
+        assert!(dir.path().join(BlobStore::path(&hash)).is_file());
+    }
+
+    #[test]
+    fn collects_whatever_nothing_refers_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::new(dir.path().to_path_buf(), None);
+        let kept = store.put(b"kept").unwrap();
+        let unused = store.put(b"unused").unwrap();
+        let unused_path = dir.path().join(BlobStore::path(&unused));
+        // Left behind by a write that never finished
+        let temporary = unused_path.with_file_name(format!(".{}.1.tmp", unused));
+        std::fs::write(&temporary, b"partial").unwrap();
+        let references = HashMap::from([(kept.clone(), 2)]);
+
+        let report = store.gc(&references, true).unwrap();
+        assert_eq!((report.kept, report.references, report.removed), (1, 2, 2));
+        assert_eq!(report.removed_bytes, 6 + 7);
+        assert!(unused_path.exists() && temporary.exists());
+
+        store.gc(&references, false).unwrap();
+        assert!(!unused_path.exists() && !temporary.exists());
+        // Along with the shards they were in, unless they share one with a blob that's kept
+        if unused[0..2] != kept[0..2] {
+            assert!(!dir.path().join(BLOBS).join(&unused[0..2]).exists());
+        }
+        assert_eq!(store.get(&kept).unwrap(), b"kept");
+    }
+}
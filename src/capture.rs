@@ -0,0 +1,65 @@
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use metrohash::MetroHash64;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use crate::replay::ReplayBackend;
+use crate::types::Window;
+
+const JPEG_QUALITY: u8 = 85;
+
+/// Points at a directory of recorded frames to replay instead of capturing the screen.
+const REPLAY_ENV: &str = "ELEPHANT_REPLAY";
+
+/// Something that can enumerate the visible windows and screenshot them.
+pub trait CaptureBackend: Send {
+    /// Returns every capturable window, with larger `z` values being closer to the front.
+    fn get_windows(&mut self) -> Result<Vec<Window>>;
+
+    /// Whether there's nothing left to capture. Live backends never run out.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+
+    /// Whether ticks should be spaced out in real time rather than run back to back.
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+pub fn backend_from_env() -> Result<Box<dyn CaptureBackend>> {
+    match std::env::var_os(REPLAY_ENV) {
+        Some(directory) => Ok(Box::new(ReplayBackend::open(Path::new(&directory))?)),
+        None => default_backend(),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn default_backend() -> Result<Box<dyn CaptureBackend>> {
+    Ok(Box::new(crate::screenshots::CoreGraphicsBackend))
+}
+
+#[cfg(target_os = "linux")]
+pub fn default_backend() -> Result<Box<dyn CaptureBackend>> {
+    Ok(Box::new(crate::x11::X11Backend::connect()?))
+}
+
+pub fn jpeg_metrohash(jpeg: &[u8]) -> u64 {
+    let mut hasher = MetroHash64::new();
+    jpeg.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Encodes tightly packed RGB8 pixels as a JPEG.
+pub fn rgb_to_jpeg(rgb: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode(
+        rgb,
+        width,
+        height,
+        ColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
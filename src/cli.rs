@@ -0,0 +1,596 @@
+use anyhow::{anyhow, Result};
+use arrow_array::{Array, RecordBatch, StringArray};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::TryStreamExt;
+use std::collections::BTreeSet;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::blobs;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::embedder::embedder_from_config;
+use crate::frames;
+use crate::fts::TextIndex;
+use crate::migrations;
+use crate::queue::Queue;
+use crate::retention;
+use crate::rotation;
+use crate::search::{self, SearchOptions};
+use crate::vector_index;
+use crate::worker;
+
+/// Records what's on screen so it can be searched later. Runs the recorder when no command is
+/// given.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Searches recorded frames by what they look like and the text in them
+    Search(SearchArgs),
+    /// Upgrades the database to the current schema. Recording does this automatically.
+    Migrate(MigrateArgs),
+    /// Deletes screenshots no frame refers to any more. Can't run while recording.
+    Gc(GcArgs),
+    /// Applies the retention settings now, rather than waiting for the recorder to. Can't run
+    /// while recording.
+    Prune(PruneArgs),
+    /// Keeps frames from ever being shrunk or deleted by retention. Can't run while recording.
+    Pin(PinArgs),
+    /// Shows how much of the database the vector index covers, and builds it if it's due and
+    /// nothing's recording (which keeps the index up to date itself)
+    Index(IndexArgs),
+    /// Writes out a frame's screenshot, decrypting it if need be
+    Show(ShowArgs),
+    /// Shows how many frames are waiting to be recorded, and for how long
+    Queue,
+    /// Re-encrypts everything under a new key from wherever `encryption.key` says, encrypting
+    /// whatever wasn't yet. Can't run while recording.
+    RotateKey,
+}
+
+#[derive(Args)]
+pub struct MigrateArgs {
+    /// Show what would change without touching anything
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct GcArgs {
+    /// Show what would be deleted without deleting it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct PruneArgs {
+    /// Show what would change without touching anything
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct PinArgs {
+    /// Frame ids, as shown by search
+    #[arg(required = true)]
+    frame_ids: Vec<u64>,
+
+    /// Let retention have them again
+    #[arg(long)]
+    unpin: bool,
+}
+
+#[derive(Args)]
+pub struct IndexArgs {
+    /// Build the index now, even if there are fewer frames than `index.min_rows`. Can't run
+    /// while recording.
+    #[arg(long)]
+    rebuild: bool,
+}
+
+#[derive(Args)]
+pub struct ShowArgs {
+    /// As shown by search
+    frame_id: u64,
+
+    /// Where to write the JPEG, rather than standard output
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SearchArgs {
+    /// Words, "quoted phrases" and prefixes like `Connect*`
+    #[arg(required = true)]
+    query: Vec<String>,
+
+    /// How many results to show
+    #[arg(short = 'k', long, default_value_t = 10)]
+    top_k: usize,
+
+    #[arg(long, value_enum, default_value_t = Mode::Hybrid)]
+    mode: Mode,
+
+    /// Only show windows belonging to this application
+    #[arg(long)]
+    app: Option<String>,
+
+    /// Only show windows whose title contains this
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Only show frames captured at or after this local time, like `2024-03-01` or
+    /// `2024-03-01 14:30`
+    #[arg(long, value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only show frames captured before this local time
+    #[arg(long, value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+
+    /// Only show frames matching this SQL predicate over the screenshots table
+    #[arg(long = "where", value_name = "PREDICATE")]
+    filter: Option<String>,
+
+    /// IVF partitions to search, instead of `index.nprobes`
+    #[arg(long)]
+    nprobes: Option<usize>,
+
+    /// Re-rank this many times the candidates by exact distance, instead of
+    /// `index.refine_factor`. 0 turns it off.
+    #[arg(long)]
+    refine_factor: Option<u32>,
+
+    /// Print results as a JSON array instead
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    /// Both, fused by rank
+    Hybrid,
+    /// Only how closely the screenshot matches the query
+    Vector,
+    /// Only the OCR text
+    Text,
+}
+
+#[tokio::main]
+pub async fn search(args: SearchArgs, data_dir: DataDir, config: Config) -> Result<()> {
+    let data_dir = data_dir.unlock()?;
+    let embedder = embedder_from_config(&config.embedder)?;
+    let db = lancedb::connect(&data_dir.database_uri()?)
+        .execute()
+        .await?;
+    let table = db.open_table(frames::TABLE).execute().await.map_err(|e| {
+        anyhow!(
+            "Unable to open {}, has anything been recorded yet? {}",
+            data_dir.database().display(),
+            e
+        )
+    })?;
+    worker::check_embedder(&table, embedder.as_ref()).await?;
+    let text_index = TextIndex::open_existing(&data_dir.text_index(), data_dir.cipher())?;
+
+    let (vector_weight, text_weight) = match args.mode {
+        Mode::Hybrid => (1.0, 1.0),
+        Mode::Vector => (1.0, 0.0),
+        Mode::Text => (0.0, 1.0),
+    };
+    let options = SearchOptions {
+        limit: args.top_k,
+        vector_weight,
+        text_weight,
+        filter: filter(&args, data_dir.cipher().is_some()),
+        // Sealed titles can only be checked once they're opened
+        title: args.title.clone().filter(|_| data_dir.cipher().is_some()),
+        nprobes: args.nprobes.unwrap_or(config.index.nprobes),
+        refine_factor: args.refine_factor.unwrap_or(config.index.refine_factor),
+    };
+    let mut hits = search::search(
+        &table,
+        &text_index,
+        embedder.as_ref(),
+        data_dir.cipher(),
+        &args.query.join(" "),
+        &options,
+    )
+    .await?;
+    // Retention leaves an empty path once a screenshot is gone
+    for hit in hits.iter_mut().filter(|hit| !hit.image_path.is_empty()) {
+        hit.image_path = data_dir
+            .resolve(&hit.image_path)
+            .to_string_lossy()
+            .into_owned();
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+        return Ok(());
+    }
+
+    let (before, after) = if std::io::stdout().is_terminal() {
+        ("\x1b[1m", "\x1b[0m")
+    } else {
+        ("[", "]")
+    };
+    for (i, hit) in hits.iter().enumerate() {
+        let mut reasons = Vec::new();
+        if let Some(vector) = &hit.vector {
+            reasons.push(format!(
+                "vector #{} distance {:.4}",
+                vector.rank + 1,
+                vector.distance
+            ));
+        }
+        if let Some(text) = &hit.text {
+            reasons.push(format!("text #{} bm25 {:.2}", text.rank + 1, text.score));
+        }
+        println!(
+            "{:>3}. {}  {}{}",
+            i + 1,
+            hit.captured_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            hit.app
+                .as_ref()
+                .map(|app| format!("{} - ", app))
+                .unwrap_or_default(),
+            hit.title
+        );
+        println!(
+            "     frame {} score {:.4} ({})",
+            hit.frame_id,
+            hit.score,
+            reasons.join(", ")
+        );
+        match (hit.image_path.is_empty(), data_dir.cipher().is_some()) {
+            (true, _) => println!("     (screenshot no longer kept)"),
+            (false, true) => println!("     elephant show {} -o frame.jpg", hit.frame_id),
+            (false, false) => println!("     {}", hit.image_path),
+        }
+        if let Some(text) = &hit.text {
+            if !text.snippet.fragment.is_empty() {
+                println!(
+                    "     {}",
+                    text.snippet.highlighted(before, after).replace('\n', " ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn migrate(args: MigrateArgs, data_dir: DataDir) -> Result<()> {
+    // Screenshots copied into the blob store get sealed on the way
+    let data_dir = data_dir.unlock()?;
+    let db = lancedb::connect(&data_dir.database_uri()?)
+        .execute()
+        .await?;
+    let (_lock, mut text_writer) = match args.dry_run {
+        true => (None, None),
+        false => (
+            Some(data_dir.lock()?),
+            Some(TextIndex::open(&data_dir.text_index(), data_dir.cipher())?.writer()?),
+        ),
+    };
+    let plan = migrations::migrate(&db, &data_dir, text_writer.as_mut(), args.dry_run).await?;
+    match plan {
+        Some(plan) if args.dry_run => print!("Would migrate {}", plan),
+        Some(plan) => print!("Migrated {}", plan),
+        None => println!("{} is already up to date", data_dir.database().display()),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn gc(args: GcArgs, data_dir: DataDir) -> Result<()> {
+    // Recording adds blobs before the frames that use them, so it can't be going on at the same
+    // time
+    let _lock = data_dir.lock()?;
+    let table = open_current_table(&data_dir).await?;
+    let references = blobs::references(&table, &data_dir).await?;
+    let report = data_dir.blob_store().gc(&references, args.dry_run)?;
+    println!(
+        "{} {} unreferenced blobs ({:.1} MB), kept {} used by {} frames",
+        if args.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        },
+        report.removed,
+        report.removed_bytes as f64 / 1_000_000.0,
+        report.kept,
+        report.references
+    );
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn prune(args: PruneArgs, data_dir: DataDir, config: Config) -> Result<()> {
+    // Shrinking screenshots means opening them
+    let data_dir = data_dir.unlock()?;
+    let (_lock, mut text_writer) = match args.dry_run {
+        true => (None, None),
+        false => (
+            Some(data_dir.lock()?),
+            Some(TextIndex::open(&data_dir.text_index(), data_dir.cipher())?.writer()?),
+        ),
+    };
+    let table = open_current_table(&data_dir).await?;
+    let report = retention::apply(
+        &table,
+        &data_dir,
+        text_writer.as_mut(),
+        &config.retention,
+        args.dry_run,
+    )
+    .await?;
+    match args.dry_run {
+        // Eviction depends on how much space the earlier steps free up, so it can't be predicted
+        true => println!(
+            "Would shrink {} screenshots and drop {}, {:.1} MB in use",
+            report.thumbnailed,
+            report.dropped,
+            report.usage as f64 / 1_000_000.0
+        ),
+        false => println!("{}", report),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn pin(args: PinArgs, data_dir: DataDir) -> Result<()> {
+    // Retention could be rewriting the same rows
+    let _lock = data_dir.lock()?;
+    let table = open_current_table(&data_dir).await?;
+    // Each frame is only found once, however many times it's given
+    let ids: BTreeSet<u64> = args.frame_ids.iter().copied().collect();
+    let list: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    let predicate = format!("frame_id IN ({})", list.join(", "));
+    let found = table.count_rows(Some(predicate.clone())).await?;
+    if found < ids.len() {
+        return Err(anyhow!("Only found {} of the {} frames", found, ids.len()));
+    }
+    frames::update(&table, &predicate, &[("pinned", (!args.unpin).to_string())]).await?;
+    println!(
+        "{} {} frames",
+        if args.unpin { "Unpinned" } else { "Pinned" },
+        found
+    );
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn index(args: IndexArgs, data_dir: DataDir, config: Config) -> Result<()> {
+    let lock = data_dir.lock();
+    let table = open_current_table(&data_dir).await?;
+    let before = vector_index::status(&table).await?;
+    let built = match (args.rebuild, lock) {
+        (true, lock) => {
+            let _lock = lock?;
+            vector_index::build(&table, &config.index, before.rows).await?;
+            true
+        }
+        (false, Ok(_lock)) => vector_index::maintain(&table, &config.index).await?,
+        // The recorder builds it when it's due
+        (false, Err(_)) => false,
+    };
+    match built {
+        true => println!("Built the index: {}", vector_index::status(&table).await?),
+        false => println!("{}", before),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn show(args: ShowArgs, data_dir: DataDir) -> Result<()> {
+    let data_dir = data_dir.unlock()?;
+    let table = open_current_table(&data_dir).await?;
+    let batches: Vec<RecordBatch> = table
+        .query()
+        .filter(format!("frame_id = {}", args.frame_id))
+        .select(&["image_hash"])
+        .execute_stream()
+        .await?
+        .try_collect()
+        .await?;
+    let hashes = batches
+        .iter()
+        .find(|batch| batch.num_rows() > 0)
+        .ok_or_else(|| anyhow!("There's no frame {}", args.frame_id))?
+        .column_by_name("image_hash")
+        .and_then(|c| c.as_any().downcast_ref::<StringArray>())
+        .ok_or_else(|| anyhow!("{} is missing the image_hash column", frames::TABLE))?;
+    if hashes.is_null(0) {
+        return Err(anyhow!(
+            "Frame {}'s screenshot is no longer kept",
+            args.frame_id
+        ));
+    }
+    let jpeg = data_dir.blob_store().get(hashes.value(0))?;
+    match args.output {
+        Some(output) => std::fs::write(&output, jpeg)
+            .map_err(|e| anyhow!("Unable to write {}: {}", output.display(), e))?,
+        None if std::io::stdout().is_terminal() => {
+            return Err(anyhow!("Not writing a JPEG to a terminal, pass --output"));
+        }
+        None => std::io::stdout().write_all(&jpeg)?,
+    }
+    Ok(())
+}
+
+pub fn queue(data_dir: DataDir) -> Result<()> {
+    println!("{}", Queue::open(&data_dir)?.stats());
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn rotate_key(data_dir: DataDir, config: Config) -> Result<()> {
+    let source = config
+        .encryption
+        .map(|e| e.key)
+        .ok_or_else(|| anyhow!("Set encryption.key in the config to say where the new key goes"))?;
+    let _lock = data_dir.lock()?;
+    let table = open_current_table(&data_dir).await?;
+    let db = lancedb::connect(&data_dir.database_uri()?)
+        .execute()
+        .await?;
+    println!(
+        "{}",
+        rotation::rotate(&db, &table, &data_dir, source).await?
+    );
+    Ok(())
+}
+
+/// Opens the `screenshots` table, as long as it's already been migrated to the current schema.
+async fn open_current_table(data_dir: &DataDir) -> Result<lancedb::Table> {
+    let db = lancedb::connect(&data_dir.database_uri()?)
+        .execute()
+        .await?;
+    if migrations::migrate(&db, data_dir, None, true)
+        .await?
+        .is_some()
+    {
+        return Err(anyhow!(
+            "The database needs upgrading first, run elephant migrate"
+        ));
+    }
+    db.open_table(frames::TABLE).execute().await.map_err(|e| {
+        anyhow!(
+            "Unable to open {}, has anything been recorded yet? {}",
+            data_dir.database().display(),
+            e
+        )
+    })
+}
+
+/// Turns the filtering flags into one SQL predicate. Titles can't be matched in SQL once they're
+/// sealed.
+fn filter(args: &SearchArgs, sealed: bool) -> Option<String> {
+    let mut predicates = Vec::new();
+    if let Some(app) = &args.app {
+        predicates.push(format!("app = {}", frames::quote(app)));
+    }
+    if let Some(title) = args.title.as_ref().filter(|_| !sealed) {
+        predicates.push(format!(
+            "title LIKE {}",
+            frames::quote(&format!("%{}%", title))
+        ));
+    }
+    if let Some(since) = args.since {
+        predicates.push(format!("captured_at >= {}", frames::timestamp(since)));
+    }
+    if let Some(until) = args.until {
+        predicates.push(format!("captured_at < {}", frames::timestamp(until)));
+    }
+    if let Some(filter) = &args.filter {
+        predicates.push(format!("({})", filter));
+    }
+    (!predicates.is_empty()).then(|| predicates.join(" AND "))
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    let naive = match NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+    {
+        Ok(naive) => naive,
+        Err(_) => NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map_err(|_| anyhow!("Expected a time like 2024-03-01 or 2024-03-01 14:30"))?
+            .and_time(NaiveTime::MIN),
+    };
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("{} doesn't exist in the local timezone", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search_args(args: &[&str]) -> SearchArgs {
+        let cli =
+            Cli::try_parse_from(["elephant", "search", "invoice"].iter().chain(args)).unwrap();
+        match cli.command {
+            Some(Command::Search(args)) => args,
+            _ => panic!("Expected a search"),
+        }
+    }
+
+    fn local(date: (i32, u32, u32), time: (u32, u32, u32)) -> DateTime<Utc> {
+        let naive = NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(time.0, time.1, time.2)
+            .unwrap();
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_local_dates_and_times() {
+        assert_eq!(
+            parse_time("2024-03-01").unwrap(),
+            local((2024, 3, 1), (0, 0, 0))
+        );
+        assert_eq!(
+            parse_time("2024-03-01 14:30").unwrap(),
+            local((2024, 3, 1), (14, 30, 0))
+        );
+        assert_eq!(
+            parse_time("2024-03-01 14:30:15").unwrap(),
+            local((2024, 3, 1), (14, 30, 15))
+        );
+        for bad in ["yesterday", "2024-02-30", "2024-03-01T14:30", "14:30"] {
+            assert!(parse_time(bad).is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn combines_the_flags_into_one_predicate() {
+        assert_eq!(filter(&search_args(&[]), false), None);
+
+        let args = search_args(&[
+            "--app",
+            "Mail",
+            "--title",
+            "O'Brien",
+            "--since",
+            "2024-03-01",
+            "--until",
+            "2024-03-02",
+            "--where",
+            "z = 0 OR pinned",
+        ]);
+        assert_eq!(
+            filter(&args, false).unwrap(),
+            format!(
+                "app = 'Mail' AND title LIKE '%O''Brien%' AND captured_at >= {} \
+                    AND captured_at < {} AND (z = 0 OR pinned)",
+                frames::timestamp(local((2024, 3, 1), (0, 0, 0))),
+                frames::timestamp(local((2024, 3, 2), (0, 0, 0)))
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_sealed_titles_out_of_the_predicate() {
+        let args = search_args(&["--title", "Budget"]);
+        assert_eq!(filter(&args, true), None);
+        let args = search_args(&["--title", "Budget", "--app", "Numbers"]);
+        assert_eq!(filter(&args, true).unwrap(), "app = 'Numbers'");
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use image::imageops::FilterType;
+use ndarray::{Array2, Array4};
+use ort::session::{Session, SessionInputValue, SessionOutputs};
+use ort::value::{Tensor, ValueType};
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+
+use crate::embedder::Embedder;
+
+const VISUAL_MODEL: &str = "visual.onnx";
+const TEXTUAL_MODEL: &str = "textual.onnx";
+const TOKENIZER: &str = "tokenizer.json";
+
+/// Which family the exported model belongs to, since they disagree on preprocessing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipVariant {
+    Clip,
+    Siglip,
+}
+
+impl ClipVariant {
+    fn mean(&self) -> [f32; 3] {
+        match self {
+            ClipVariant::Clip => [0.48145466, 0.4578275, 0.40821073],
+            ClipVariant::Siglip => [0.5, 0.5, 0.5],
+        }
+    }
+
+    fn std(&self) -> [f32; 3] {
+        match self {
+            ClipVariant::Clip => [0.26862955, 0.2613026, 0.2757771],
+            ClipVariant::Siglip => [0.5, 0.5, 0.5],
+        }
+    }
+
+    fn default_context_length(&self) -> usize {
+        match self {
+            ClipVariant::Clip => 77,
+            ClipVariant::Siglip => 64,
+        }
+    }
+}
+
+impl std::str::FromStr for ClipVariant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "clip" => Ok(ClipVariant::Clip),
+            "siglip" => Ok(ClipVariant::Siglip),
+            _ => Err(anyhow!("Unknown CLIP variant {}", s)),
+        }
+    }
+}
+
+/// Runs a CLIP-family model on the CPU so nothing ever leaves the machine.
+///
+/// Expects a directory holding the image tower as `visual.onnx`, the text tower as
+/// `textual.onnx` and the HuggingFace `tokenizer.json` that goes with them.
+pub struct ClipEmbedder {
+    visual: Arc<Mutex<Session>>,
+    textual: Arc<Mutex<Session>>,
+    tokenizer: Arc<Tokenizer>,
+    variant: ClipVariant,
+    image_size: u32,
+    context_length: usize,
+    dimension: usize,
+    model_id: String,
+    /// The outputs holding the embeddings.
+    image_output: String,
+    text_output: String,
+}
+
+impl ClipEmbedder {
+    pub fn open(directory: &Path, variant: ClipVariant) -> Result<Self> {
+        let visual = Session::builder()?.commit_from_file(directory.join(VISUAL_MODEL))?;
+        let textual = Session::builder()?.commit_from_file(directory.join(TEXTUAL_MODEL))?;
+        let tokenizer = Tokenizer::from_file(directory.join(TOKENIZER)).map_err(|e| anyhow!(e))?;
+
+        let image_size = match last_dimension(&visual.inputs[0].input_type) {
+            Some(size) => size as u32,
+            None => 224,
+        };
+        let context_length = last_dimension(&textual.inputs[0].input_type)
+            .unwrap_or(variant.default_context_length());
+        let image_output = embedding_output(&visual, "image_embeds", VISUAL_MODEL)?;
+        let text_output = embedding_output(&textual, "text_embeds", TEXTUAL_MODEL)?;
+        let dimension = visual
+            .outputs
+            .iter()
+            .find(|o| o.name == image_output)
+            .and_then(|o| last_dimension(&o.output_type))
+            .ok_or_else(|| anyhow!("Unable to tell the embedding size of {}", VISUAL_MODEL))?;
+        let model_id = model_id(directory)?;
+
+        Ok(Self {
+            visual: Arc::new(Mutex::new(visual)),
+            textual: Arc::new(Mutex::new(textual)),
+            tokenizer: Arc::new(tokenizer),
+            variant,
+            image_size,
+            context_length,
+            dimension,
+            model_id,
+            image_output,
+            text_output,
+        })
+    }
+
+    fn preprocess_image(&self, jpeg: &[u8]) -> Result<Array4<f32>> {
+        let image = image::load_from_memory(jpeg)?;
+        let size = self.image_size;
+        let image = match self.variant {
+            // CLIP resizes the short side and center crops, SigLIP just squashes
+            ClipVariant::Clip => {
+                let scale = size as f32 / image.width().min(image.height()) as f32;
+                let width = ((image.width() as f32 * scale).round() as u32).max(size);
+                let height = ((image.height() as f32 * scale).round() as u32).max(size);
+                image
+                    .resize_exact(width, height, FilterType::CatmullRom)
+                    .crop_imm((width - size) / 2, (height - size) / 2, size, size)
+            }
+            ClipVariant::Siglip => image.resize_exact(size, size, FilterType::CatmullRom),
+        }
+        .to_rgb8();
+
+        let (mean, std) = (self.variant.mean(), self.variant.std());
+        let mut pixels = Array4::zeros((1, 3, size as usize, size as usize));
+        for (x, y, pixel) in image.enumerate_pixels() {
+            for c in 0..3 {
+                pixels[[0, c, y as usize, x as usize]] =
+                    (pixel[c] as f32 / 255.0 - mean[c]) / std[c];
+            }
+        }
+        Ok(pixels)
+    }
+
+    fn tokenize(&self, text: &str) -> Result<(Array2<i64>, Array2<i64>)> {
+        let encoding = self.tokenizer.encode(text, true).map_err(|e| anyhow!(e))?;
+        let pad = self.tokenizer.get_padding().map(|p| p.pad_id).unwrap_or(0) as i64;
+        let mut ids = Array2::from_elem((1, self.context_length), pad);
+        let mut mask = Array2::zeros((1, self.context_length));
+        for (i, &id) in encoding
+            .get_ids()
+            .iter()
+            .take(self.context_length)
+            .enumerate()
+        {
+            ids[[0, i]] = id as i64;
+            mask[[0, i]] = 1;
+        }
+        Ok((ids, mask))
+    }
+}
+
+#[async_trait]
+impl Embedder for ClipEmbedder {
+    async fn embed_image(&self, jpeg: &[u8]) -> Result<Vec<f32>> {
+        let pixels = self.preprocess_image(jpeg)?;
+        let visual = self.visual.clone();
+        let output = self.image_output.clone();
+        let dimension = self.dimension;
+        tokio::task::spawn_blocking(move || {
+            let mut visual = visual.lock().unwrap();
+            let name = visual.inputs[0].name.clone();
+            let outputs = visual.run(vec![(name, Tensor::from_array(pixels)?)])?;
+            normalized_embedding(&outputs, &output, dimension)
+        })
+        .await?
+    }
+
+    async fn embed_text(&self, text: &str) -> Result<Vec<f32>> {
+        let (ids, mask) = self.tokenize(text)?;
+        let textual = self.textual.clone();
+        let output = self.text_output.clone();
+        let dimension = self.dimension;
+        tokio::task::spawn_blocking(move || {
+            let mut textual = textual.lock().unwrap();
+            let mut inputs: Vec<(Cow<str>, SessionInputValue)> = Vec::new();
+            for input in &textual.inputs {
+                let value = if input.name.contains("mask") {
+                    Tensor::from_array(mask.clone())?
+                } else {
+                    Tensor::from_array(ids.clone())?
+                };
+                inputs.push((input.name.clone().into(), value.into()));
+            }
+            let outputs = textual.run(inputs)?;
+            normalized_embedding(&outputs, &output, dimension)
+        })
+        .await?
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+}
+
+/// The directory's name, plus a hash of both towers so that swapping in a different model under
+/// the same name doesn't mix its vectors up with the old one's.
+fn model_id(directory: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    for file in [VISUAL_MODEL, TEXTUAL_MODEL] {
+        let path = directory.join(file);
+        let mut model = std::fs::File::open(&path)
+            .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+        std::io::copy(&mut model, &mut hasher)?;
+    }
+    let name = directory
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "clip".into());
+    Ok(format!("{}@{}", name, &hasher.finalize().to_hex()[..16]))
+}
+
+fn last_dimension(value_type: &ValueType) -> Option<usize> {
+    match value_type {
+        ValueType::Tensor { shape, .. } => shape
+            .last()
+            .filter(|&&d| d > 0)
+            .map(|&d| d as usize),
+        _ => None,
+    }
+}
+
+/// Which output holds the embedding: the conventionally named one when a model has several, like
+/// `last_hidden_state` and `pooler_output` alongside it, or else the first.
+fn embedding_output(session: &Session, preferred: &str, file: &str) -> Result<String> {
+    session
+        .outputs
+        .iter()
+        .find(|o| o.name == preferred)
+        .or_else(|| session.outputs.first())
+        .map(|o| o.name.clone())
+        .ok_or_else(|| anyhow!("{} has no outputs", file))
+}
+
+/// Pulls out the embedding and L2 normalizes it so distances are comparable across images and
+/// text.
+fn normalized_embedding(
+    outputs: &SessionOutputs,
+    name: &str,
+    dimension: usize,
+) -> Result<Vec<f32>> {
+    let value = outputs
+        .get(name)
+        .ok_or_else(|| anyhow!("The model didn't produce {}", name))?;
+    normalize(
+        value.try_extract_array::<f32>()?.iter().copied().collect(),
+        dimension,
+    )
+}
+
+/// Checks the embedding is as long as the image tower said it would be, since the text tower
+/// doesn't have to agree, then L2 normalizes it.
+fn normalize(mut embedding: Vec<f32>, dimension: usize) -> Result<Vec<f32>> {
+    if embedding.len() != dimension {
+        return Err(anyhow!(
+            "Expected {} dimensions from the model but got {}",
+            dimension,
+            embedding.len()
+        ));
+    }
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+    Ok(embedding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_embeddings_of_the_right_length() {
+        assert_eq!(normalize(vec![3.0, 4.0], 2).unwrap(), vec![0.6, 0.8]);
+        assert_eq!(normalize(vec![0.0, 0.0], 2).unwrap(), vec![0.0, 0.0]);
+        let error = normalize(vec![3.0, 4.0, 0.0], 2).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected 2 dimensions from the model but got 3"
+        );
+    }
+
+    #[test]
+    fn tells_models_apart_by_their_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("ViT-B-32");
+        std::fs::create_dir(&model).unwrap();
+        std::fs::write(model.join(VISUAL_MODEL), b"visual").unwrap();
+        std::fs::write(model.join(TEXTUAL_MODEL), b"textual").unwrap();
+
+        let before = model_id(&model).unwrap();
+        assert!(before.starts_with("ViT-B-32@"));
+        assert_eq!(model_id(&model.join(".")).unwrap(), before);
+        std::fs::write(model.join(TEXTUAL_MODEL), b"retrained").unwrap();
+        assert_ne!(model_id(&model).unwrap(), before);
+    }
+}
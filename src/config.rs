@@ -0,0 +1,606 @@
+use anyhow::{anyhow, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::vector_index;
+
+/// Points at the config file, instead of the platform's usual place for it.
+const CONFIG_ENV: &str = "ELEPHANT_CONFIG";
+
+/// The longest `capture.interval` can be, a day.
+const MAX_INTERVAL_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+/// Everything that can be set in `config.toml`. Anything left out gets its default.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where recordings are kept. Changing it takes a restart.
+    pub data_dir: Option<PathBuf>,
+    pub capture: CaptureConfig,
+    pub embedder: EmbedderConfig,
+    pub ocr: OcrConfig,
+    pub pipeline: PipelineConfig,
+    pub retention: RetentionConfig,
+    pub index: IndexConfig,
+    /// Left out, nothing is encrypted unless the data directory already has a key.
+    pub encryption: Option<EncryptionConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Seconds between looking at the screen.
+    pub interval: f64,
+    /// How many of the 64 bits in a window's perceptual hash, or in the hash of any one of its
+    /// tiles, have to flip before it counts as changed. 0 records every visible change, however
+    /// small.
+    pub change_threshold: u32,
+    /// Only OCR and embed the part of a window that changed, rather than all of it. The whole
+    /// screenshot is still kept.
+    pub crop_changes: bool,
+}
+
+/// How much of each stage between capturing a window and storing it can happen at once. Changing
+/// these takes a restart.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Windows waiting between any two stages before captures start being skipped.
+    pub queue_size: usize,
+    /// Screenshots being OCR'd at once.
+    pub ocr_concurrency: usize,
+    /// Embedding requests in flight at once.
+    pub embed_concurrency: usize,
+}
+
+/// Nothing is ever deleted unless these are set.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Days to keep screenshots at full resolution before shrinking them to thumbnails.
+    pub full_days: Option<u32>,
+    /// Days to keep screenshots at all. Text and embeddings outlive them.
+    pub image_days: Option<u32>,
+    /// The longest side of a thumbnail, in pixels.
+    pub thumbnail_size: u32,
+    /// Gigabytes the data directory can take up before the oldest frames are deleted outright.
+    pub quota_gb: Option<f64>,
+}
+
+/// The IVF-PQ index over embeddings, which keeps vector search fast once there are a lot of
+/// frames. Until then every embedding is compared.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+    /// Frames there have to be before the index is built.
+    pub min_rows: usize,
+    /// Frames added since the index was last built before it's built again. Until then they're
+    /// still found, just by comparing every one of them.
+    pub rebuild_after: usize,
+    /// IVF partitions. Left out, it's about the square root of the number of frames.
+    pub num_partitions: Option<u32>,
+    /// PQ sub-vectors, which has to divide the embedding dimension. Left out, each covers about
+    /// 16 dimensions.
+    pub num_sub_vectors: Option<u32>,
+    /// Partitions searched per query. More is slower but finds more.
+    pub nprobes: usize,
+    /// Re-ranks this many times the requested candidates by their exact distance. 0 turns that
+    /// off.
+    pub refine_factor: u32,
+}
+
+/// Encrypts screenshots and the text found in them. Once the data directory has a key, whatever is
+/// recorded into it is encrypted, whether or not this is still set.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Where the key that unlocks everything comes from. Changing it takes `elephant rotate-key`.
+    pub key: KeySource,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    /// Derived from a passphrase, asked for on startup or read from `$ELEPHANT_PASSPHRASE`.
+    Passphrase,
+    /// A random key kept in the macOS Keychain or the Secret Service on Linux.
+    Keyring,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum EmbedderConfig {
+    Vertex(VertexConfig),
+    Clip(ClipConfig),
+    OpenAi(OpenAiConfig),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VertexConfig {
+    pub project: String,
+    pub location: String,
+    pub model: String,
+    /// One of the sizes the model supports: 128, 256, 512 or 1408.
+    pub dimension: usize,
+    /// Sent along with every screenshot to steer its embedding.
+    pub prompt: String,
+    /// A service account key or `gcloud auth application-default login` file. Google's usual
+    /// places are looked in when this isn't set.
+    pub credentials: Option<PathBuf>,
+    /// Where to get access tokens from, instead of wherever the credentials say.
+    pub token_uri: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClipConfig {
+    /// The directory holding `visual.onnx`, `textual.onnx` and `tokenizer.json`.
+    pub model: PathBuf,
+    /// `clip` or `siglip`.
+    #[serde(default = "default_clip_variant")]
+    pub variant: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OpenAiConfig {
+    pub url: String,
+    pub model: String,
+    pub dimension: usize,
+    pub api_key: Option<String>,
+    /// Says the model embeds images as well as text. Screenshots go out as `data:` URLs, which a
+    /// text-only model would quietly embed as text, so elephant won't start without it.
+    #[serde(default)]
+    pub multimodal: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "engine", rename_all = "lowercase")]
+pub enum OcrConfig {
+    /// Vision on macOS, and tesseract (when installed) everywhere else.
+    #[default]
+    Auto,
+    Vision,
+    Tesseract(TesseractConfig),
+    None,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TesseractConfig {
+    /// Tesseract language codes, like `eng` or `deu`.
+    pub languages: Vec<String>,
+    /// Tesseract's `--psm`, 3 being fully automatic and 11 being sparse text.
+    pub page_segmentation_mode: u32,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            interval: 10.0,
+            change_threshold: 4,
+            crop_changes: false,
+        }
+    }
+}
+
+impl CaptureConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(self.interval)
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 32,
+            ocr_concurrency: 2,
+            embed_concurrency: 4,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            full_days: None,
+            image_days: None,
+            thumbnail_size: 320,
+            quota_gb: None,
+        }
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            min_rows: 5000,
+            rebuild_after: 5000,
+            num_partitions: None,
+            num_sub_vectors: None,
+            nprobes: 20,
+            refine_factor: 10,
+        }
+    }
+}
+
+impl Default for EmbedderConfig {
+    fn default() -> Self {
+        EmbedderConfig::Vertex(VertexConfig::default())
+    }
+}
+
+impl Default for VertexConfig {
+    fn default() -> Self {
+        Self {
+            project: "1012868746574".into(),
+            location: "us-west1".into(),
+            model: "multimodalembedding@001".into(),
+            dimension: 1408,
+            prompt: "Provide a full description of this screenshot. Be as accurate and detailed \
+                as possible."
+                .into(),
+            credentials: None,
+            token_uri: None,
+        }
+    }
+}
+
+fn default_clip_variant() -> String {
+    "clip".into()
+}
+
+impl Default for TesseractConfig {
+    fn default() -> Self {
+        Self {
+            languages: vec!["eng".into()],
+            page_segmentation_mode: 3,
+        }
+    }
+}
+
+impl Config {
+    /// `$ELEPHANT_CONFIG`, or `elephant/config.toml` in the platform's config directory
+    /// (`~/.config` on Linux and `~/Library/Application Support` on macOS).
+    pub fn path() -> Option<PathBuf> {
+        match std::env::var_os(CONFIG_ENV) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => dirs::config_dir().map(|d| d.join("elephant").join("config.toml")),
+        }
+    }
+
+    /// Reads and validates the config at `path`, falling back to the defaults if there's no
+    /// file there.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path.filter(|p| p.exists()) else {
+            return Ok(Self::default());
+        };
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+        let config: Config =
+            toml::from_str(&raw).map_err(|e| anyhow!("{} is invalid: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| anyhow!("{} is invalid: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(self.capture.interval > 0.0 && self.capture.interval <= MAX_INTERVAL_SECONDS) {
+            return Err(anyhow!(
+                "capture.interval must be a positive number of seconds, up to {}",
+                MAX_INTERVAL_SECONDS
+            ));
+        }
+
+        if self.capture.change_threshold >= 64 {
+            return Err(anyhow!("capture.change_threshold must be less than 64"));
+        }
+
+        match &self.embedder {
+            EmbedderConfig::Vertex(vertex) => {
+                if ![128, 256, 512, 1408].contains(&vertex.dimension) {
+                    return Err(anyhow!(
+                        "embedder.dimension must be 128, 256, 512 or 1408 for Vertex, not {}",
+                        vertex.dimension
+                    ));
+                }
+                if let Some(credentials) = &vertex.credentials {
+                    if !expand_home(credentials).is_file() {
+                        return Err(anyhow!(
+                            "embedder.credentials {} isn't a file",
+                            credentials.display()
+                        ));
+                    }
+                }
+                if let Some(uri) = &vertex.token_uri {
+                    if !uri.starts_with("http://") && !uri.starts_with("https://") {
+                        return Err(anyhow!("embedder.token_uri must be an http(s) URL"));
+                    }
+                }
+            }
+            EmbedderConfig::Clip(clip) => {
+                if !["clip", "siglip"].contains(&clip.variant.as_str()) {
+                    return Err(anyhow!(
+                        "embedder.variant must be clip or siglip, not {}",
+                        clip.variant
+                    ));
+                }
+                if !expand_home(&clip.model).is_dir() {
+                    return Err(anyhow!(
+                        "embedder.model {} isn't a directory",
+                        clip.model.display()
+                    ));
+                }
+            }
+            EmbedderConfig::OpenAi(openai) => {
+                if !openai.url.starts_with("http://") && !openai.url.starts_with("https://") {
+                    return Err(anyhow!("embedder.url must be an http(s) URL"));
+                }
+                if openai.dimension == 0 {
+                    return Err(anyhow!("embedder.dimension must be more than 0"));
+                }
+                if !openai.multimodal {
+                    return Err(anyhow!(
+                        "embedder.multimodal must be true, since screenshots can only be \
+                            embedded by a model that takes images"
+                    ));
+                }
+            }
+        }
+
+        let pipeline = &self.pipeline;
+        if pipeline.queue_size == 0
+            || pipeline.ocr_concurrency == 0
+            || pipeline.embed_concurrency == 0
+        {
+            return Err(anyhow!(
+                "pipeline.queue_size, pipeline.ocr_concurrency and pipeline.embed_concurrency \
+                 must be more than 0"
+            ));
+        }
+
+        let retention = &self.retention;
+        if let (Some(full), Some(image)) = (retention.full_days, retention.image_days) {
+            if image < full {
+                return Err(anyhow!(
+                    "retention.image_days can't be less than retention.full_days"
+                ));
+            }
+        }
+        if retention.thumbnail_size < 16 {
+            return Err(anyhow!(
+                "retention.thumbnail_size must be at least 16 pixels"
+            ));
+        }
+        if let Some(quota) = retention.quota_gb {
+            if !(quota.is_finite() && quota > 0.0) {
+                return Err(anyhow!("retention.quota_gb must be a positive number"));
+            }
+        }
+
+        let index = &self.index;
+        if index.min_rows < vector_index::MIN_ROWS {
+            return Err(anyhow!(
+                "index.min_rows must be at least {}",
+                vector_index::MIN_ROWS
+            ));
+        }
+        if index.rebuild_after == 0 {
+            return Err(anyhow!("index.rebuild_after must be more than 0"));
+        }
+        if index.num_partitions == Some(0) || index.num_sub_vectors == Some(0) {
+            return Err(anyhow!(
+                "index.num_partitions and index.num_sub_vectors must be more than 0"
+            ));
+        }
+        if index.nprobes == 0 {
+            return Err(anyhow!("index.nprobes must be more than 0"));
+        }
+
+        if let OcrConfig::Tesseract(tesseract) = &self.ocr {
+            if tesseract.languages.is_empty() {
+                return Err(anyhow!("ocr.languages needs at least one language"));
+            }
+            if tesseract.page_segmentation_mode > 13 {
+                return Err(anyhow!(
+                    "ocr.page_segmentation_mode must be between 0 and 13"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps an eye on the config file and hands out the latest valid version of it.
+pub struct ConfigWatcher {
+    _watcher: Option<RecommendedWatcher>,
+    receiver: watch::Receiver<Config>,
+}
+
+impl ConfigWatcher {
+    /// Starts watching `path`, which doesn't need to exist yet. Edits that don't parse or
+    /// validate are reported and otherwise ignored, as is the file going away.
+    pub fn new(path: Option<PathBuf>, initial: Config) -> Result<Self> {
+        let (sender, receiver) = watch::channel(initial);
+        let Some(path) = path else {
+            return Ok(Self {
+                _watcher: None,
+                receiver,
+            });
+        };
+
+        // Editors tend to replace files rather than write to them, so watch the directory
+        let directory = path
+            .parent()
+            .ok_or_else(|| anyhow!("{} has no parent directory", path.display()))?
+            .to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+        let watched = path.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                if !event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == watched.file_name())
+                {
+                    return;
+                }
+                // Editors that replace the file delete it first, and a config that's been deleted
+                // shouldn't quietly turn back into the defaults either
+                if !watched.exists() {
+                    return;
+                }
+                match Config::load(Some(&watched)) {
+                    Ok(config) => {
+                        sender.send_if_modified(|current| {
+                            if *current == config {
+                                return false;
+                            }
+                            println!("Reloaded {}", watched.display());
+                            *current = config;
+                            true
+                        });
+                    }
+                    Err(e) => eprintln!("Ignoring config change: {:#}", e),
+                }
+            })?;
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: Some(watcher),
+            receiver,
+        })
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Config> {
+        self.receiver.clone()
+    }
+}
+
+/// Lets paths in the config start with `~/`.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(toml: &str) -> Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, toml).unwrap();
+        Config::load(Some(&path))
+    }
+
+    #[test]
+    fn wants_to_be_told_openai_models_take_images() {
+        let openai = r#"
+            [embedder]
+            provider = "openai"
+            url = "http://localhost:8080"
+            model = "jina-clip-v1"
+            dimension = 768
+        "#;
+        let error = load(openai).unwrap_err();
+        assert!(format!("{:#}", error).contains("embedder.multimodal must be true"));
+
+        let config = load(&format!("{}multimodal = true\n", openai)).unwrap();
+        assert!(matches!(
+            config.embedder,
+            EmbedderConfig::OpenAi(OpenAiConfig {
+                multimodal: true,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn falls_back_to_the_defaults_without_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        assert_eq!(Config::load(Some(&path)).unwrap(), Config::default());
+        assert_eq!(Config::load(None).unwrap(), Config::default());
+        assert_eq!(load("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn rejects_values_it_cant_use() {
+        for (toml, error) in [
+            ("[capture]\ninterval = 0", "capture.interval"),
+            ("[capture]\ninterval = -1", "capture.interval"),
+            ("[capture]\ninterval = nan", "capture.interval"),
+            ("[capture]\ninterval = inf", "capture.interval"),
+            ("[capture]\ninterval = 1e300", "capture.interval"),
+            (
+                "[capture]\nchange_threshold = 64",
+                "capture.change_threshold",
+            ),
+            ("[pipeline]\nqueue_size = 0", "pipeline.queue_size"),
+            (
+                "[retention]\nfull_days = 7\nimage_days = 3",
+                "retention.image_days",
+            ),
+            ("[retention]\nquota_gb = 0", "retention.quota_gb"),
+            ("[index]\nmin_rows = 10", "index.min_rows"),
+            ("[index]\nnprobes = 0", "index.nprobes"),
+            (
+                "[embedder]\nprovider = \"vertex\"\ndimension = 100",
+                "embedder.dimension",
+            ),
+            (
+                "[ocr]\nengine = \"tesseract\"\nlanguages = []",
+                "ocr.languages",
+            ),
+            ("[capture]\nintervals = 10", "unknown field"),
+        ] {
+            let message = format!("{:#}", load(toml).unwrap_err());
+            assert!(message.contains(error), "{:?} gave {}", toml, message);
+        }
+
+        let config = load("[capture]\ninterval = 86400").unwrap();
+        assert_eq!(config.capture.interval(), Duration::from_secs(86400));
+    }
+
+    #[tokio::test]
+    async fn publishes_edits_and_keeps_the_last_good_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[capture]\ninterval = 5").unwrap();
+        let watcher =
+            ConfigWatcher::new(Some(path.clone()), Config::load(Some(&path)).unwrap()).unwrap();
+        let mut config = watcher.subscribe();
+        assert_eq!(config.borrow().capture.interval, 5.0);
+
+        // Written elsewhere and moved into place, like editors do, so it's never seen half written
+        let replace = |toml: &str| {
+            let edited = dir.path().join("config.toml.swp");
+            std::fs::write(&edited, toml).unwrap();
+            std::fs::rename(&edited, &path).unwrap();
+        };
+        replace("[capture]\ninterval = 7");
+        tokio::time::timeout(Duration::from_secs(10), config.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.borrow_and_update().capture.interval, 7.0);
+
+        // Neither a broken edit nor the file going away loses the settings
+        replace("[capture]\ninterval = 0");
+        std::fs::remove_file(&path).unwrap();
+        let changed = tokio::time::timeout(Duration::from_secs(1), config.changed()).await;
+        assert!(changed.is_err());
+        assert_eq!(config.borrow().capture.interval, 7.0);
+    }
+}
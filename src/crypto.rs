@@ -0,0 +1,326 @@
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::prelude::*;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+use crate::config::KeySource;
+
+/// Where the data key is kept, wrapped, relative to the data directory.
+pub const KEY_FILE: &str = "key.json";
+/// The next key while `elephant rotate-key` is part way through.
+pub const NEXT_KEY_FILE: &str = "key.next.json";
+
+/// Starts every sealed blob. JPEGs start with 0xFFD8, so the two can't be confused.
+const MAGIC: &[u8] = b"elephnt1";
+/// Starts every sealed string in the table.
+const TEXT_PREFIX: &str = "sealed1:";
+const NONCE_BYTES: usize = 24;
+const TAG_BYTES: usize = 16;
+const SALT_BYTES: usize = 16;
+
+/// Separates the key for hashing words from the data key it's derived from.
+const WORD_KEY_CONTEXT: &str = "elephant 2024 text index words";
+
+const PASSPHRASE_ENV: &str = "ELEPHANT_PASSPHRASE";
+const KEYRING_SERVICE: &str = "elephant";
+
+/// Encrypts and authenticates with XChaCha20-Poly1305 under the data directory's key. Nonces are
+/// random, so the same plaintext seals differently every time.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    word_key: [u8; 32],
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+impl Cipher {
+    fn new(key: &Key) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(key),
+            word_key: blake3::derive_key(WORD_KEY_CONTEXT, key),
+        }
+    }
+
+    /// A key nobody else has, for tests.
+    #[cfg(test)]
+    pub fn random() -> Self {
+        Self::new(&XChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, plaintext)
+            .expect("encrypting into memory can't fail");
+        [MAGIC, nonce.as_slice(), &ciphertext].concat()
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let rest = sealed
+            .strip_prefix(MAGIC)
+            .filter(|rest| rest.len() >= NONCE_BYTES + TAG_BYTES)
+            .ok_or_else(|| anyhow!("Not sealed"))?;
+        let (nonce, ciphertext) = rest.split_at(NONCE_BYTES);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                anyhow!("Unable to decrypt, it's under a different key or was tampered with")
+            })
+    }
+
+    /// How long `seal` makes something `plaintext_len` bytes long.
+    pub fn sealed_len(plaintext_len: usize) -> usize {
+        MAGIC.len() + NONCE_BYTES + plaintext_len + TAG_BYTES
+    }
+
+    /// Seals text so it can go in a string column.
+    pub fn seal_text(&self, text: &str) -> String {
+        format!(
+            "{}{}",
+            TEXT_PREFIX,
+            BASE64_STANDARD_NO_PAD.encode(self.seal(text.as_bytes()))
+        )
+    }
+
+    /// Stands in for a word in the text index. The same word always hashes the same under the
+    /// same key, so it can still be searched for, but can't be read back.
+    pub fn word_hash(&self, word: &str) -> String {
+        blake3::keyed_hash(&self.word_key, word.as_bytes()).to_hex()[..32].to_string()
+    }
+
+    pub fn open_text(&self, sealed: &str) -> Result<String> {
+        let encoded = sealed
+            .strip_prefix(TEXT_PREFIX)
+            .ok_or_else(|| anyhow!("Not sealed"))?;
+        let plaintext = self.open(&BASE64_STANDARD_NO_PAD.decode(encoded)?)?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn is_sealed_text(text: &str) -> bool {
+    text.starts_with(TEXT_PREFIX)
+}
+
+/// Reads a string column that may predate encryption.
+pub fn open_text(cipher: Option<&Cipher>, text: &str) -> Result<String> {
+    match (is_sealed_text(text), cipher) {
+        (false, _) => Ok(text.to_string()),
+        (true, Some(cipher)) => cipher.open_text(text),
+        (true, None) => Err(anyhow!(
+            "The data directory is encrypted but wasn't unlocked"
+        )),
+    }
+}
+
+/// Seals text when there's a key to do it with.
+pub fn seal_text(cipher: Option<&Cipher>, text: &str) -> String {
+    match cipher {
+        Some(cipher) => cipher.seal_text(text),
+        None => text.to_string(),
+    }
+}
+
+/// The data key, sealed with a key that comes from `source`. This is all that changes when the
+/// passphrase does, while rotating swaps the data key too.
+#[derive(Deserialize, Serialize)]
+struct KeyFile {
+    source: KeySource,
+    /// Names the keyring entry, so a rotation can't clobber the entry still in use.
+    id: String,
+    /// Only for passphrases.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdf: Option<Kdf>,
+    wrapped_key: String,
+}
+
+/// Argon2id parameters, kept so they can be raised later without locking anyone out.
+#[derive(Deserialize, Serialize)]
+struct Kdf {
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl Kdf {
+    fn generate() -> Self {
+        let mut salt = [0; SALT_BYTES];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            salt: BASE64_STANDARD.encode(salt),
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+
+    fn derive(&self, passphrase: &str) -> Result<Key> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow!("Bad key derivation parameters: {}", e))?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(
+                passphrase.as_bytes(),
+                &BASE64_STANDARD.decode(&self.salt)?,
+                &mut key,
+            )
+            .map_err(|e| anyhow!("Unable to derive a key: {}", e))?;
+        Ok(key)
+    }
+}
+
+/// Unlocks the key in `root`, first making one from `source` if there isn't one. `None` means
+/// there's no key and no `source` to make one from, so nothing gets encrypted.
+pub fn unlock(root: &Path, source: Option<KeySource>) -> Result<Option<Cipher>> {
+    let path = root.join(KEY_FILE);
+    if path.exists() {
+        return Ok(Some(unwrap(&read(&path)?)?));
+    }
+    let Some(source) = source else {
+        return Ok(None);
+    };
+    let (cipher, key_file) = generate(source)?;
+    write(&path, &key_file)?;
+    println!(
+        "Encrypting everything recorded from now on, under the key in {}",
+        path.display()
+    );
+    Ok(Some(cipher))
+}
+
+/// Starts, or picks back up, replacing the key in `root` with a new one from `source`. Returns
+/// the current key, if there is one, and the new one. Until `finish_rotation`, data can be under
+/// either of them.
+pub fn start_rotation(root: &Path, source: KeySource) -> Result<(Option<Cipher>, Cipher)> {
+    let current = unlock(root, None)?;
+    let next_path = root.join(NEXT_KEY_FILE);
+    if next_path.exists() {
+        println!("Picking up the rotation that was already under way");
+        return Ok((current, unwrap(&read(&next_path)?)?));
+    }
+    let (next, key_file) = generate(source)?;
+    write(&next_path, &key_file)?;
+    Ok((current, next))
+}
+
+/// Makes the new key the current one, once nothing is under the old one any more.
+pub fn finish_rotation(root: &Path) -> Result<()> {
+    let path = root.join(KEY_FILE);
+    let previous = path.exists().then(|| read(&path)).transpose()?;
+    std::fs::rename(root.join(NEXT_KEY_FILE), &path)?;
+    if let Some(previous) = previous.filter(|p| p.source == KeySource::Keyring) {
+        if let Err(e) = keyring_entry(&previous.id).and_then(|entry| {
+            entry
+                .delete_password()
+                .map_err(|e| anyhow!("Unable to remove the old keyring entry: {}", e))
+        }) {
+            eprintln!("{:#}", e);
+        }
+    }
+    Ok(())
+}
+
+fn generate(source: KeySource) -> Result<(Cipher, KeyFile)> {
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    let mut id = [0; 8];
+    OsRng.fill_bytes(&mut id);
+    let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let (wrapping, kdf) = match source {
+        KeySource::Passphrase => {
+            let kdf = Kdf::generate();
+            (kdf.derive(&new_passphrase()?)?, Some(kdf))
+        }
+        KeySource::Keyring => {
+            let wrapping = XChaCha20Poly1305::generate_key(&mut OsRng);
+            keyring_entry(&id)?
+                .set_password(&BASE64_STANDARD.encode(wrapping))
+                .map_err(|e| anyhow!("Unable to save the key to the keyring: {}", e))?;
+            (wrapping, None)
+        }
+    };
+    let key_file = KeyFile {
+        source,
+        id,
+        kdf,
+        wrapped_key: BASE64_STANDARD.encode(Cipher::new(&wrapping).seal(&key)),
+    };
+    Ok((Cipher::new(&key), key_file))
+}
+
+fn unwrap(key_file: &KeyFile) -> Result<Cipher> {
+    let wrapping = match (&key_file.source, &key_file.kdf) {
+        (KeySource::Passphrase, Some(kdf)) => kdf.derive(&passphrase()?)?,
+        (KeySource::Passphrase, None) => {
+            return Err(anyhow!("The key file is missing its kdf parameters"));
+        }
+        (KeySource::Keyring, _) => {
+            let secret = keyring_entry(&key_file.id)?
+                .get_password()
+                .map_err(|e| anyhow!("Unable to read the key from the keyring: {}", e))?;
+            *Key::from_slice(&BASE64_STANDARD.decode(secret)?)
+        }
+    };
+    let key = Cipher::new(&wrapping)
+        .open(&BASE64_STANDARD.decode(&key_file.wrapped_key)?)
+        .map_err(|_| match key_file.source {
+            KeySource::Passphrase => anyhow!("Wrong passphrase"),
+            KeySource::Keyring => anyhow!("The keyring holds the wrong key"),
+        })?;
+    Ok(Cipher::new(Key::from_slice(&key)))
+}
+
+/// Entries are named after the key file's id, which is random, so data directories don't share.
+fn keyring_entry(id: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, id)
+        .map_err(|e| anyhow!("Unable to use the keyring: {}", e))
+}
+
+fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password("Passphrase: ")?)
+}
+
+fn new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if passphrase.len() < 8 {
+        return Err(anyhow!("Passphrases need at least 8 characters"));
+    }
+    if rpassword::prompt_password("Again: ")? != passphrase {
+        return Err(anyhow!("The passphrases didn't match"));
+    }
+    Ok(passphrase)
+}
+
+fn read(path: &Path) -> Result<KeyFile> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&raw).map_err(|e| anyhow!("{} is invalid: {}", path.display(), e))
+}
+
+fn write(path: &Path, key_file: &KeyFile) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_string_pretty(key_file)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Result};
+use fs4::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::blobs::{BlobStore, Claims, BLOBS};
+use crate::config::{expand_home, Config};
+use crate::crypto::{self, Cipher};
+
+/// Overrides where everything is stored.
+const DATA_DIR_ENV: &str = "ELEPHANT_DATA_DIR";
+
+const DATABASE: &str = "db";
+const TEXT_INDEX: &str = "fts";
+const SCREENSHOTS: &str = "screenshots";
+const BACKUPS: &str = "backups";
+const QUEUE: &str = "queue";
+const LOCK: &str = "elephant.lock";
+
+/// The one directory everything elephant records lives under, laid out as
+///
+/// ```text
+/// db/            the lancedb database
+/// fts/           the full-text index over OCR text
+/// blobs/         screenshots, filed by content hash
+/// backups/       tables copied aside before migrations
+/// queue/         frames that haven't made it into the database yet
+/// elephant.lock  held by whichever process is recording
+/// key.json       the encryption key, wrapped, if there is one
+/// ```
+#[derive(Clone, Debug)]
+pub struct DataDir {
+    root: PathBuf,
+    /// Set once unlocked, when there's a key.
+    cipher: Option<Cipher>,
+    /// Shared by every blob store handed out, and every clone.
+    claims: Claims,
+}
+
+/// Keeps other processes from writing to the data directory until dropped.
+pub struct DataLock {
+    _file: File,
+}
+
+impl DataDir {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            cipher: None,
+            claims: Claims::default(),
+        }
+    }
+
+    /// `$ELEPHANT_DATA_DIR`, then `data_dir` from the config, then the platform's usual place
+    /// for application data: `~/.local/share/elephant` (or wherever `$XDG_DATA_HOME` says) on
+    /// Linux and `~/Library/Application Support/elephant` on macOS.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        if let Some(root) = std::env::var_os(DATA_DIR_ENV) {
+            return Ok(Self::new(PathBuf::from(root)));
+        }
+        if let Some(root) = &config.data_dir {
+            return Ok(Self::new(expand_home(root)));
+        }
+        let data = dirs::data_dir().ok_or_else(|| {
+            anyhow!(
+                "Unable to find a home for the data directory, set {}",
+                DATA_DIR_ENV
+            )
+        })?;
+        Ok(Self::new(data.join("elephant")))
+    }
+
+    /// Makes sure every directory exists.
+    pub fn create(&self) -> Result<()> {
+        for directory in [
+            self.database(),
+            self.text_index(),
+            self.root.join(BLOBS),
+            self.backups(),
+            self.queue(),
+        ] {
+            std::fs::create_dir_all(&directory)
+                .map_err(|e| anyhow!("Unable to create {}: {}", directory.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Older versions kept everything in whatever directory elephant was started from.
+    pub fn warn_about_old_layout(&self) {
+        if Path::new("data-ldb").exists() && !self.database().join("screenshots.lance").exists() {
+            println!(
+                "Found a database from an older elephant in the current directory. To keep it, \
+                 move data-ldb to {}, data-fts to {} and out to {}",
+                self.database().display(),
+                self.text_index().display(),
+                self.screenshots().display()
+            );
+        }
+    }
+
+    /// Unlocks the key, if there is one, asking for the passphrase if need be. Screenshots and
+    /// text are then sealed on the way in and opened on the way out.
+    pub fn unlock(mut self) -> Result<Self> {
+        self.cipher = crypto::unlock(&self.root, None)?;
+        Ok(self)
+    }
+
+    /// Like `unlock`, but makes a key first if `config` asks for encryption and there isn't one.
+    pub fn unlock_or_create_key(mut self, config: &Config) -> Result<Self> {
+        self.cipher = crypto::unlock(&self.root, config.encryption.as_ref().map(|e| e.key))?;
+        Ok(self)
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+
+    /// The root, for things like the key that live directly in it.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Fails straight away, rather than waiting, if someone else has it.
+    pub fn lock(&self) -> Result<DataLock> {
+        let path = self.root.join(LOCK);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            return Err(anyhow!(
+                "{} is already in use by another elephant (pid {})",
+                self.root.display(),
+                holder.trim()
+            ));
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(DataLock { _file: file })
+    }
+
+    pub fn database(&self) -> PathBuf {
+        self.root.join(DATABASE)
+    }
+
+    pub fn text_index(&self) -> PathBuf {
+        self.root.join(TEXT_INDEX)
+    }
+
+    /// Where older versions kept screenshots, before there was a blob store.
+    pub fn screenshots(&self) -> PathBuf {
+        self.root.join(SCREENSHOTS)
+    }
+
+    pub fn blob_store(&self) -> BlobStore {
+        BlobStore::sharing(self.root.clone(), self.cipher.clone(), self.claims.clone())
+    }
+
+    pub fn backups(&self) -> PathBuf {
+        self.root.join(BACKUPS)
+    }
+
+    pub fn queue(&self) -> PathBuf {
+        self.root.join(QUEUE)
+    }
+
+    /// Where older versions kept the JPEG for the frame with the given `metrohash`, relative to
+    /// the root so the whole directory can be moved.
+    pub fn screenshot_path(&self, metrohash: u64) -> PathBuf {
+        Path::new(SCREENSHOTS).join(format!("{}.jpg", metrohash))
+    }
+
+    /// Resolves a path stored in the database.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// lancedb wants a URI rather than a path.
+    pub fn database_uri(&self) -> Result<String> {
+        self.database()
+            .to_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("{} isn't valid UTF-8", self.database().display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_lets_one_elephant_in_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        data_dir.create().unwrap();
+
+        let lock = data_dir.lock().unwrap();
+        let error = data_dir.lock().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} is already in use by another elephant (pid {})",
+                dir.path().display(),
+                std::process::id()
+            )
+        );
+
+        drop(lock);
+        assert!(data_dir.lock().is_ok());
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use image::imageops::{self, FilterType};
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+
+use crate::capture::rgb_to_jpeg;
+
+/// dHash compares each pixel with its right neighbour, so the image is shrunk to one column wider
+/// than the hash.
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+
+/// The side of a square tile in image pixels. Tiles on the right and bottom edges can be smaller.
+const TILE_SIZE: u32 = 128;
+
+/// What a screenshot looks like, coarsely enough that noise doesn't matter.
+pub struct Fingerprint {
+    /// A 64 bit difference hash of the whole image. Unlike a hash of the bytes it barely moves
+    /// when a cursor blinks or a clock ticks, but changes a lot when the content does.
+    pub hash: u64,
+    pub tiles: Tiles,
+}
+
+/// The image cut into a grid, with a difference hash per tile.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Tiles {
+    pub width: u32,
+    pub height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    /// Row by row, starting at the top left.
+    pub hashes: Vec<u64>,
+}
+
+/// A rectangle in image pixels, with the origin at the top left.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Which parts of a window changed since it was last recorded.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChangeReport {
+    /// Indices into `Tiles::hashes`.
+    pub tiles: Vec<usize>,
+    /// How many tiles there are altogether.
+    pub total: usize,
+    /// The smallest rectangle covering every changed tile.
+    pub region: Region,
+}
+
+impl ChangeReport {
+    /// Everything changed, like when a window first shows up or is resized.
+    pub fn whole(tiles: &Tiles) -> Self {
+        Self {
+            tiles: (0..tiles.hashes.len()).collect(),
+            total: tiles.hashes.len(),
+            region: Region {
+                x: 0,
+                y: 0,
+                width: tiles.width,
+                height: tiles.height,
+            },
+        }
+    }
+
+    pub fn is_whole(&self) -> bool {
+        self.tiles.len() == self.total
+    }
+}
+
+/// Decodes a JPEG-encoded screenshot and hashes it, both as a whole and tile by tile.
+pub fn fingerprint(jpeg: &[u8]) -> Result<Fingerprint> {
+    let gray = image::load_from_memory(jpeg)?.to_luma8();
+    let (width, height) = gray.dimensions();
+    let columns = width.div_ceil(TILE_SIZE);
+    let rows = height.div_ceil(TILE_SIZE);
+    let mut hashes = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        for column in 0..columns {
+            let (x, y) = (column * TILE_SIZE, row * TILE_SIZE);
+            let tile = imageops::crop_imm(
+                &gray,
+                x,
+                y,
+                TILE_SIZE.min(width - x),
+                TILE_SIZE.min(height - y),
+            )
+            .to_image();
+            hashes.push(dhash(&tile));
+        }
+    }
+    Ok(Fingerprint {
+        hash: dhash(&gray),
+        tiles: Tiles {
+            width,
+            height,
+            columns,
+            rows,
+            hashes,
+        },
+    })
+}
+
+fn dhash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            let brighter = small.get_pixel(x + 1, y)[0] > small.get_pixel(x, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+/// How many of the 64 bits differ.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Works out which tiles of `current` have more than `threshold` bits of their hash different from
+/// `previous`, if any do. Grids of different sizes can't be lined up, so they count as a whole
+/// change.
+pub fn compare(previous: &Tiles, current: &Tiles, threshold: u32) -> Option<ChangeReport> {
+    if previous.columns != current.columns || previous.rows != current.rows {
+        return Some(ChangeReport::whole(current));
+    }
+    let tiles: Vec<usize> = previous
+        .hashes
+        .iter()
+        .zip(&current.hashes)
+        .enumerate()
+        .filter(|(_, (a, b))| hamming_distance(**a, **b) > threshold)
+        .map(|(i, _)| i)
+        .collect();
+    if tiles.is_empty() {
+        return None;
+    }
+
+    let column = |i: &usize| *i as u32 % current.columns;
+    let row = |i: &usize| *i as u32 / current.columns;
+    let left = tiles.iter().map(column).min().unwrap_or(0) * TILE_SIZE;
+    let top = tiles.iter().map(row).min().unwrap_or(0) * TILE_SIZE;
+    let right = ((tiles.iter().map(column).max().unwrap_or(0) + 1) * TILE_SIZE).min(current.width);
+    let bottom = ((tiles.iter().map(row).max().unwrap_or(0) + 1) * TILE_SIZE).min(current.height);
+    Some(ChangeReport {
+        tiles,
+        total: current.hashes.len(),
+        region: Region {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        },
+    })
+}
+
+/// Cuts `region` out of a JPEG-encoded screenshot.
+pub fn crop(jpeg: &[u8], region: &Region) -> Result<Vec<u8>> {
+    let rgb = image::load_from_memory(jpeg)?.to_rgb8();
+    let cropped =
+        imageops::crop_imm(&rgb, region.x, region.y, region.width, region.height).to_image();
+    rgb_to_jpeg(cropped.as_raw(), cropped.width(), cropped.height())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// A black 300 by 200 window, with a 40 pixel white square at `square` if anywhere.
+    fn window(square: Option<(u32, u32)>) -> Vec<u8> {
+        let image = RgbImage::from_fn(300, 200, |x, y| match square {
+            Some((left, top)) if (left..left + 40).contains(&x) && (top..top + 40).contains(&y) => {
+                Rgb([255; 3])
+            }
+            _ => Rgb([0; 3]),
+        });
+        rgb_to_jpeg(image.as_raw(), 300, 200).unwrap()
+    }
+
+    /// A 300 by 200 window shading from black to white, or back again when `reversed`.
+    fn gradient(reversed: bool) -> Vec<u8> {
+        let image = RgbImage::from_fn(300, 200, |x, _| {
+            let shade = (x * 255 / 299) as u8;
+            Rgb([if reversed { 255 - shade } else { shade }; 3])
+        });
+        rgb_to_jpeg(image.as_raw(), 300, 200).unwrap()
+    }
+
+    #[test]
+    fn counts_the_bits_that_differ() {
+        assert_eq!(hamming_distance(0b1011, 0b1011), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(0, u64::MAX), 64);
+    }
+
+    #[test]
+    fn hashes_move_with_the_content_rather_than_the_bytes() {
+        let hash = |jpeg: &[u8]| fingerprint(jpeg).unwrap().hash;
+        let plain = hash(&gradient(false));
+        assert_eq!(hash(&gradient(false)), plain);
+
+        // Every pixel a shade darker, which changes every byte of the JPEG
+        let image = image::load_from_memory(&gradient(false)).unwrap().to_rgb8();
+        let darker = RgbImage::from_fn(300, 200, |x, y| {
+            Rgb(image.get_pixel(x, y).0.map(|c| c.saturating_sub(8)))
+        });
+        let darker = rgb_to_jpeg(darker.as_raw(), 300, 200).unwrap();
+        assert!(hamming_distance(hash(&darker), plain) <= 4);
+
+        // Every comparison the other way around
+        assert!(hamming_distance(hash(&gradient(true)), plain) >= 56);
+    }
+
+    #[test]
+    fn fingerprints_tile_by_tile() {
+        let tiles = fingerprint(&window(None)).unwrap().tiles;
+        assert_eq!((tiles.columns, tiles.rows), (3, 2));
+        assert_eq!(tiles.hashes.len(), 6);
+    }
+
+    #[test]
+    fn finds_the_tiles_that_changed() {
+        let before = fingerprint(&window(None)).unwrap().tiles;
+        let after = fingerprint(&window(Some((150, 140)))).unwrap().tiles;
+        let change = compare(&before, &after, 4).unwrap();
+        assert_eq!(change.tiles, [4]);
+        assert_eq!(change.total, 6);
+        assert!(!change.is_whole());
+        // The bottom row of tiles is cut short by the edge of the window
+        assert_eq!(
+            change.region,
+            Region {
+                x: 128,
+                y: 128,
+                width: 128,
+                height: 72
+            }
+        );
+        assert!(compare(&after, &after, 0).is_none());
+        // Nothing changes enough when every bit has to
+        assert!(compare(&before, &after, 64).is_none());
+    }
+
+    #[test]
+    fn grids_that_dont_line_up_changed_everywhere() {
+        let before = fingerprint(&window(None)).unwrap().tiles;
+        let image = RgbImage::new(100, 100);
+        let smaller = rgb_to_jpeg(image.as_raw(), 100, 100).unwrap();
+        let after = fingerprint(&smaller).unwrap().tiles;
+        let change = compare(&before, &after, 4).unwrap();
+        assert!(change.is_whole());
+        assert_eq!((change.region.width, change.region.height), (100, 100));
+    }
+
+    #[test]
+    fn crops_out_the_region() {
+        let region = Region {
+            x: 128,
+            y: 128,
+            width: 128,
+            height: 72,
+        };
+        let cropped = crop(&window(Some((150, 140))), &region).unwrap();
+        let image = image::load_from_memory(&cropped).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (128, 72));
+        // The square is still there, shifted by where the region starts
+        assert!(image.get_pixel(42, 32)[0] > 200);
+        assert!(image.get_pixel(5, 5)[0] < 50);
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::config::EmbedderConfig;
+use crate::openai::OpenAiEmbedder;
+use crate::vertex::VertexEmbedder;
+
+/// Turns screenshots and queries into vectors that live in the same space.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Embeds a JPEG-encoded screenshot.
+    async fn embed_image(&self, jpeg: &[u8]) -> Result<Vec<f32>>;
+
+    async fn embed_text(&self, text: &str) -> Result<Vec<f32>>;
+
+    /// The length of every vector this embedder returns.
+    fn dimension(&self) -> usize;
+
+    /// Identifies the model, since vectors from different models can't be compared.
+    fn model_id(&self) -> &str;
+}
+
+pub fn embedder_from_config(config: &EmbedderConfig) -> Result<Box<dyn Embedder>> {
+    match config {
+        EmbedderConfig::Vertex(vertex) => Ok(Box::new(VertexEmbedder::new(vertex)?)),
+        #[cfg(feature = "onnx")]
+        EmbedderConfig::Clip(clip) => Ok(Box::new(crate::clip::ClipEmbedder::open(
+            &crate::config::expand_home(&clip.model),
+            clip.variant.parse()?,
+        )?)),
+        #[cfg(not(feature = "onnx"))]
+        EmbedderConfig::Clip(_) => Err(anyhow::anyhow!(
+            "elephant was built without the onnx feature"
+        )),
+        EmbedderConfig::OpenAi(openai) => Ok(Box::new(OpenAiEmbedder::new(
+            &openai.url,
+            &openai.model,
+            openai.api_key.clone(),
+            openai.dimension,
+        ))),
+    }
+}
@@ -0,0 +1,445 @@
+use anyhow::{anyhow, Result};
+use arrow_array::types::Float32Type;
+use arrow_array::{
+    ArrayRef, BooleanArray, FixedSizeListArray, Float32Array, Int32Array, ListArray, RecordBatch,
+    StringArray, StructArray, TimestampMillisecondArray, UInt32Array, UInt64Array,
+};
+use arrow_buffer::OffsetBuffer;
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use metrohash::MetroHash64;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::crypto::{seal_text, Cipher};
+use crate::diff::ChangeReport;
+use crate::ocr::{lines_to_text, OcrLine};
+use crate::types::Window;
+
+/// Where every captured frame ends up.
+pub const TABLE: &str = "screenshots";
+
+/// Bump this, and add a migration to `migrations.rs`, whenever `schema` changes.
+pub const SCHEMA_VERSION: u32 = 7;
+
+/// The schema metadata key the version is stored under.
+pub const SCHEMA_VERSION_KEY: &str = "elephant.schema_version";
+
+/// The screenshot is as it was captured.
+pub const TIER_FULL: &str = "full";
+/// The screenshot has been shrunk.
+pub const TIER_THUMBNAIL: &str = "thumbnail";
+/// The screenshot is gone, but its text and embedding are still there.
+pub const TIER_NONE: &str = "none";
+
+/// The `model_id` of frames migrated from before it was stored.
+pub const UNKNOWN_MODEL: &str = "unknown";
+
+/// A changed window along with everything we worked out about it.
+pub struct Frame {
+    /// Unique across every frame ever captured, unlike `window.jpeg_metrohash`.
+    pub id: u64,
+    pub window: Window,
+    pub embedding: Vec<f32>,
+    pub ocr: Vec<OcrLine>,
+    /// The blob `window.jpeg` is stored as, and where that is.
+    pub image_hash: String,
+    pub image_path: PathBuf,
+    pub change: ChangeReport,
+    /// Whether `embedding` and `ocr` only cover `change.region`.
+    pub cropped: bool,
+}
+
+pub fn schema(dimension: usize) -> SchemaRef {
+    let metadata = HashMap::from([(SCHEMA_VERSION_KEY.to_string(), SCHEMA_VERSION.to_string())]);
+    Arc::new(Schema::new_with_metadata(
+        vec![
+            Field::new("frame_id", DataType::UInt64, false),
+            Field::new("metrohash", DataType::UInt64, false),
+            // Milliseconds since the epoch, in UTC
+            Field::new(
+                "captured_at",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+            Field::new("window_id", DataType::UInt32, false),
+            // Sealed, like the OCR text, when the data directory is encrypted
+            Field::new("title", DataType::Utf8, false),
+            Field::new("app", DataType::Utf8, true),
+            Field::new("z", DataType::UInt32, false),
+            Field::new("window_x", DataType::Int32, false),
+            Field::new("window_y", DataType::Int32, false),
+            Field::new("window_width", DataType::UInt32, false),
+            Field::new("window_height", DataType::UInt32, false),
+            Field::new("display_id", DataType::UInt32, true),
+            Field::new("image_path", DataType::Utf8, false),
+            // Null for frames whose screenshot was already gone when the blob store came along
+            Field::new("image_hash", DataType::Utf8, true),
+            // One of the `TIER_` constants, as retention shrinks and then drops screenshots
+            Field::new("image_tier", DataType::Utf8, false),
+            // Kept no matter what retention says
+            Field::new("pinned", DataType::Boolean, false),
+            Field::new("image_width", DataType::UInt32, false),
+            Field::new("image_height", DataType::UInt32, false),
+            Field::new("image_bytes", DataType::UInt64, false),
+            // The part of the image that changed since the window was last recorded
+            Field::new("changed_x", DataType::UInt32, false),
+            Field::new("changed_y", DataType::UInt32, false),
+            Field::new("changed_width", DataType::UInt32, false),
+            Field::new("changed_height", DataType::UInt32, false),
+            Field::new("cropped", DataType::Boolean, false),
+            Field::new("model_id", DataType::Utf8, false),
+            Field::new(
+                "embedding",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    dimension as i32,
+                ),
+                true,
+            ),
+            Field::new("ocr_text", DataType::Utf8, true),
+            Field::new(
+                "ocr_lines",
+                DataType::List(Arc::new(ocr_line_field())),
+                true,
+            ),
+        ],
+        metadata,
+    ))
+}
+
+pub fn new_frame_id(window: &Window) -> u64 {
+    let mut hasher = MetroHash64::new();
+    window.captured_at.hash(&mut hasher);
+    window.id.hash(&mut hasher);
+    window.jpeg_metrohash.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn ocr_line_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("text", DataType::Utf8, false),
+        Field::new("confidence", DataType::Float32, false),
+        Field::new("x", DataType::Float32, false),
+        Field::new("y", DataType::Float32, false),
+        Field::new("width", DataType::Float32, false),
+        Field::new("height", DataType::Float32, false),
+    ])
+}
+
+pub fn ocr_line_field() -> Field {
+    Field::new("item", DataType::Struct(ocr_line_fields()), true)
+}
+
+pub fn to_batch(
+    schema: &SchemaRef,
+    dimension: usize,
+    model_id: &str,
+    frames: &[Frame],
+    cipher: Option<&Cipher>,
+) -> Result<RecordBatch> {
+    let windows = || frames.iter().map(|f| &f.window);
+    let regions = || frames.iter().map(|f| &f.change.region);
+    let mut captured_at = Vec::new();
+    let mut image_sizes = Vec::new();
+    for window in windows() {
+        captured_at.push(window.captured_at.duration_since(UNIX_EPOCH)?.as_millis() as i64);
+        image_sizes.push(
+            image::io::Reader::new(Cursor::new(&window.jpeg))
+                .with_guessed_format()?
+                .into_dimensions()?,
+        );
+    }
+
+    Ok(RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(UInt64Array::from_iter_values(frames.iter().map(|f| f.id))),
+            Arc::new(UInt64Array::from_iter_values(
+                windows().map(|w| w.jpeg_metrohash),
+            )),
+            Arc::new(TimestampMillisecondArray::from(captured_at)),
+            Arc::new(UInt32Array::from_iter_values(windows().map(|w| w.id))),
+            Arc::new(StringArray::from_iter_values(
+                windows().map(|w| seal_text(cipher, &w.title)),
+            )),
+            Arc::new(StringArray::from_iter(windows().map(|w| w.app.as_deref()))),
+            Arc::new(UInt32Array::from_iter_values(windows().map(|w| w.z as u32))),
+            Arc::new(Int32Array::from_iter_values(windows().map(|w| w.bounds.x))),
+            Arc::new(Int32Array::from_iter_values(windows().map(|w| w.bounds.y))),
+            Arc::new(UInt32Array::from_iter_values(
+                windows().map(|w| w.bounds.width),
+            )),
+            Arc::new(UInt32Array::from_iter_values(
+                windows().map(|w| w.bounds.height),
+            )),
+            Arc::new(UInt32Array::from_iter(windows().map(|w| w.display_id))),
+            Arc::new(StringArray::from_iter_values(
+                frames.iter().map(|f| f.image_path.to_string_lossy()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                frames.iter().map(|f| &f.image_hash),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                frames.iter().map(|_| TIER_FULL),
+            )),
+            Arc::new(BooleanArray::from(vec![false; frames.len()])),
+            Arc::new(UInt32Array::from_iter_values(
+                image_sizes.iter().map(|(width, _)| *width),
+            )),
+            Arc::new(UInt32Array::from_iter_values(
+                image_sizes.iter().map(|(_, height)| *height),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                windows().map(|w| w.jpeg.len() as u64),
+            )),
+            Arc::new(UInt32Array::from_iter_values(regions().map(|r| r.x))),
+            Arc::new(UInt32Array::from_iter_values(regions().map(|r| r.y))),
+            Arc::new(UInt32Array::from_iter_values(regions().map(|r| r.width))),
+            Arc::new(UInt32Array::from_iter_values(regions().map(|r| r.height))),
+            Arc::new(BooleanArray::from_iter(
+                frames.iter().map(|f| Some(f.cropped)),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                frames.iter().map(|_| model_id),
+            )),
+            Arc::new(
+                FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+                    frames
+                        .iter()
+                        .map(|f| Some(f.embedding.iter().map(|i| Some(*i)))),
+                    dimension as i32,
+                ),
+            ),
+            Arc::new(StringArray::from_iter_values(
+                frames
+                    .iter()
+                    .map(|f| seal_text(cipher, &lines_to_text(&f.ocr))),
+            )),
+            Arc::new(ocr_lines_array(frames, cipher)),
+        ],
+    )?)
+}
+
+fn ocr_lines_array(frames: &[Frame], cipher: Option<&Cipher>) -> ListArray {
+    let lines = || frames.iter().flat_map(|f| f.ocr.iter());
+    let columns: Vec<ArrayRef> = vec![
+        // Strings need to know how many there are up front, which flat_map can't say
+        Arc::new(StringArray::from(
+            lines()
+                .map(|l| seal_text(cipher, &l.text))
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(Float32Array::from_iter_values(
+            lines().map(|l| l.confidence),
+        )),
+        Arc::new(Float32Array::from_iter_values(lines().map(|l| l.bounds.x))),
+        Arc::new(Float32Array::from_iter_values(lines().map(|l| l.bounds.y))),
+        Arc::new(Float32Array::from_iter_values(
+            lines().map(|l| l.bounds.width),
+        )),
+        Arc::new(Float32Array::from_iter_values(
+            lines().map(|l| l.bounds.height),
+        )),
+    ];
+    ListArray::new(
+        Arc::new(ocr_line_field()),
+        OffsetBuffer::from_lengths(frames.iter().map(|f| f.ocr.len())),
+        Arc::new(StructArray::new(ocr_line_fields(), columns, None)),
+        None,
+    )
+}
+
+/// Quotes a string for use in a predicate over the table.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// A `captured_at` literal for use in a predicate over the table.
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    format!(
+        "timestamp '{}'",
+        time.naive_utc().format("%Y-%m-%d %H:%M:%S%.3f")
+    )
+}
+
+/// Sets each column to the SQL expression paired with it, in the rows matching `predicate`.
+pub async fn update(
+    table: &lancedb::Table,
+    predicate: &str,
+    columns: &[(&str, String)],
+) -> Result<()> {
+    let table = table
+        .as_native()
+        .ok_or_else(|| anyhow!("{} isn't a local table", TABLE))?;
+    table
+        .update(
+            Some(predicate),
+            columns.iter().map(|(c, e)| (*c, e.as_str())).collect(),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{jpeg_metrohash, rgb_to_jpeg};
+    use crate::diff::{fingerprint, Region};
+    use crate::ocr::BoundingBox;
+    use crate::types::WindowBounds;
+    use arrow_array::Array;
+    use std::time::{Duration, SystemTime};
+
+    fn frame(id: u64, ocr: Vec<OcrLine>) -> Frame {
+        let jpeg = rgb_to_jpeg(&[200; 80 * 60 * 3], 80, 60).unwrap();
+        let fingerprint = fingerprint(&jpeg).unwrap();
+        Frame {
+            id,
+            window: Window {
+                id: 7,
+                title: "Terminal".into(),
+                app: Some("Terminal".into()),
+                bounds: WindowBounds {
+                    x: -10,
+                    y: 20,
+                    width: 40,
+                    height: 30,
+                },
+                display_id: Some(1),
+                captured_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+                jpeg_metrohash: jpeg_metrohash(&jpeg),
+                jpeg,
+                perceptual_hash: fingerprint.hash,
+                tiles: fingerprint.tiles.clone(),
+                z: 2,
+            },
+            embedding: vec![0.0, 1.0],
+            ocr,
+            image_hash: "abc".into(),
+            image_path: PathBuf::from("blobs/ab/abc"),
+            change: ChangeReport::whole(&fingerprint.tiles),
+            cropped: false,
+        }
+    }
+
+    fn line(text: &str, y: f32) -> OcrLine {
+        OcrLine {
+            text: text.into(),
+            confidence: 0.5,
+            bounds: BoundingBox {
+                x: 0.25,
+                y,
+                width: 0.5,
+                height: 0.1,
+            },
+        }
+    }
+
+    fn column<T: Array + 'static>(column: Option<&ArrayRef>) -> &T {
+        column.unwrap().as_any().downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn stores_each_frames_ocr_lines() {
+        let frames = vec![
+            frame(1, vec![line("cargo build", 0.8), line("Compiling", 0.6)]),
+            frame(2, Vec::new()),
+        ];
+        let batch = to_batch(&schema(2), 2, "test", &frames, None).unwrap();
+
+        let text: &StringArray = column(batch.column_by_name("ocr_text"));
+        assert_eq!(text.value(0), "cargo build\nCompiling");
+        assert_eq!(text.value(1), "");
+
+        let lines: &ListArray = column(batch.column_by_name("ocr_lines"));
+        assert_eq!(lines.value_offsets(), &[0, 2, 2]);
+        let first = lines.value(0);
+        let first: &StructArray = column(Some(&first));
+        let texts: &StringArray = column(first.column_by_name("text"));
+        assert_eq!(
+            (texts.value(0), texts.value(1)),
+            ("cargo build", "Compiling")
+        );
+        let ys: &Float32Array = column(first.column_by_name("y"));
+        assert_eq!(ys.values(), &[0.8, 0.6]);
+        assert!(lines.value(1).is_empty());
+    }
+
+    #[test]
+    fn seals_ocr_text_when_encrypted() {
+        let cipher = Cipher::random();
+        let frames = vec![frame(1, vec![line("hunter2", 0.5)])];
+        let batch = to_batch(&schema(2), 2, "test", &frames, Some(&cipher)).unwrap();
+
+        let text: &StringArray = column(batch.column_by_name("ocr_text"));
+        assert_ne!(text.value(0), "hunter2");
+        assert_eq!(cipher.open_text(text.value(0)).unwrap(), "hunter2");
+        let lines: &ListArray = column(batch.column_by_name("ocr_lines"));
+        let line = lines.value(0);
+        let line: &StructArray = column(Some(&line));
+        let texts: &StringArray = column(line.column_by_name("text"));
+        assert_eq!(cipher.open_text(texts.value(0)).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn records_where_and_when_each_frame_was_captured() {
+        let mut second = frame(2, Vec::new());
+        second.window.app = None;
+        second.window.display_id = None;
+        second.change.region = Region {
+            x: 40,
+            y: 30,
+            width: 20,
+            height: 10,
+        };
+        second.cropped = true;
+        let frames = vec![frame(1, Vec::new()), second];
+        let batch = to_batch(&schema(2), 2, "clip@1234", &frames, None).unwrap();
+
+        let captured_at: &TimestampMillisecondArray = column(batch.column_by_name("captured_at"));
+        assert_eq!(captured_at.value(0), 1_700_000_000_123);
+        let u32s = |name| -> Vec<u32> {
+            let values: &UInt32Array = column(batch.column_by_name(name));
+            values.iter().map(|v| v.unwrap_or(u32::MAX)).collect()
+        };
+        assert_eq!(u32s("window_id"), [7, 7]);
+        assert_eq!(u32s("z"), [2, 2]);
+        assert_eq!(u32s("window_width"), [40, 40]);
+        assert_eq!(u32s("window_height"), [30, 30]);
+        assert_eq!(u32s("display_id"), [1, u32::MAX]);
+        assert_eq!(u32s("image_width"), [80, 80]);
+        assert_eq!(u32s("image_height"), [60, 60]);
+        assert_eq!(u32s("changed_x"), [0, 40]);
+        assert_eq!(u32s("changed_y"), [0, 30]);
+        assert_eq!(u32s("changed_width"), [80, 20]);
+        assert_eq!(u32s("changed_height"), [60, 10]);
+        let window_x: &Int32Array = column(batch.column_by_name("window_x"));
+        let window_y: &Int32Array = column(batch.column_by_name("window_y"));
+        assert_eq!((window_x.value(0), window_y.value(0)), (-10, 20));
+
+        let apps: &StringArray = column(batch.column_by_name("app"));
+        assert_eq!(apps.value(0), "Terminal");
+        assert!(apps.is_null(1));
+        let bytes: &UInt64Array = column(batch.column_by_name("image_bytes"));
+        assert_eq!(bytes.value(0), frames[0].window.jpeg.len() as u64);
+        let tiers: &StringArray = column(batch.column_by_name("image_tier"));
+        assert_eq!(tiers.value(0), TIER_FULL);
+        let pinned: &BooleanArray = column(batch.column_by_name("pinned"));
+        assert!(!pinned.value(0));
+        let cropped: &BooleanArray = column(batch.column_by_name("cropped"));
+        assert_eq!((cropped.value(0), cropped.value(1)), (false, true));
+        let model_ids: &StringArray = column(batch.column_by_name("model_id"));
+        assert_eq!(model_ids.value(1), "clip@1234");
+        let paths: &StringArray = column(batch.column_by_name("image_path"));
+        assert_eq!(paths.value(0), "blobs/ab/abc");
+        assert_eq!(
+            batch.schema().metadata()[SCHEMA_VERSION_KEY],
+            SCHEMA_VERSION.to_string()
+        );
+    }
+}
@@ -0,0 +1,487 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::Path;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, PhrasePrefixQuery, PhraseQuery, Query, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, TEXT};
+use tantivy::tokenizer::{
+    LowerCaser, RemoveLongFilter, SimpleTokenizer, TextAnalyzer, Token, TokenFilter, TokenStream,
+    Tokenizer,
+};
+use tantivy::{
+    doc, Index, IndexReader, IndexWriter, ReloadPolicy, SnippetGenerator, TantivyDocument, Term,
+};
+
+use crate::crypto::Cipher;
+
+const WRITER_MEMORY_BYTES: usize = 50_000_000;
+const SNIPPET_CHARS: usize = 150;
+/// Kept alongside an index whose words are hashed, holding what the key hashes its own name to,
+/// so an index from under another key is noticed rather than silently finding nothing.
+const KEYED_MARKER: &str = "keyed";
+
+/// A full-text index over each frame's OCR text, keyed by the frame id in the `screenshots`
+/// table. The text itself isn't kept, only what's needed to find it, so snippets are made from
+/// the table's copy. When the data directory is encrypted, even the words are only kept as keyed
+/// hashes, so searches find whole words and prefixes can't be expanded.
+pub struct TextIndex {
+    index: Index,
+    reader: IndexReader,
+    fields: Fields,
+}
+
+pub struct TextIndexWriter {
+    writer: IndexWriter,
+    fields: Fields,
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    frame_id: Field,
+    text: Field,
+}
+
+#[derive(Debug)]
+pub struct TextHit {
+    pub frame_id: u64,
+    /// BM25, so only comparable with other hits from the same query.
+    pub score: f32,
+}
+
+/// Picks out and marks up the parts of a frame's text that matched a query.
+pub struct Highlighter {
+    generator: SnippetGenerator,
+    tokenizer: TextAnalyzer,
+    field: Field,
+    /// Everything the query looked for, and the prefixes among them.
+    terms: BTreeMap<String, f32>,
+    prefixes: Vec<String>,
+}
+
+/// The most relevant bit of a frame's text, with the parts that matched marked.
+#[derive(Debug, Default, Serialize)]
+pub struct Snippet {
+    pub fragment: String,
+    /// Byte ranges into `fragment`.
+    pub highlights: Vec<Range<usize>>,
+}
+
+impl Snippet {
+    /// Wraps every highlighted range in `before` and `after`.
+    pub fn highlighted(&self, before: &str, after: &str) -> String {
+        let mut out = String::new();
+        let mut last = 0;
+        for range in &self.highlights {
+            out.push_str(&self.fragment[last..range.start]);
+            out.push_str(before);
+            out.push_str(&self.fragment[range.clone()]);
+            out.push_str(after);
+            last = range.end;
+        }
+        out.push_str(&self.fragment[last..]);
+        out
+    }
+}
+
+impl TextIndex {
+    /// Opens the index in `directory`, creating it if it doesn't exist yet, with its words hashed
+    /// if there's a `cipher`. Indexes made by older versions also store the text, which is
+    /// harmless but for taking up room, and ones made before encryption was turned on keep the
+    /// words as they are until `elephant rotate-key` rebuilds them.
+    pub fn open(directory: &Path, cipher: Option<&Cipher>) -> Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        let mmap = MmapDirectory::open(directory)?;
+        if Index::exists(&mmap)? {
+            return Self::from_index(Index::open(mmap)?, directory, cipher);
+        }
+        if let Some(cipher) = cipher {
+            std::fs::write(directory.join(KEYED_MARKER), cipher.word_hash(KEYED_MARKER))?;
+        }
+        let mut builder = Schema::builder();
+        builder.add_u64_field("frame_id", INDEXED | STORED | FAST);
+        builder.add_text_field("text", TEXT);
+        let index = Index::create(mmap, builder.build(), Default::default())?;
+        Self::from_index(index, directory, cipher)
+    }
+
+    /// Opens an index that's already there, for searching without recording.
+    pub fn open_existing(directory: &Path, cipher: Option<&Cipher>) -> Result<Self> {
+        Self::from_index(Index::open_in_dir(directory)?, directory, cipher)
+    }
+
+    fn from_index(index: Index, directory: &Path, cipher: Option<&Cipher>) -> Result<Self> {
+        let marker = directory.join(KEYED_MARKER);
+        if marker.exists() {
+            let cipher = cipher.ok_or_else(|| {
+                anyhow!("The text index is encrypted but the data directory wasn't unlocked")
+            })?;
+            if std::fs::read_to_string(&marker)? != cipher.word_hash(KEYED_MARKER) {
+                return Err(anyhow!(
+                    "The text index is under a different key, run `elephant rotate-key` to \
+                     finish rebuilding it"
+                ));
+            }
+            // The text field uses the default tokenizer, so this swaps in for it everywhere
+            index
+                .tokenizers()
+                .register("default", keyed_analyzer(cipher.clone()));
+        }
+        let schema = index.schema();
+        let fields = Fields {
+            frame_id: schema.get_field("frame_id")?,
+            text: schema.get_field("text")?,
+        };
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        Ok(Self {
+            index,
+            reader,
+            fields,
+        })
+    }
+
+    /// Only one writer can be open at once, even across processes.
+    pub fn writer(&self) -> Result<TextIndexWriter> {
+        Ok(TextIndexWriter {
+            writer: self.index.writer(WRITER_MEMORY_BYTES)?,
+            fields: self.fields,
+        })
+    }
+
+    /// Finds frames matching every clause of `query`. Clauses are words, `"quoted phrases"` or
+    /// prefixes like `Connect*`.
+    pub fn search(&self, query: &str, limit: usize) -> Result<(Vec<TextHit>, Highlighter)> {
+        self.reader.reload()?;
+        let mut prefixes = Vec::new();
+        let query = self.parse(query, &mut prefixes)?;
+        let searcher = self.reader.searcher();
+
+        let mut hits = Vec::new();
+        for (score, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            if let Some(frame_id) = doc.get_first(self.fields.frame_id).and_then(|v| v.as_u64()) {
+                hits.push(TextHit { frame_id, score });
+            }
+        }
+
+        let mut terms = BTreeMap::new();
+        query.query_terms(&mut |term, _| {
+            if let Some(text) = term.value().as_str() {
+                terms.insert(text.to_string(), 1.0);
+            }
+        });
+        let highlighter = Highlighter {
+            generator: SnippetGenerator::create(&searcher, &query, self.fields.text)?,
+            tokenizer: self.index.tokenizer_for_field(self.fields.text)?,
+            field: self.fields.text,
+            terms,
+            prefixes,
+        };
+        Ok((hits, highlighter))
+    }
+
+    fn parse(&self, query: &str, prefixes: &mut Vec<String>) -> Result<BooleanQuery> {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        for (i, part) in query.split('"').enumerate() {
+            // Odd parts were inside quotes
+            let pieces = if i % 2 == 1 {
+                vec![part]
+            } else {
+                part.split_whitespace().collect()
+            };
+            for piece in pieces {
+                let (piece, prefix) = match piece.strip_suffix('*') {
+                    Some(stripped) => (stripped, true),
+                    None => (piece, false),
+                };
+                if let Some(clause) = self.clause(piece, prefix, prefixes)? {
+                    clauses.push((Occur::Must, clause));
+                }
+            }
+        }
+        Ok(BooleanQuery::new(clauses))
+    }
+
+    /// Tokenizes `text` the same way the index did, so things like `ERR-1234` turn into phrases.
+    fn clause(
+        &self,
+        text: &str,
+        prefix: bool,
+        prefixes: &mut Vec<String>,
+    ) -> Result<Option<Box<dyn Query>>> {
+        let mut tokenizer = self.index.tokenizer_for_field(self.fields.text)?;
+        let mut stream = tokenizer.token_stream(text);
+        let mut terms = Vec::new();
+        while let Some(token) = stream.next() {
+            terms.push(Term::from_field_text(self.fields.text, &token.text));
+        }
+
+        Ok(match terms.len() {
+            0 => None,
+            _ if prefix => {
+                if let Some(last) = terms
+                    .last()
+                    .and_then(|t| t.value().as_str().map(String::from))
+                {
+                    prefixes.push(last);
+                }
+                Some(Box::new(PhrasePrefixQuery::new(terms)))
+            }
+            1 => Some(Box::new(TermQuery::new(
+                terms.remove(0),
+                IndexRecordOption::WithFreqsAndPositions,
+            ))),
+            _ => Some(Box::new(PhraseQuery::new(terms))),
+        })
+    }
+}
+
+impl Highlighter {
+    pub fn snippet(&self, text: &str) -> Snippet {
+        let snippet = self.tantivy_snippet(text);
+        Snippet {
+            fragment: snippet.fragment().to_string(),
+            highlights: snippet.highlighted().to_vec(),
+        }
+    }
+
+    /// Tantivy only highlights the terms it knows about up front, which doesn't include whatever
+    /// the prefixes expanded to.
+    fn tantivy_snippet(&self, text: &str) -> tantivy::Snippet {
+        if self.prefixes.is_empty() {
+            return self.generator.snippet(text);
+        }
+
+        let mut terms = self.terms.clone();
+        let mut tokenizer = self.tokenizer.clone();
+        {
+            let mut stream = tokenizer.token_stream(text);
+            while let Some(token) = stream.next() {
+                if self
+                    .prefixes
+                    .iter()
+                    .any(|p| token.text.starts_with(p.as_str()))
+                {
+                    terms.insert(token.text.clone(), 1.0);
+                }
+            }
+        }
+        SnippetGenerator::new(terms, tokenizer, self.field, SNIPPET_CHARS).snippet(text)
+    }
+}
+
+impl TextIndexWriter {
+    pub fn add(&mut self, frame_id: u64, text: &str) -> Result<()> {
+        self.writer.add_document(doc!(
+            self.fields.frame_id => frame_id,
+            self.fields.text => text,
+        ))?;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, frame_id: u64) {
+        self.writer
+            .delete_term(Term::from_field_u64(self.fields.frame_id, frame_id));
+    }
+
+    /// Nothing is visible to searches, or safe from crashes, until this is called.
+    pub fn commit(&mut self) -> Result<()> {
+        self.writer.commit()?;
+        Ok(())
+    }
+}
+
+/// Tantivy's default analyzer, but with each word swapped for its keyed hash. Offsets still point
+/// into the text, so snippets of it are highlighted as usual.
+fn keyed_analyzer(cipher: Cipher) -> TextAnalyzer {
+    TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .filter(WordHashes(cipher))
+        .build()
+}
+
+#[derive(Clone)]
+struct WordHashes(Cipher);
+
+impl TokenFilter for WordHashes {
+    type Tokenizer<T: Tokenizer> = WordHashTokenizer<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> WordHashTokenizer<T> {
+        WordHashTokenizer {
+            tokenizer,
+            cipher: self.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct WordHashTokenizer<T> {
+    tokenizer: T,
+    cipher: Cipher,
+}
+
+impl<T: Tokenizer> Tokenizer for WordHashTokenizer<T> {
+    type TokenStream<'a> = WordHashStream<'a, T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        WordHashStream {
+            tail: self.tokenizer.token_stream(text),
+            cipher: &self.cipher,
+        }
+    }
+}
+
+struct WordHashStream<'a, T> {
+    tail: T,
+    cipher: &'a Cipher,
+}
+
+impl<T: TokenStream> TokenStream for WordHashStream<'_, T> {
+    fn advance(&mut self) -> bool {
+        if !self.tail.advance() {
+            return false;
+        }
+        let token = self.tail.token_mut();
+        token.text = self.cipher.word_hash(&token.text);
+        true
+    }
+
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(directory: &Path, texts: &[(u64, &str)]) -> TextIndex {
+        let index = TextIndex::open(directory, None).unwrap();
+        let mut writer = index.writer().unwrap();
+        for (frame_id, text) in texts {
+            writer.add(*frame_id, text).unwrap();
+        }
+        writer.commit().unwrap();
+        index
+    }
+
+    fn frame_ids(index: &TextIndex, query: &str) -> Vec<u64> {
+        let mut ids: Vec<u64> = index
+            .search(query, 10)
+            .unwrap()
+            .0
+            .iter()
+            .map(|h| h.frame_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn finds_phrases_only_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(
+            dir.path(),
+            &[
+                (1, "Connection refused by the server"),
+                (2, "the server refused the connection"),
+                (3, "Build failed with ERR-1234"),
+                (4, "1234 ERR"),
+            ],
+        );
+
+        assert_eq!(frame_ids(&index, "\"connection refused\""), [1]);
+        assert_eq!(frame_ids(&index, "refused connection"), [1, 2]);
+        assert_eq!(frame_ids(&index, "\"refused server\""), Vec::<u64>::new());
+        // Split up by the tokenizer, so it's a phrase without needing quotes
+        assert_eq!(frame_ids(&index, "ERR-1234"), [3]);
+    }
+
+    #[test]
+    fn expands_prefixes() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(
+            dir.path(),
+            &[
+                (1, "Connection refused"),
+                (2, "connected to the network"),
+                (3, "disconnect"),
+                (4, "refused by the server"),
+            ],
+        );
+
+        assert_eq!(frame_ids(&index, "Connect*"), [1, 2]);
+        assert_eq!(frame_ids(&index, "\"refused by t*\""), [4]);
+        assert_eq!(frame_ids(&index, "Connect* refused"), [1]);
+    }
+
+    #[test]
+    fn highlights_what_matched() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = "Connection refused, reconnecting to the server";
+        let index = index(dir.path(), &[(1, text)]);
+
+        let (_, highlighter) = index.search("Connect* server", 10).unwrap();
+        assert_eq!(
+            highlighter.snippet(text).highlighted("[", "]"),
+            "[Connection] refused, reconnecting to the [server]"
+        );
+
+        let (_, highlighter) = index.search("\"to the\"", 10).unwrap();
+        let snippet = highlighter.snippet(text);
+        assert_eq!(
+            snippet.highlighted("<b>", "</b>"),
+            "Connection refused, reconnecting <b>to</b> <b>the</b> server"
+        );
+        assert_eq!(&snippet.fragment[snippet.highlights[0].clone()], "to");
+    }
+
+    #[test]
+    fn forgets_deleted_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(dir.path(), &[(1, "hello"), (2, "hello again")]);
+        let mut writer = index.writer().unwrap();
+        writer.delete(1);
+        writer.commit().unwrap();
+
+        assert_eq!(frame_ids(&index, "hello"), [2]);
+    }
+
+    #[test]
+    fn keeps_only_hashes_of_the_words_when_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::random();
+        let index = TextIndex::open(dir.path(), Some(&cipher)).unwrap();
+        let mut writer = index.writer().unwrap();
+        writer.add(1, "Connection refused by the Server").unwrap();
+        writer.add(2, "the server is fine").unwrap();
+        writer.commit().unwrap();
+
+        let (hits, highlighter) = index.search("\"refused by\" server", 10).unwrap();
+        assert_eq!(hits.iter().map(|h| h.frame_id).collect::<Vec<_>>(), [1]);
+        let snippet = highlighter.snippet("Connection refused by the Server");
+        assert_eq!(
+            snippet.highlighted("[", "]"),
+            "Connection [refused] [by] the [Server]"
+        );
+
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let bytes = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(!bytes.windows(7).any(|w| w == b"refused"));
+        }
+
+        drop(index);
+        assert!(TextIndex::open_existing(dir.path(), None).is_err());
+        assert!(TextIndex::open_existing(dir.path(), Some(&Cipher::random())).is_err());
+    }
+}
@@ -1,158 +1,143 @@
+#[cfg(target_os = "macos")]
 #[macro_use]
 extern crate objc;
 
-use cocoa::appkit::{
-    NSApp, NSApplication, NSApplicationActivateIgnoringOtherApps,
-    NSApplicationActivationPolicyAccessory, NSBackingStoreBuffered, NSImage, NSMenu, NSMenuItem,
-    NSRunningApplication, NSSquareStatusItemLength, NSStatusBar, NSStatusItem, NSWindow,
-    NSWindowStyleMask,
-};
-use cocoa::base::{id, nil, NO};
-use cocoa::delegate;
-use cocoa::foundation::{NSAutoreleasePool, NSData, NSPoint, NSRect, NSSize, NSString};
-use core_graphics::access::ScreenCaptureAccess;
-
-use objc::runtime::{Object, Sel};
+use anyhow::Context as _;
+use clap::Parser;
 use std::collections::HashMap;
-use std::os::raw::c_void;
 use std::sync::{Arc, Mutex};
-use std::thread;
-
+use tokio_util::sync::CancellationToken;
+
+#[cfg(target_os = "macos")]
+mod app;
+mod blobs;
+mod capture;
+mod cli;
+#[cfg(feature = "onnx")]
+mod clip;
+mod config;
+mod crypto;
+mod data_dir;
+mod diff;
+mod embedder;
+mod frames;
+mod fts;
+mod google_auth;
+mod migrations;
+#[cfg(target_os = "macos")]
 mod objc_ffi;
+mod ocr;
+mod openai;
+mod pipeline;
+mod queue;
+mod replay;
+mod retention;
+mod rotation;
+#[cfg(target_os = "macos")]
 mod screenshots;
+mod search;
+mod tesseract;
 mod types;
+mod vector_index;
+mod vertex;
+#[cfg(target_os = "macos")]
+mod vision;
 mod worker;
+#[cfg(target_os = "linux")]
+mod x11;
 
-use crate::objc_ffi::NSTextView;
-use crate::types::State;
-use crate::worker::record_state_loop;
-
-extern "C" fn should_close(_: &Object, _: Sel, _: id) -> bool {
-    return false;
-}
-
-extern "C" fn close(this: &Object, _: Sel, _: id) {
-    unsafe {
-        // Am I bad a person for dropping the Arc? Yes I am.
-        let state_ptr = *this.get_ivar::<*mut c_void>("state") as *mut Mutex<State>;
-        let mut state = (*(state_ptr as *mut Mutex<State>)).lock().unwrap();
-        state.window_open = false;
-    }
-}
-
-extern "C" fn open(this: &Object, _: Sel, _: id) {
-    unsafe {
-        // Am I bad a person for dropping the Arc? Yes I am.
-        let state_ptr = *this.get_ivar::<*mut c_void>("state") as *mut Mutex<State>;
-        let mut state = (*(state_ptr as *mut Mutex<State>)).lock().unwrap();
-        let window_delegate: id = *this.get_ivar("window_delegate");
-        if !state.window_open {
-            open_window(&mut state, window_delegate);
-        }
-    }
-}
-
-extern "C" fn will_terminate(_: &Object, _: Sel, _: id) {
-    // Required? Idk
-}
+use crate::capture::backend_from_env;
+use crate::cli::{Cli, Command};
+use crate::config::{Config, ConfigWatcher};
+use crate::data_dir::DataDir;
+use crate::embedder::embedder_from_config;
+use crate::ocr::engine_from_config;
+use crate::types::{Health, State};
 
-fn add_to_status_bar() {
-    unsafe {
-        let menu = NSMenu::new(nil).autorelease();
-        let open = NSMenuItem::new(nil)
-            .initWithTitle_action_keyEquivalent_(
-                NSString::alloc(nil).init_str("Open"),
-                sel!(open:),
-                NSString::alloc(nil).init_str(""),
-            )
-            .autorelease();
-        menu.addItem_(open);
-        let quit = NSMenuItem::new(nil)
-            .initWithTitle_action_keyEquivalent_(
-                NSString::alloc(nil).init_str("Quit"),
-                sel!(terminate:),
-                NSString::alloc(nil).init_str(""),
-            )
-            .autorelease();
-        menu.addItem_(quit);
-
-        let icon = include_bytes!("icon.svg");
-        let icon_data =
-            NSData::dataWithBytes_length_(nil, icon.as_ptr() as *mut c_void, icon.len() as u64);
-        let icon_image = NSImage::initWithData_(NSImage::alloc(nil), icon_data);
-        let _r: bool = msg_send![icon_image, setTemplate: true];
-        let status_bar = NSStatusBar::systemStatusBar(nil);
-        let status_item = status_bar.statusItemWithLength_(NSSquareStatusItemLength);
-        let status_button = status_item.button();
-        cocoa::appkit::NSButton::setImage_(status_button, icon_image);
-        status_item.setMenu_(menu);
+fn main() {
+    let command = Cli::parse().command;
+    let config_path = Config::path();
+    let config = Config::load(config_path.as_deref()).unwrap_or_else(|e| fail(e));
+    let data_dir = DataDir::from_config(&config).unwrap_or_else(|e| fail(e));
+    let result = match command {
+        Some(Command::Search(args)) => cli::search(args, data_dir, config),
+        Some(Command::Migrate(args)) => cli::migrate(args, data_dir),
+        Some(Command::Gc(args)) => cli::gc(args, data_dir),
+        Some(Command::Prune(args)) => cli::prune(args, data_dir, config),
+        Some(Command::Pin(args)) => cli::pin(args, data_dir),
+        Some(Command::Index(args)) => cli::index(args, data_dir, config),
+        Some(Command::Show(args)) => cli::show(args, data_dir),
+        Some(Command::Queue) => cli::queue(data_dir),
+        Some(Command::RotateKey) => cli::rotate_key(data_dir, config),
+        None => ConfigWatcher::new(config_path, config)
+            .context("Unable to watch the config file")
+            .and_then(|watcher| record(data_dir, watcher)),
+    };
+    if let Err(e) = result {
+        fail(e);
     }
 }
 
-fn open_window(state: &mut State, window_delegate: id) {
-    unsafe {
-        let window = NSWindow::alloc(nil).initWithContentRect_styleMask_backing_defer_(
-            NSRect::new(NSPoint::new(0., 0.), NSSize::new(200., 200.)),
-            NSWindowStyleMask::NSTitledWindowMask
-                | NSWindowStyleMask::NSClosableWindowMask
-                | NSWindowStyleMask::NSMiniaturizableWindowMask,
-            NSBackingStoreBuffered,
-            NO,
-        );
-        window.cascadeTopLeftFromPoint_(NSPoint::new(20., 20.));
-        window.center();
-        let title = NSString::alloc(nil).init_str("Hello World!").autorelease();
-        window.setTitle_(title);
-        window.setDelegate_(window_delegate);
-
-        let count = state.windows.len().to_string();
-        let content = NSTextView::alloc(nil).initWithFrame_(window.contentView().frame());
-        content.setEditable_(NO);
-        content.setString_(NSString::alloc(nil).init_str(&count).autorelease());
-        window.setContentView_(content);
-
-        window.makeKeyAndOrderFront_(nil);
-
-        let current_app = NSRunningApplication::currentApplication(nil);
-        current_app.activateWithOptions_(NSApplicationActivateIgnoringOtherApps);
-        state.window_open = true;
-    }
+fn fail(error: anyhow::Error) -> ! {
+    eprintln!("{:#}", error);
+    std::process::exit(1);
 }
 
-fn main() {
-    unsafe {
-        let pool = NSAutoreleasePool::new(nil);
-
-        if ScreenCaptureAccess::default().preflight() == false {
-            println!("Don't have permission to take screenshots, exiting");
-            return;
+fn record(data_dir: DataDir, watcher: ConfigWatcher) -> anyhow::Result<()> {
+    data_dir
+        .create()
+        .context("Unable to set up the data directory")?;
+    data_dir.warn_about_old_layout();
+    let _lock = data_dir
+        .lock()
+        .context("Unable to lock the data directory")?;
+    let data_dir = data_dir
+        .unlock_or_create_key(&watcher.subscribe().borrow())
+        .context("Unable to unlock the data directory")?;
+    let state = Arc::new(Mutex::new(State {
+        windows: HashMap::new(),
+        #[cfg(target_os = "macos")]
+        window_open: false,
+        health: Health::default(),
+    }));
+    let config = watcher.subscribe();
+    let embedder =
+        embedder_from_config(&config.borrow().embedder).context("Unable to set up embeddings")?;
+    let ocr = engine_from_config(&config.borrow().ocr).context("Unable to set up OCR")?;
+
+    // Ctrl-C or a SIGTERM finishes off the frames in flight, and a second one doesn't wait
+    let cancel = CancellationToken::new();
+    let cancel_on_signal = cancel.clone();
+    ctrlc::set_handler(move || {
+        if cancel_on_signal.is_cancelled() {
+            std::process::exit(130);
         }
-
-        let state = Arc::new(Mutex::new(State {
-            windows: HashMap::new(),
-            window_open: false,
-        }));
-        let cloned = Arc::clone(&state);
-        thread::spawn(|| {
-            record_state_loop(cloned).unwrap();
-        });
-
-        let window_delegate = delegate!("WindowDelegate", {
-            state: *mut c_void = Arc::<Mutex<State>>::as_ptr(&state) as *const c_void,
-            (windowWillClose:) => close as extern "C" fn(&Object, Sel, id)
-        });
-
-        let app = NSApp();
-        app.setActivationPolicy_(NSApplicationActivationPolicyAccessory);
-        app.setDelegate_(delegate!("AppDelegate", {
-            state: *mut c_void = Arc::<Mutex<State>>::as_ptr(&state) as *const c_void,
-            window_delegate: id = window_delegate,
-            (open:) => open as extern "C" fn(&Object, Sel, id),
-            (applicationShouldTerminateAfterLastWindowClosed:) => should_close as extern "C" fn(&Object, Sel, id) -> bool,
-            (applicationWillTerminate:) => will_terminate as extern "C" fn(&Object, Sel, id)
-        }));
-        add_to_status_bar();
-        app.run();
-        pool.drain();
-    }
+        println!("Finishing up, press Ctrl-C again to quit straight away");
+        cancel_on_signal.cancel();
+    })
+    .context("Unable to handle signals")?;
+
+    #[cfg(target_os = "macos")]
+    app::run(
+        state,
+        data_dir,
+        config,
+        backend_from_env,
+        embedder,
+        ocr,
+        cancel,
+    );
+
+    // There's no status bar to live in, so just record in the foreground
+    #[cfg(not(target_os = "macos"))]
+    worker::record_state_loop(
+        state,
+        data_dir,
+        config,
+        backend_from_env,
+        embedder,
+        ocr,
+        cancel,
+    )?;
+    Ok(())
 }
@@ -0,0 +1,763 @@
+use anyhow::{anyhow, Result};
+use arrow_array::{
+    new_null_array, Array, ArrayRef, BooleanArray, Int32Array, RecordBatch, RecordBatchIterator,
+    StringArray, TimestampMillisecondArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use futures::TryStreamExt;
+use metrohash::MetroHash64;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::blobs::BlobStore;
+use crate::data_dir::DataDir;
+use crate::frames::{self, SCHEMA_VERSION, SCHEMA_VERSION_KEY};
+use crate::fts::TextIndexWriter;
+
+/// One step from the version before `to` up to `to`.
+struct Migration {
+    to: u32,
+    description: &'static str,
+    /// Upgrades a batch of rows in the previous version's schema.
+    rewrite: fn(RecordBatch, &mut Context) -> Result<RecordBatch>,
+}
+
+/// Where `rewrite_table` writes the new table before swapping it in.
+const STAGING_TABLE: &str = "screenshots_rewritten";
+/// Where the old table goes while it's swapped out.
+const RETIRED_TABLE: &str = "screenshots_retired";
+
+/// In order. Every one of them rewrites the whole table, so they're all run together in a single
+/// pass and the table is only replaced once.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 2,
+        description: "add empty ocr_text and ocr_lines columns",
+        rewrite: add_ocr,
+    },
+    Migration {
+        to: 3,
+        description: "give every frame a frame_id and index its OCR text",
+        rewrite: add_frame_ids,
+    },
+    Migration {
+        to: 4,
+        description: "add window and image metadata, guessing what we can from the stored JPEGs",
+        rewrite: add_metadata,
+    },
+    Migration {
+        to: 5,
+        description: "add changed region columns, covering the whole image",
+        rewrite: add_changed_region,
+    },
+    Migration {
+        to: 6,
+        description: "move screenshots into the blob store and add an image_hash column",
+        rewrite: add_image_hashes,
+    },
+    Migration {
+        to: 7,
+        description: "add image_tier and pinned columns for retention",
+        rewrite: add_retention,
+    },
+];
+
+struct Context<'a> {
+    /// Counts rows across batches so made up ids don't collide.
+    row: u64,
+    data_dir: &'a DataDir,
+    text_writer: Option<&'a mut TextIndexWriter>,
+    /// Screenshots copied into the blob store, to delete once the new table is in place. Frames
+    /// that looked exactly alike share one.
+    moved: HashSet<PathBuf>,
+}
+
+/// What a migration did, or would do on a dry run.
+pub struct Plan {
+    pub from: u32,
+    pub to: u32,
+    pub rows: usize,
+    pub steps: Vec<&'static str>,
+    pub backup: PathBuf,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} rows in {} from version {} to {}, backed up to {}",
+            self.rows,
+            frames::TABLE,
+            self.from,
+            self.to,
+            self.backup.display()
+        )?;
+        for step in &self.steps {
+            writeln!(f, "  - {}", step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Brings the `screenshots` table in `database` up to `SCHEMA_VERSION`, returning what was done or
+/// `None` if it was already current. The table's directory is copied aside before it's touched.
+///
+/// Rows are rewritten in memory, so this needs room for the whole table.
+pub async fn migrate(
+    db: &lancedb::connection::Connection,
+    data_dir: &DataDir,
+    text_writer: Option<&mut TextIndexWriter>,
+    dry_run: bool,
+) -> Result<Option<Plan>> {
+    if !db
+        .table_names()
+        .execute()
+        .await?
+        .iter()
+        .any(|name| name == frames::TABLE)
+    {
+        return Ok(None);
+    }
+
+    let table = db.open_table(frames::TABLE).execute().await?;
+    let schema = table.schema().await?;
+    let from = stored_version(&schema)?;
+    if from > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "{} is at schema version {}, which is newer than this elephant understands ({})",
+            frames::TABLE,
+            from,
+            SCHEMA_VERSION
+        ));
+    }
+    if from == SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.to > from).collect();
+    let backup = data_dir.backups().join(format!(
+        "{}-v{}-{}.lance",
+        frames::TABLE,
+        from,
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    ));
+    let plan = Plan {
+        from,
+        to: SCHEMA_VERSION,
+        rows: table.count_rows(None).await?,
+        steps: pending.iter().map(|m| m.description).collect(),
+        backup,
+    };
+    if dry_run {
+        return Ok(Some(plan));
+    }
+
+    let target = frames::schema(dimension(&schema)?);
+    let mut context = Context {
+        row: 0,
+        data_dir,
+        text_writer,
+        moved: HashSet::new(),
+    };
+    rewrite_table(db, data_dir, &plan.backup, &target, |mut batch| {
+        let rows = batch.num_rows() as u64;
+        for migration in &pending {
+            batch = (migration.rewrite)(batch, &mut context)?;
+        }
+        context.row += rows;
+        Ok(batch)
+    })
+    .await?;
+    if let Some(text_writer) = context.text_writer {
+        text_writer.commit()?;
+    }
+    for path in &context.moved {
+        // Already gone is as good as deleted, now the blob store has it
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(anyhow!("Unable to remove {}: {}", path.display(), e));
+            }
+        }
+    }
+    if !context.moved.is_empty() {
+        // Only goes once nothing else has been left in it
+        let _ = std::fs::remove_dir(data_dir.screenshots());
+    }
+    Ok(Some(plan))
+}
+
+/// Copies the `screenshots` table to `backup`, then replaces it with every batch passed through
+/// `rewrite` and put into `target`'s shape. Rows are rewritten in memory, so this needs room for
+/// the whole table.
+///
+/// The new table is written under another name and only swapped in once it's complete, so if
+/// anything goes wrong the old one is still there.
+pub async fn rewrite_table(
+    db: &lancedb::connection::Connection,
+    data_dir: &DataDir,
+    backup: &Path,
+    target: &SchemaRef,
+    rewrite: impl FnMut(RecordBatch) -> Result<RecordBatch>,
+) -> Result<()> {
+    let current = table_dir(data_dir, frames::TABLE);
+    copy_dir(&current, backup)?;
+    replace_table(db, data_dir, target, rewrite)
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Unable to rewrite {}: {:#}. It was backed up to {} first",
+                frames::TABLE,
+                e,
+                backup.display()
+            )
+        })
+}
+
+async fn replace_table(
+    db: &lancedb::connection::Connection,
+    data_dir: &DataDir,
+    target: &SchemaRef,
+    mut rewrite: impl FnMut(RecordBatch) -> Result<RecordBatch>,
+) -> Result<()> {
+    let table = db.open_table(frames::TABLE).execute().await?;
+    let mut batches = Vec::new();
+    let mut stream = table.query().execute_stream().await?;
+    while let Some(batch) = stream.try_next().await? {
+        batches.push(project(&rewrite(batch)?, target)?);
+    }
+
+    // Left over from an earlier attempt that didn't finish
+    let staged = table_dir(data_dir, STAGING_TABLE);
+    if staged.exists() {
+        std::fs::remove_dir_all(&staged)?;
+    }
+    if batches.is_empty() {
+        db.create_empty_table(STAGING_TABLE, target.clone())
+            .execute()
+            .await?;
+    } else {
+        db.create_table(
+            STAGING_TABLE,
+            Box::new(RecordBatchIterator::new(
+                batches.into_iter().map(Ok),
+                target.clone(),
+            )),
+        )
+        .execute()
+        .await?;
+    }
+
+    // A lance table is just a directory, so swapping them over is a couple of renames
+    let current = table_dir(data_dir, frames::TABLE);
+    let retired = table_dir(data_dir, RETIRED_TABLE);
+    if retired.exists() {
+        std::fs::remove_dir_all(&retired)?;
+    }
+    std::fs::rename(&current, &retired)?;
+    if let Err(e) = std::fs::rename(&staged, &current) {
+        std::fs::rename(&retired, &current)?;
+        return Err(e.into());
+    }
+    std::fs::remove_dir_all(&retired)?;
+    Ok(())
+}
+
+fn table_dir(data_dir: &DataDir, table: &str) -> PathBuf {
+    data_dir.database().join(format!("{}.lance", table))
+}
+
+/// Tables from before the version was stored are recognized by their columns.
+fn stored_version(schema: &Schema) -> Result<u32> {
+    if let Some(version) = schema.metadata().get(SCHEMA_VERSION_KEY) {
+        return Ok(version.parse()?);
+    }
+    Ok(if schema.column_with_name("captured_at").is_some() {
+        4
+    } else if schema.column_with_name("frame_id").is_some() {
+        3
+    } else if schema.column_with_name("ocr_text").is_some() {
+        2
+    } else {
+        1
+    })
+}
+
+fn dimension(schema: &Schema) -> Result<usize> {
+    match schema.field_with_name("embedding")?.data_type() {
+        DataType::FixedSizeList(_, size) => Ok(*size as usize),
+        other => Err(anyhow!("Unexpected embedding type {}", other)),
+    }
+}
+
+fn add_ocr(batch: RecordBatch, _: &mut Context) -> Result<RecordBatch> {
+    let rows = batch.num_rows();
+    let ocr_lines = frames::ocr_line_field();
+    let ocr_lines_type = DataType::List(Arc::new(ocr_lines));
+    append(
+        batch,
+        vec![
+            (
+                Field::new("ocr_text", DataType::Utf8, true),
+                new_null_array(&DataType::Utf8, rows),
+            ),
+            (
+                Field::new("ocr_lines", ocr_lines_type.clone(), true),
+                new_null_array(&ocr_lines_type, rows),
+            ),
+        ],
+    )
+}
+
+fn add_frame_ids(batch: RecordBatch, context: &mut Context) -> Result<RecordBatch> {
+    let metrohashes = column::<UInt64Array>(&batch, "metrohash")?;
+    let texts = column::<StringArray>(&batch, "ocr_text")?;
+    let mut frame_ids = Vec::with_capacity(batch.num_rows());
+    for (i, metrohash) in metrohashes.values().iter().enumerate() {
+        let mut hasher = MetroHash64::new();
+        metrohash.hash(&mut hasher);
+        (context.row + i as u64).hash(&mut hasher);
+        let frame_id = hasher.finish();
+        frame_ids.push(frame_id);
+
+        if let Some(text_writer) = context.text_writer.as_deref_mut() {
+            if texts.is_valid(i) && !texts.value(i).is_empty() {
+                // Ids come out the same every time, so this replaces anything a failed attempt
+                // indexed rather than adding it twice
+                text_writer.delete(frame_id);
+                text_writer.add(frame_id, texts.value(i))?;
+            }
+        }
+    }
+    append(
+        batch,
+        vec![(
+            Field::new("frame_id", DataType::UInt64, false),
+            Arc::new(UInt64Array::from(frame_ids)),
+        )],
+    )
+}
+
+fn add_metadata(batch: RecordBatch, context: &mut Context) -> Result<RecordBatch> {
+    let rows = batch.num_rows();
+    let mut captured_at = Vec::with_capacity(rows);
+    let mut image_paths = Vec::with_capacity(rows);
+    let mut widths = Vec::with_capacity(rows);
+    let mut heights = Vec::with_capacity(rows);
+    let mut sizes = Vec::with_capacity(rows);
+    for metrohash in column::<UInt64Array>(&batch, "metrohash")?.values() {
+        let path = context.data_dir.screenshot_path(*metrohash);
+        let file = context.data_dir.resolve(&path);
+        // The JPEG's modification time is when it was captured, give or take an embedding call
+        let (modified, size) = match std::fs::metadata(&file) {
+            Ok(metadata) => (metadata.modified()?, metadata.len()),
+            Err(_) => (UNIX_EPOCH, 0),
+        };
+        let (width, height) = image::image_dimensions(&file).unwrap_or((0, 0));
+        captured_at.push(modified.duration_since(UNIX_EPOCH)?.as_millis() as i64);
+        image_paths.push(path.to_string_lossy().into_owned());
+        widths.push(width);
+        heights.push(height);
+        sizes.push(size);
+    }
+
+    let zeros = || Arc::new(UInt32Array::from(vec![0; rows])) as ArrayRef;
+    append(
+        batch,
+        vec![
+            (
+                Field::new(
+                    "captured_at",
+                    DataType::Timestamp(TimeUnit::Millisecond, None),
+                    false,
+                ),
+                Arc::new(TimestampMillisecondArray::from(captured_at)),
+            ),
+            (Field::new("window_id", DataType::UInt32, false), zeros()),
+            (
+                Field::new("title", DataType::Utf8, false),
+                Arc::new(StringArray::from(vec![""; rows])),
+            ),
+            (
+                Field::new("app", DataType::Utf8, true),
+                new_null_array(&DataType::Utf8, rows),
+            ),
+            (Field::new("z", DataType::UInt32, false), zeros()),
+            (
+                Field::new("window_x", DataType::Int32, false),
+                Arc::new(Int32Array::from(vec![0; rows])),
+            ),
+            (
+                Field::new("window_y", DataType::Int32, false),
+                Arc::new(Int32Array::from(vec![0; rows])),
+            ),
+            // Treat the screenshot as the whole window, which it almost is
+            (
+                Field::new("window_width", DataType::UInt32, false),
+                Arc::new(UInt32Array::from(widths.clone())),
+            ),
+            (
+                Field::new("window_height", DataType::UInt32, false),
+                Arc::new(UInt32Array::from(heights.clone())),
+            ),
+            (
+                Field::new("display_id", DataType::UInt32, true),
+                new_null_array(&DataType::UInt32, rows),
+            ),
+            (
+                Field::new("image_path", DataType::Utf8, false),
+                Arc::new(StringArray::from(image_paths)),
+            ),
+            (
+                Field::new("image_width", DataType::UInt32, false),
+                Arc::new(UInt32Array::from(widths)),
+            ),
+            (
+                Field::new("image_height", DataType::UInt32, false),
+                Arc::new(UInt32Array::from(heights)),
+            ),
+            (
+                Field::new("image_bytes", DataType::UInt64, false),
+                Arc::new(UInt64Array::from(sizes)),
+            ),
+            (
+                Field::new("model_id", DataType::Utf8, false),
+                Arc::new(StringArray::from(vec![frames::UNKNOWN_MODEL; rows])),
+            ),
+        ],
+    )
+}
+
+fn add_changed_region(batch: RecordBatch, _: &mut Context) -> Result<RecordBatch> {
+    let rows = batch.num_rows();
+    let zeros = || Arc::new(UInt32Array::from(vec![0; rows])) as ArrayRef;
+    let width = batch.column_by_name("image_width").cloned();
+    let height = batch.column_by_name("image_height").cloned();
+    append(
+        batch,
+        vec![
+            (Field::new("changed_x", DataType::UInt32, false), zeros()),
+            (Field::new("changed_y", DataType::UInt32, false), zeros()),
+            (
+                Field::new("changed_width", DataType::UInt32, false),
+                width.ok_or_else(|| anyhow!("{} is missing image_width", frames::TABLE))?,
+            ),
+            (
+                Field::new("changed_height", DataType::UInt32, false),
+                height.ok_or_else(|| anyhow!("{} is missing image_height", frames::TABLE))?,
+            ),
+            (
+                Field::new("cropped", DataType::Boolean, false),
+                Arc::new(BooleanArray::from(vec![false; rows])),
+            ),
+        ],
+    )
+}
+
+fn add_image_hashes(batch: RecordBatch, context: &mut Context) -> Result<RecordBatch> {
+    let blobs = context.data_dir.blob_store();
+    let mut paths = Vec::with_capacity(batch.num_rows());
+    let mut hashes = Vec::with_capacity(batch.num_rows());
+    for path in column::<StringArray>(&batch, "image_path")?.iter() {
+        let path = path.unwrap_or_default();
+        // Screenshots that have gone missing keep pointing at where they used to be
+        let original = context.data_dir.resolve(path);
+        match std::fs::read(&original) {
+            Ok(jpeg) => {
+                let hash = blobs.put(&jpeg)?;
+                paths.push(BlobStore::path(&hash).to_string_lossy().into_owned());
+                hashes.push(Some(hash));
+                context.moved.insert(original);
+            }
+            Err(_) => {
+                paths.push(path.to_string());
+                hashes.push(None);
+            }
+        }
+    }
+    let batch = replace(batch, "image_path", Arc::new(StringArray::from(paths)))?;
+    append(
+        batch,
+        vec![(
+            Field::new("image_hash", DataType::Utf8, true),
+            Arc::new(StringArray::from(hashes)),
+        )],
+    )
+}
+
+fn add_retention(batch: RecordBatch, _: &mut Context) -> Result<RecordBatch> {
+    let hashes = column::<StringArray>(&batch, "image_hash")?;
+    let tiers: Vec<&str> = (0..batch.num_rows())
+        .map(|i| match hashes.is_valid(i) {
+            true => frames::TIER_FULL,
+            false => frames::TIER_NONE,
+        })
+        .collect();
+    let tiers = Arc::new(StringArray::from(tiers));
+    let rows = batch.num_rows();
+    append(
+        batch,
+        vec![
+            (Field::new("image_tier", DataType::Utf8, false), tiers),
+            (
+                Field::new("pinned", DataType::Boolean, false),
+                Arc::new(BooleanArray::from(vec![false; rows])),
+            ),
+        ],
+    )
+}
+
+fn append(batch: RecordBatch, columns: Vec<(Field, ArrayRef)>) -> Result<RecordBatch> {
+    let mut fields: Vec<Field> = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.as_ref().clone())
+        .collect();
+    let mut arrays = batch.columns().to_vec();
+    for (field, array) in columns {
+        fields.push(field);
+        arrays.push(array);
+    }
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+}
+
+pub fn replace(batch: RecordBatch, name: &str, array: ArrayRef) -> Result<RecordBatch> {
+    let index = batch.schema().index_of(name)?;
+    let mut arrays = batch.columns().to_vec();
+    arrays[index] = array;
+    Ok(RecordBatch::try_new(batch.schema(), arrays)?)
+}
+
+/// Puts the columns in the order `schema` has them, and picks up its metadata.
+fn project(batch: &RecordBatch, schema: &SchemaRef) -> Result<RecordBatch> {
+    let mut arrays = Vec::new();
+    for field in schema.fields() {
+        arrays.push(
+            batch
+                .column_by_name(field.name())
+                .ok_or_else(|| anyhow!("Rewriting didn't produce a {} column", field.name()))?
+                .clone(),
+        );
+    }
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+fn column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<T>())
+        .ok_or_else(|| anyhow!("{} is missing the {} column", frames::TABLE, name))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let destination = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &destination)?;
+        } else {
+            std::fs::copy(entry.path(), destination)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::rgb_to_jpeg;
+    use crate::fts::TextIndex;
+    use arrow_array::types::Float32Type;
+    use arrow_array::FixedSizeListArray;
+
+    const DIMENSION: i32 = 4;
+
+    /// The table as the very first version wrote it, with a row per metrohash and a screenshot
+    /// for metrohash 1 only.
+    async fn v1_database(
+        data_dir: &DataDir,
+        metrohashes: &[u64],
+    ) -> lancedb::connection::Connection {
+        data_dir.create().unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("metrohash", DataType::UInt64, false),
+            Field::new(
+                "embedding",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIMENSION,
+                ),
+                true,
+            ),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(UInt64Array::from(metrohashes.to_vec())),
+                Arc::new(
+                    FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+                        metrohashes
+                            .iter()
+                            .map(|_| Some(vec![Some(0.5); DIMENSION as usize])),
+                        DIMENSION,
+                    ),
+                ),
+            ],
+        )
+        .unwrap();
+        let screenshots = data_dir.screenshots();
+        std::fs::create_dir_all(&screenshots).unwrap();
+        let jpeg = rgb_to_jpeg(&[255; 8 * 6 * 3], 8, 6).unwrap();
+        std::fs::write(data_dir.resolve(data_dir.screenshot_path(1)), jpeg).unwrap();
+
+        let db = lancedb::connect(&data_dir.database_uri().unwrap())
+            .execute()
+            .await
+            .unwrap();
+        db.create_table(
+            frames::TABLE,
+            Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)),
+        )
+        .execute()
+        .await
+        .unwrap();
+        db
+    }
+
+    async fn rows(db: &lancedb::connection::Connection) -> Vec<RecordBatch> {
+        let table = db.open_table(frames::TABLE).execute().await.unwrap();
+        table
+            .query()
+            .execute_stream()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrates_a_v1_table_to_the_current_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let db = v1_database(&data_dir, &[1, 2]).await;
+
+        let plan = migrate(&db, &data_dir, None, false).await.unwrap().unwrap();
+        assert_eq!((plan.from, plan.to, plan.rows), (1, SCHEMA_VERSION, 2));
+        assert_eq!(plan.steps.len(), MIGRATIONS.len());
+        assert!(plan.backup.is_dir());
+
+        let table = db.open_table(frames::TABLE).execute().await.unwrap();
+        let schema = table.schema().await.unwrap();
+        assert_eq!(stored_version(&schema).unwrap(), SCHEMA_VERSION);
+        assert_eq!(schema.fields(), frames::schema(DIMENSION as usize).fields());
+
+        let mut seen = 0;
+        for batch in rows(&db).await {
+            let hashes = column::<StringArray>(&batch, "image_hash").unwrap();
+            let tiers = column::<StringArray>(&batch, "image_tier").unwrap();
+            let widths = column::<UInt32Array>(&batch, "image_width").unwrap();
+            let metrohashes = column::<UInt64Array>(&batch, "metrohash").unwrap();
+            for i in 0..batch.num_rows() {
+                if metrohashes.value(i) == 1 {
+                    assert!(data_dir.blob_store().get(hashes.value(i)).is_ok());
+                    assert_eq!(tiers.value(i), frames::TIER_FULL);
+                    assert_eq!(widths.value(i), 8);
+                } else {
+                    assert!(hashes.is_null(i));
+                    assert_eq!(tiers.value(i), frames::TIER_NONE);
+                }
+                seen += 1;
+            }
+        }
+        assert_eq!(seen, 2);
+
+        // The screenshot has moved into the blob store
+        assert!(!data_dir.screenshots().exists());
+
+        // Nothing's left over from the swap, and there's nothing more to do
+        assert!(!table_dir(&data_dir, STAGING_TABLE).exists());
+        assert!(!table_dir(&data_dir, RETIRED_TABLE).exists());
+        assert!(migrate(&db, &data_dir, None, false)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn dry_runs_leave_the_table_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let db = v1_database(&data_dir, &[1, 2]).await;
+
+        let plan = migrate(&db, &data_dir, None, true).await.unwrap().unwrap();
+        assert_eq!(plan.from, 1);
+        assert!(!plan.backup.exists());
+        assert!(data_dir.resolve(data_dir.screenshot_path(1)).exists());
+        let table = db.open_table(frames::TABLE).execute().await.unwrap();
+        assert_eq!(stored_version(&table.schema().await.unwrap()).unwrap(), 1);
+    }
+
+    #[test]
+    fn indexing_text_again_replaces_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let index = TextIndex::open(&dir.path().join("fts"), None).unwrap();
+        let mut writer = index.writer().unwrap();
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "metrohash",
+                Arc::new(UInt64Array::from(vec![7])) as ArrayRef,
+            ),
+            (
+                "ocr_text",
+                Arc::new(StringArray::from(vec!["hello"])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+
+        // As if the first attempt failed after indexing, and the migration was run again
+        for _ in 0..2 {
+            let mut context = Context {
+                row: 0,
+                data_dir: &data_dir,
+                text_writer: Some(&mut writer),
+                moved: HashSet::new(),
+            };
+            add_frame_ids(batch.clone(), &mut context).unwrap();
+        }
+        writer.commit().unwrap();
+        assert_eq!(index.search("hello", 10).unwrap().0.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn moves_screenshots_shared_by_several_frames_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        // The same screenshot recorded twice, which older versions kept a single file for
+        let db = v1_database(&data_dir, &[1, 1, 2]).await;
+
+        migrate(&db, &data_dir, None, false).await.unwrap().unwrap();
+
+        let mut shared = Vec::new();
+        for batch in rows(&db).await {
+            let hashes = column::<StringArray>(&batch, "image_hash").unwrap();
+            let metrohashes = column::<UInt64Array>(&batch, "metrohash").unwrap();
+            for i in 0..batch.num_rows() {
+                if metrohashes.value(i) == 1 {
+                    shared.push(hashes.value(i).to_string());
+                }
+            }
+        }
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared[0], shared[1]);
+        assert!(data_dir.blob_store().get(&shared[0]).is_ok());
+        assert!(!data_dir.screenshots().exists());
+    }
+}
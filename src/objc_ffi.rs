@@ -69,6 +69,7 @@ pub trait VNImageRequestHandler: Sized {
     }
 
     unsafe fn initWithCGImage(self, cgImage: CGImageRef) -> id;
+    unsafe fn initWithData(self, imageData: id /* NSData */) -> id;
     unsafe fn performRequests(self, requests: &[id], error: Option<*mut id /* NSError */>) -> BOOL;
 }
 
@@ -81,6 +82,14 @@ impl VNImageRequestHandler for id {
         ]
     }
 
+    unsafe fn initWithData(self, imageData: id /* NSData */) -> id {
+        msg_send![
+            self,
+            initWithData: imageData
+            options: NSDictionary::dictionary(nil)
+        ]
+    }
+
     unsafe fn performRequests(self, requests: &[id], error: Option<*mut id /* NSError */>) -> BOOL {
         msg_send![
             self,
@@ -126,6 +135,7 @@ pub trait VNRecognizedText: Sized {
         range: &NSRange,
         error: Option<*mut id /* NSError */>,
     ) -> id /* VNRectangleObservation */;
+    unsafe fn confidence(self) -> f32;
     unsafe fn string(self) -> id /* NSString */;
 }
 
@@ -142,7 +152,21 @@ impl VNRecognizedText for id {
         ]
     }
 
+    unsafe fn confidence(self) -> f32 {
+        msg_send![self, confidence]
+    }
+
     unsafe fn string(self) -> id /* NSString */ {
         msg_send![self, string]
     }
 }
+
+pub trait VNRectangleObservation: Sized {
+    unsafe fn boundingBox(self) -> NSRect;
+}
+
+impl VNRectangleObservation for id {
+    unsafe fn boundingBox(self) -> NSRect {
+        msg_send![self, boundingBox]
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{OcrConfig, TesseractConfig};
+use crate::diff::Region;
+use crate::tesseract::TesseractOcr;
+
+/// One line of recognized text.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OcrLine {
+    pub text: String,
+    /// From 0 to 1, higher being more sure.
+    pub confidence: f32,
+    pub bounds: BoundingBox,
+}
+
+/// Where a line sits, normalized to the image size with the origin in the bottom left corner
+/// (which is what Vision hands back).
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    /// Maps a box found in `region` of a `width` by `height` image back onto the whole image.
+    pub fn uncrop(&self, region: &Region, width: u32, height: u32) -> Self {
+        let (width, height) = (width as f32, height as f32);
+        let (region_width, region_height) = (region.width as f32, region.height as f32);
+        // Regions are measured from the top, boxes from the bottom
+        let region_bottom = height - region.y as f32 - region_height;
+        Self {
+            x: (region.x as f32 + self.x * region_width) / width,
+            y: (region_bottom + self.y * region_height) / height,
+            width: self.width * region_width / width,
+            height: self.height * region_height / height,
+        }
+    }
+}
+
+/// Pulls the text out of screenshots. Calls block, and several can run at once.
+pub trait OcrEngine: Send + Sync {
+    /// Recognizes the text in a JPEG-encoded screenshot, roughly in reading order.
+    fn recognize(&self, jpeg: &[u8]) -> Result<Vec<OcrLine>>;
+}
+
+pub fn engine_from_config(config: &OcrConfig) -> Result<Option<Box<dyn OcrEngine>>> {
+    match config {
+        #[cfg(target_os = "macos")]
+        OcrConfig::Vision | OcrConfig::Auto => Ok(Some(Box::new(crate::vision::VisionOcr))),
+        #[cfg(not(target_os = "macos"))]
+        OcrConfig::Vision => Err(anyhow::anyhow!("Vision is only available on macOS")),
+        OcrConfig::Tesseract(tesseract) => Ok(Some(Box::new(tesseract_from_config(tesseract)?))),
+        #[cfg(not(target_os = "macos"))]
+        OcrConfig::Auto => match tesseract_from_config(&TesseractConfig::default()) {
+            Ok(tesseract) => Ok(Some(Box::new(tesseract))),
+            Err(e) => {
+                println!("Not running OCR: {}", e);
+                Ok(None)
+            }
+        },
+        OcrConfig::None => Ok(None),
+    }
+}
+
+fn tesseract_from_config(config: &TesseractConfig) -> Result<TesseractOcr> {
+    TesseractOcr::new(config.languages.clone(), config.page_segmentation_mode)
+}
+
+/// Flattens lines into the plain text that gets stored and searched.
+pub fn lines_to_text(lines: &[OcrLine]) -> String {
+    lines
+        .iter()
+        .map(|l| l.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncrops_boxes_onto_the_whole_image() {
+        // The bottom right quarter of a 200 by 100 image
+        let region = Region {
+            x: 100,
+            y: 50,
+            width: 100,
+            height: 50,
+        };
+        let found = BoundingBox {
+            x: 0.5,
+            y: 0.0,
+            width: 0.5,
+            height: 0.5,
+        };
+        let whole = found.uncrop(&region, 200, 100);
+        assert_eq!(
+            (whole.x, whole.y, whole.width, whole.height),
+            (0.75, 0.0, 0.25, 0.25)
+        );
+
+        // The top left quarter, where boxes are measured from the bottom of the image
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 100,
+            height: 50,
+        };
+        let whole = found.uncrop(&region, 200, 100);
+        assert_eq!((whole.x, whole.y), (0.25, 0.5));
+    }
+}
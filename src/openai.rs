@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use crate::embedder::Embedder;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct OpenAiEmbeddingRequest {
+    model: String,
+    input: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct OpenAiEmbeddingResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct OpenAiEmbedding {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+/// Anything that speaks OpenAI's `/v1/embeddings` protocol, like llama.cpp's server, vLLM or
+/// text-embeddings-inference.
+///
+/// The protocol only really knows about text, so screenshots are sent as `data:` URLs. Servers
+/// running a text-only model won't complain, they'll just embed the URL as if it were text, so
+/// the model has to be one that takes images.
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    dimension: usize,
+}
+
+impl OpenAiEmbedder {
+    /// `base_url` is the server root, with or without the trailing `/v1`.
+    pub fn new(base_url: &str, model: &str, api_key: Option<String>, dimension: usize) -> Self {
+        let base_url = base_url.trim_end_matches('/');
+        let url = if base_url.ends_with("/v1") {
+            format!("{}/embeddings", base_url)
+        } else {
+            format!("{}/v1/embeddings", base_url)
+        };
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            model: model.into(),
+            api_key,
+            dimension,
+        }
+    }
+
+    async fn embed(&self, input: String) -> Result<Vec<f32>> {
+        let mut request = self.client.post(&self.url).json(&OpenAiEmbeddingRequest {
+            model: self.model.clone(),
+            input: vec![input],
+        });
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let embedding = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OpenAiEmbeddingResponse>()
+            .await?
+            .data
+            .into_iter()
+            .find(|e| e.index == 0)
+            .ok_or_else(|| anyhow!("{} returned no embeddings", self.url))?
+            .embedding;
+        if embedding.len() != self.dimension {
+            return Err(anyhow!(
+                "Expected {} dimensions from {} but got {}",
+                self.dimension,
+                self.model,
+                embedding.len()
+            ));
+        }
+        Ok(embedding)
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    async fn embed_image(&self, jpeg: &[u8]) -> Result<Vec<f32>> {
+        self.embed(format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)))
+            .await
+    }
+
+    async fn embed_text(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed(text.into()).await
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn embeddings(data: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({ "data": data }))
+    }
+
+    #[tokio::test]
+    async fn embeds_images_as_data_urls() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .and(header("authorization", "Bearer secret"))
+            .and(body_partial_json(json!({
+                "model": "clip",
+                "input": ["data:image/jpeg;base64,/9g="],
+            })))
+            .respond_with(embeddings(
+                json!([{ "embedding": [0.5, 0.25], "index": 0 }]),
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let embedder = OpenAiEmbedder::new(&server.uri(), "clip", Some("secret".into()), 2);
+        assert_eq!(
+            embedder.embed_image(&[0xff, 0xd8]).await.unwrap(),
+            vec![0.5, 0.25]
+        );
+    }
+
+    #[tokio::test]
+    async fn embeds_text_and_accepts_a_v1_base_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .and(body_partial_json(json!({ "input": ["hello"] })))
+            .respond_with(embeddings(json!([
+                { "embedding": [9.0, 9.0], "index": 1 },
+                { "embedding": [1.0, 2.0], "index": 0 },
+            ])))
+            .mount(&server)
+            .await;
+
+        let embedder = OpenAiEmbedder::new(&format!("{}/v1/", server.uri()), "clip", None, 2);
+        assert_eq!(embedder.embed_text("hello").await.unwrap(), vec![1.0, 2.0]);
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_dimension() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(embeddings(json!([{ "embedding": [1.0], "index": 0 }])))
+            .mount(&server)
+            .await;
+
+        let embedder = OpenAiEmbedder::new(&server.uri(), "clip", None, 2);
+        let error = embedder.embed_text("hello").await.unwrap_err();
+        assert!(error.to_string().contains("Expected 2 dimensions"));
+    }
+
+    #[tokio::test]
+    async fn reports_server_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let embedder = OpenAiEmbedder::new(&server.uri(), "clip", None, 2);
+        assert!(embedder.embed_text("hello").await.is_err());
+    }
+}
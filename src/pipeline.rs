@@ -0,0 +1,876 @@
+use anyhow::{anyhow, Result};
+use arrow_array::{RecordBatchIterator, UInt64Array};
+use arrow_schema::SchemaRef;
+use futures::{stream, FutureExt, StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::blobs::BlobStore;
+use crate::config::{Config, IndexConfig, RetentionConfig};
+use crate::data_dir::DataDir;
+use crate::diff::{self, hamming_distance, ChangeReport};
+use crate::embedder::Embedder;
+use crate::frames::{self, Frame};
+use crate::fts::TextIndexWriter;
+use crate::ocr::{lines_to_text, OcrEngine, OcrLine};
+use crate::queue::{Entry, Queue};
+use crate::retention;
+use crate::types::{State, Window};
+use crate::vector_index;
+
+/// How often old frames are shrunk, dropped or deleted.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the vector index is checked on, and rebuilt if it's fallen behind.
+const INDEX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often housekeeping checks whether anything is due.
+const HOUSEKEEPING_CHECK: Duration = Duration::from_secs(60);
+
+/// The most frames written to the table in one go.
+const MAX_BATCH: usize = 64;
+
+/// What frames are OCR'd and embedded with, which can be swapped out while recording.
+#[derive(Clone)]
+pub struct Engines {
+    pub embedder: Arc<dyn Embedder>,
+    pub ocr: Option<Arc<dyn OcrEngine>>,
+}
+
+/// Every capturable window, as of one tick.
+struct Capture {
+    tick: u64,
+    windows: Vec<Window>,
+}
+
+/// A window that changed, filled in stage by stage on its way to the table.
+struct Job {
+    tick: u64,
+    frame_id: u64,
+    image_hash: String,
+    window: Window,
+    change: ChangeReport,
+    /// Whether it's been queued before, in which case it may already be in the table.
+    retried: bool,
+    /// Just the part that changed, when cropping. This is what's OCR'd and embedded.
+    crop: Option<Vec<u8>>,
+    ocr: Vec<OcrLine>,
+    embedding: Vec<f32>,
+    model_id: String,
+}
+
+impl Job {
+    fn new(tick: u64, entry: Entry, retried: bool) -> Self {
+        Self {
+            tick,
+            frame_id: entry.frame_id,
+            image_hash: entry.image_hash,
+            window: entry.window,
+            change: entry.change,
+            retried,
+            crop: None,
+            ocr: Vec::new(),
+            embedding: Vec::new(),
+            model_id: String::new(),
+        }
+    }
+
+    fn input(&self) -> &[u8] {
+        self.crop.as_deref().unwrap_or(&self.window.jpeg)
+    }
+}
+
+/// How a tick's frames fared, sent back for whoever's keeping track.
+pub enum Report {
+    /// Frames reached the table, or a tick had nothing that needed to.
+    Recorded,
+    /// A frame didn't make it, and is still queued unless it never got that far. `tick` is the
+    /// one it was last sent on.
+    Failed {
+        tick: u64,
+        frame_id: u64,
+        error: anyhow::Error,
+    },
+}
+
+/// Frames go from capture to diff to OCR to embed to persist, with each stage running on its own
+/// and passing them on through a bounded queue, so one being slow only holds up those before it
+/// once the queue in between fills up. Captures are skipped rather than held up, and everything
+/// else happens off the thread doing the capturing.
+pub struct Pipeline {
+    captures: Option<mpsc::Sender<Capture>>,
+    reports: mpsc::UnboundedReceiver<Report>,
+    stages: Vec<JoinHandle<()>>,
+    housekeeping: JoinHandle<()>,
+    stop_housekeeping: CancellationToken,
+    /// Set while housekeeping is busy.
+    housekeeping_busy: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+    /// Starts every stage. Sizes and concurrency come from `config` as it is now, while changes
+    /// to anything else are picked up as they happen.
+    pub fn start(
+        state_mutex: Arc<Mutex<State>>,
+        config: watch::Receiver<Config>,
+        engines: watch::Receiver<Engines>,
+        queue: Arc<Queue>,
+        persist: Persist,
+    ) -> Self {
+        let limits = config.borrow().pipeline.clone();
+        let (captures, captured) = mpsc::channel(limits.queue_size);
+        let (diffed_tx, diffed) = mpsc::channel(limits.queue_size);
+        let (recognized_tx, recognized) = mpsc::channel(limits.queue_size);
+        let (embedded_tx, embedded) = mpsc::channel(limits.queue_size);
+        let (reports_tx, reports) = mpsc::unbounded_channel();
+
+        let housekeeping = persist.housekeeping();
+        let housekeeping_busy = housekeeping.busy.clone();
+        let stop_housekeeping = CancellationToken::new();
+        let housekeeping =
+            tokio::spawn(housekeeping.run(config.clone(), stop_housekeeping.clone()));
+        let ocr_engines = engines.clone();
+        let ocr_config = config.clone();
+        let stages = vec![
+            tokio::spawn(diff_stage(
+                captured,
+                diffed_tx,
+                state_mutex,
+                config.clone(),
+                queue,
+                reports_tx.clone(),
+            )),
+            tokio::spawn(stage(
+                diffed,
+                recognized_tx,
+                limits.ocr_concurrency,
+                reports_tx.clone(),
+                move |job| {
+                    let crop_changes = ocr_config.borrow().capture.crop_changes;
+                    recognize(ocr_engines.borrow().ocr.clone(), crop_changes, job)
+                },
+            )),
+            tokio::spawn(stage(
+                recognized,
+                embedded_tx,
+                limits.embed_concurrency,
+                reports_tx.clone(),
+                move |job| embed(engines.borrow().embedder.clone(), job),
+            )),
+            tokio::spawn(persist.run(embedded, reports_tx)),
+        ];
+        Self {
+            captures: Some(captures),
+            reports,
+            stages,
+            housekeeping,
+            stop_housekeeping,
+            housekeeping_busy,
+        }
+    }
+
+    /// Hands a tick's windows to the diff stage. If it's still busy with earlier ones they're
+    /// dropped and this returns false, unless told to `wait`.
+    pub async fn capture(&self, tick: u64, windows: Vec<Window>, wait: bool) -> Result<bool> {
+        let captures = self
+            .captures
+            .as_ref()
+            .ok_or_else(|| anyhow!("The pipeline is finishing"))?;
+        let capture = Capture { tick, windows };
+        let sent = match wait {
+            true => captures.send(capture).await.map_err(|_| ()),
+            false => match captures.try_send(capture) {
+                Err(TrySendError::Full(_)) => return Ok(false),
+                sent => sent.map_err(|_| ()),
+            },
+        };
+        sent.map_err(|_| anyhow!("The pipeline stopped unexpectedly"))?;
+        Ok(true)
+    }
+
+    /// Waits for the next report, or returns `None` once every stage has stopped.
+    pub async fn report(&mut self) -> Option<Report> {
+        self.reports.recv().await
+    }
+
+    pub fn try_report(&mut self) -> Option<Report> {
+        self.reports.try_recv().ok()
+    }
+
+    /// Whether what's still going is retention or the vector index rather than frames.
+    pub fn is_housekeeping(&self) -> bool {
+        self.housekeeping_busy.load(Ordering::Relaxed)
+    }
+
+    /// Stops taking captures and waits for everything already captured to be written out, then
+    /// for any housekeeping under way to wrap up.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.captures = None;
+        for stage in &mut self.stages {
+            stage.await?;
+        }
+        self.stop_housekeeping.cancel();
+        (&mut self.housekeeping).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        for stage in &self.stages {
+            stage.abort();
+        }
+        self.housekeeping.abort();
+    }
+}
+
+/// Runs `work` on jobs from `input`, up to `limit` of them at once, and passes on the ones that
+/// succeed in whatever order they finish.
+async fn stage<F, W>(
+    input: mpsc::Receiver<Job>,
+    output: mpsc::Sender<Job>,
+    limit: usize,
+    reports: mpsc::UnboundedSender<Report>,
+    work: F,
+) where
+    F: Fn(Job) -> W,
+    W: Future<Output = Result<Job>>,
+{
+    let finished = stream::unfold(input, |mut input| async move {
+        input.recv().await.map(|job| (job, input))
+    })
+    .map(|job| {
+        let (tick, frame_id) = (job.tick, job.frame_id);
+        work(job).map(move |result| {
+            result.map_err(|error| Report::Failed {
+                tick,
+                frame_id,
+                error,
+            })
+        })
+    })
+    .buffer_unordered(limit);
+    tokio::pin!(finished);
+    while let Some(result) = finished.next().await {
+        match result {
+            Ok(job) => {
+                if output.send(job).await.is_err() {
+                    return;
+                }
+            }
+            Err(report) => {
+                let _ = reports.send(report);
+            }
+        }
+    }
+}
+
+/// Compares each capture against what was last recorded, and queues the windows that changed
+/// before passing them on. They're what later captures are compared against from then on, rather
+/// than once they've been stored, so they're only recorded once however long that takes. Frames
+/// that are waiting in the queue, having failed or been left over from last time, go first.
+async fn diff_stage(
+    mut input: mpsc::Receiver<Capture>,
+    output: mpsc::Sender<Job>,
+    state_mutex: Arc<Mutex<State>>,
+    config: watch::Receiver<Config>,
+    queue: Arc<Queue>,
+    reports: mpsc::UnboundedSender<Report>,
+) {
+    while let Some(Capture { tick, windows }) = input.recv().await {
+        let (threshold, limit) = {
+            let config = config.borrow();
+            (config.capture.change_threshold, config.pipeline.queue_size)
+        };
+        let waiting = {
+            let queue = queue.clone();
+            tokio::task::spawn_blocking(move || queue.take(limit))
+                .await
+                .unwrap_or_default()
+        };
+        let changed = compare_windows(&state_mutex, windows, threshold);
+        if changed.is_empty() && waiting.is_empty() {
+            let _ = reports.send(Report::Recorded);
+        }
+        for entry in waiting {
+            if output.send(Job::new(tick, entry, true)).await.is_err() {
+                return;
+            }
+        }
+        for (window, change) in changed {
+            let frame_id = frames::new_frame_id(&window);
+            let (window_id, perceptual_hash) = (window.id, window.perceptual_hash);
+            let pushed = {
+                let queue = queue.clone();
+                tokio::task::spawn_blocking(move || queue.push(frame_id, window, change))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|pushed| pushed)
+            };
+            let job = match pushed {
+                Ok(entry) => Job::new(tick, entry, false),
+                Err(error) => {
+                    // So it's recorded again next time, as a whole
+                    let mut state = state_mutex.lock().unwrap();
+                    if state
+                        .windows
+                        .get(&window_id)
+                        .is_some_and(|w| w.perceptual_hash == perceptual_hash)
+                    {
+                        state.windows.remove(&window_id);
+                    }
+                    drop(state);
+                    let error = error.context("Unable to queue a frame");
+                    let _ = reports.send(Report::Failed {
+                        tick,
+                        frame_id,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            if output.send(job).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type ChangedWindow = (Window, ChangeReport);
+
+/// Picks out the windows that look different from when they were last recorded, along with which
+/// parts of them changed, and makes every window what it'll be compared against next time.
+/// Windows whose perceptual hashes, and those of each of their tiles, are at most `threshold` bits
+/// apart count as unchanged.
+fn compare_windows(
+    state_mutex: &Mutex<State>,
+    windows: Vec<Window>,
+    threshold: u32,
+) -> Vec<ChangedWindow> {
+    let mut state = state_mutex.lock().unwrap();
+    let mut changed: Vec<ChangedWindow> = Vec::new();
+    let mut current = HashMap::new();
+    for mut window in windows {
+        let last_window = state.windows.get(&window.id);
+        if let Some(last) = last_window {
+            let resized = window.bounds.width != last.bounds.width
+                || window.bounds.height != last.bounds.height;
+            // A small change in a big window barely moves the hash of the whole thing, so it's
+            // enough for one tile to have changed as much
+            let change = match window.jpeg_metrohash == last.jpeg_metrohash {
+                true => None,
+                false => diff::compare(&last.tiles, &window.tiles, threshold),
+            };
+            let change = match change {
+                Some(change) => change,
+                None if !resized
+                    && hamming_distance(window.perceptual_hash, last.perceptual_hash)
+                        <= threshold =>
+                {
+                    // Keep comparing against what was recorded, so slow changes still add up
+                    window.perceptual_hash = last.perceptual_hash;
+                    window.tiles = last.tiles.clone();
+                    current.insert(window.id, window);
+                    continue;
+                }
+                // Changed overall without any one tile changing much, so there's nothing to
+                // narrow down
+                None => ChangeReport::whole(&window.tiles),
+            };
+            current.insert(window.id, window.clone());
+            changed.push((window, change));
+            continue;
+        }
+        let change = ChangeReport::whole(&window.tiles);
+        current.insert(window.id, window.clone());
+        changed.push((window, change));
+    }
+    state.windows = current;
+    changed
+}
+
+/// Crops out the part that changed, when `crop_changes` says to, then OCRs it.
+async fn recognize(
+    ocr: Option<Arc<dyn OcrEngine>>,
+    crop_changes: bool,
+    mut job: Job,
+) -> Result<Job> {
+    tokio::task::spawn_blocking(move || {
+        // Only the changed part of each window gets looked at when cropping
+        if crop_changes && !job.change.is_whole() {
+            job.crop = Some(diff::crop(&job.window.jpeg, &job.change.region)?);
+        }
+        let Some(ocr) = ocr else {
+            return Ok(job);
+        };
+        let mut recognized = ocr.recognize(job.input())?;
+        if job.crop.is_some() {
+            for line in &mut recognized {
+                line.bounds = line.bounds.uncrop(
+                    &job.change.region,
+                    job.window.tiles.width,
+                    job.window.tiles.height,
+                );
+            }
+        }
+        job.ocr = recognized;
+        Ok(job)
+    })
+    .await?
+}
+
+async fn embed(embedder: Arc<dyn Embedder>, mut job: Job) -> Result<Job> {
+    job.embedding = embedder.embed_image(job.input()).await?;
+    job.model_id = embedder.model_id().to_string();
+    Ok(job)
+}
+
+/// The last stage, and the only thing adding to the table and text index while recording.
+pub struct Persist {
+    table: lancedb::Table,
+    schema: SchemaRef,
+    dimension: usize,
+    data_dir: DataDir,
+    /// Held for the whole of each write, and by retention, so the two never interleave.
+    text_writer: Arc<tokio::sync::Mutex<TextIndexWriter>>,
+    queue: Arc<Queue>,
+}
+
+impl Persist {
+    pub fn new(
+        table: lancedb::Table,
+        dimension: usize,
+        data_dir: DataDir,
+        text_writer: TextIndexWriter,
+        queue: Arc<Queue>,
+    ) -> Self {
+        Self {
+            table,
+            schema: frames::schema(dimension),
+            dimension,
+            data_dir,
+            text_writer: Arc::new(tokio::sync::Mutex::new(text_writer)),
+            queue,
+        }
+    }
+
+    /// What runs retention and looks after the vector index alongside this.
+    fn housekeeping(&self) -> Housekeeping {
+        Housekeeping {
+            table: self.table.clone(),
+            data_dir: self.data_dir.clone(),
+            text_writer: self.text_writer.clone(),
+            retained_at: None,
+            indexed_at: None,
+            busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    async fn run(mut self, mut input: mpsc::Receiver<Job>, reports: mpsc::UnboundedSender<Report>) {
+        while let Some(job) = input.recv().await {
+            let mut jobs = vec![job];
+            while jobs.len() < MAX_BATCH {
+                match input.try_recv() {
+                    Ok(job) => jobs.push(job),
+                    Err(_) => break,
+                }
+            }
+            self.write(jobs, &reports).await;
+        }
+    }
+
+    async fn write(&mut self, jobs: Vec<Job>, reports: &mpsc::UnboundedSender<Report>) {
+        // Only when the embedder was just swapped out will there be more than one
+        let mut by_model: HashMap<String, Vec<Job>> = HashMap::new();
+        for job in jobs {
+            by_model.entry(job.model_id.clone()).or_default().push(job);
+        }
+        for (model_id, jobs) in by_model {
+            let origins: Vec<(u64, u64)> = jobs.iter().map(|j| (j.tick, j.frame_id)).collect();
+            if let Err(e) = self.add(&model_id, jobs).await {
+                for (tick, frame_id) in origins {
+                    let error = anyhow!("{:#}", e);
+                    let _ = reports.send(Report::Failed {
+                        tick,
+                        frame_id,
+                        error,
+                    });
+                }
+                continue;
+            }
+            let _ = reports.send(Report::Recorded);
+        }
+    }
+
+    async fn add(&mut self, model_id: &str, mut jobs: Vec<Job>) -> Result<()> {
+        let mut text_writer = self.text_writer.lock().await;
+        // Elephant may have stopped after adding these but before taking them off the queue
+        let retried: Vec<u64> = jobs
+            .iter()
+            .filter(|j| j.retried)
+            .map(|j| j.frame_id)
+            .collect();
+        if !retried.is_empty() {
+            let recorded = self.recorded(&retried).await?;
+            let (done, rest): (Vec<Job>, Vec<Job>) = jobs
+                .into_iter()
+                .partition(|job| recorded.contains(&job.frame_id));
+            jobs = rest;
+            if !done.is_empty() {
+                // Their text may not have been committed, so it's indexed again in place of any
+                for job in &done {
+                    text_writer.delete(job.frame_id);
+                    if !job.ocr.is_empty() {
+                        text_writer.add(job.frame_id, &lines_to_text(&job.ocr))?;
+                    }
+                }
+                text_writer.commit()?;
+                for job in done {
+                    self.queue.remove(job.frame_id)?;
+                }
+            }
+        }
+        if jobs.is_empty() {
+            return Ok(());
+        }
+
+        // Screenshots were stored when they were queued
+        let new_frames: Vec<Frame> = jobs
+            .into_iter()
+            .map(|job| Frame {
+                id: job.frame_id,
+                image_path: BlobStore::path(&job.image_hash),
+                image_hash: job.image_hash,
+                cropped: job.crop.is_some(),
+                window: job.window,
+                embedding: job.embedding,
+                ocr: job.ocr,
+                change: job.change,
+            })
+            .collect();
+        let new_batches = RecordBatchIterator::new(
+            vec![frames::to_batch(
+                &self.schema,
+                self.dimension,
+                model_id,
+                &new_frames,
+                self.data_dir.cipher(),
+            )?]
+            .into_iter()
+            .map(Ok),
+            self.schema.clone(),
+        );
+        self.table.add(Box::new(new_batches)).execute().await?;
+
+        for frame in &new_frames {
+            if !frame.ocr.is_empty() {
+                text_writer.add(frame.id, &lines_to_text(&frame.ocr))?;
+            }
+        }
+        text_writer.commit()?;
+        for frame in &new_frames {
+            self.queue.remove(frame.id)?;
+        }
+        Ok(())
+    }
+
+    /// Which of `frame_ids` are already in the table.
+    async fn recorded(&self, frame_ids: &[u64]) -> Result<HashSet<u64>> {
+        let ids: Vec<String> = frame_ids.iter().map(|id| id.to_string()).collect();
+        let mut recorded = HashSet::new();
+        let mut stream = self
+            .table
+            .query()
+            .filter(format!("frame_id IN ({})", ids.join(", ")))
+            .select(&["frame_id"])
+            .execute_stream()
+            .await?;
+        while let Some(batch) = stream.try_next().await? {
+            let ids = batch
+                .column_by_name("frame_id")
+                .and_then(|c| c.as_any().downcast_ref::<UInt64Array>())
+                .ok_or_else(|| anyhow!("{} is missing the frame_id column", frames::TABLE))?;
+            recorded.extend(ids.values().iter().copied());
+        }
+        Ok(recorded)
+    }
+}
+
+/// Shrinks, drops and deletes old frames, and builds the vector index, every so often. It runs
+/// beside the persist stage so a long index build doesn't hold up frames, since lance lets an
+/// index be committed around rows added meanwhile.
+struct Housekeeping {
+    table: lancedb::Table,
+    data_dir: DataDir,
+    text_writer: Arc<tokio::sync::Mutex<TextIndexWriter>>,
+    /// When retention last ran, if it has.
+    retained_at: Option<Instant>,
+    /// When the vector index was last checked on, if it has been.
+    indexed_at: Option<Instant>,
+    /// Set while either is being seen to.
+    busy: Arc<AtomicBool>,
+}
+
+impl Housekeeping {
+    /// Stops once `stop` is cancelled, though never part way through.
+    async fn run(mut self, mut config: watch::Receiver<Config>, stop: CancellationToken) {
+        let mut current = config.borrow_and_update().clone();
+        let mut check = tokio::time::interval(HOUSEKEEPING_CHECK);
+        loop {
+            tokio::select! {
+                _ = stop.cancelled() => break,
+                Ok(()) = config.changed() => {
+                    let next = config.borrow_and_update().clone();
+                    if next.retention != current.retention {
+                        self.retained_at = None;
+                    }
+                    if next.index != current.index {
+                        self.indexed_at = None;
+                    }
+                    current = next;
+                }
+                _ = check.tick() => {}
+            }
+            self.busy.store(true, Ordering::Relaxed);
+            self.maybe_apply_retention(&current.retention).await;
+            self.maybe_maintain_index(&current.index).await;
+            self.busy.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs retention every so often, and straight away when its settings change.
+    async fn maybe_apply_retention(&mut self, config: &RetentionConfig) {
+        if self
+            .retained_at
+            .is_some_and(|at| at.elapsed() < RETENTION_INTERVAL)
+        {
+            return;
+        }
+        self.retained_at = Some(Instant::now());
+        let mut text_writer = self.text_writer.lock().await;
+        match retention::apply(
+            &self.table,
+            &self.data_dir,
+            Some(&mut text_writer),
+            config,
+            false,
+        )
+        .await
+        {
+            Ok(report) if report.changed_anything() => println!("Retention: {}", report),
+            Ok(_) => {}
+            Err(e) => eprintln!("Retention failed: {:#}", e),
+        }
+    }
+
+    /// Builds the vector index once there are enough frames, and rebuilds it every so often as
+    /// more are added.
+    async fn maybe_maintain_index(&mut self, config: &IndexConfig) {
+        if self
+            .indexed_at
+            .is_some_and(|at| at.elapsed() < INDEX_INTERVAL)
+        {
+            return;
+        }
+        self.indexed_at = Some(Instant::now());
+        match vector_index::maintain(&self.table, config).await {
+            Ok(true) => match vector_index::status(&self.table).await {
+                Ok(status) => println!("Rebuilt the vector index: {}", status),
+                Err(e) => eprintln!(
+                    "Rebuilt the vector index, but can't tell how it's doing: {:#}",
+                    e
+                ),
+            },
+            Ok(false) => {}
+            Err(e) => eprintln!("Unable to build the vector index: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{jpeg_metrohash, rgb_to_jpeg};
+    use crate::fts::TextIndex;
+    use crate::ocr::BoundingBox;
+    use crate::types::{Health, WindowBounds};
+    use image::{Rgb, RgbImage};
+    use std::time::SystemTime;
+
+    /// A black 1024 by 768 window, with a small white square at `square` if anywhere.
+    fn window(square: Option<(u32, u32)>) -> Window {
+        window_of(RgbImage::from_fn(1024, 768, |x, y| match square {
+            Some((left, top)) if (left..left + 20).contains(&x) && (top..top + 20).contains(&y) => {
+                Rgb([255; 3])
+            }
+            _ => Rgb([0; 3]),
+        }))
+    }
+
+    fn window_of(image: RgbImage) -> Window {
+        let jpeg = rgb_to_jpeg(image.as_raw(), 1024, 768).unwrap();
+        let fingerprint = diff::fingerprint(&jpeg).unwrap();
+        Window {
+            id: 1,
+            title: "Editor".into(),
+            app: None,
+            bounds: WindowBounds {
+                x: 0,
+                y: 0,
+                width: 1024,
+                height: 768,
+            },
+            display_id: None,
+            captured_at: SystemTime::now(),
+            jpeg_metrohash: jpeg_metrohash(&jpeg),
+            jpeg,
+            perceptual_hash: fingerprint.hash,
+            tiles: fingerprint.tiles,
+            z: 0,
+        }
+    }
+
+    fn state() -> Mutex<State> {
+        Mutex::new(State {
+            windows: HashMap::new(),
+            #[cfg(target_os = "macos")]
+            window_open: false,
+            health: Health::default(),
+        })
+    }
+
+    #[test]
+    fn records_new_windows_whole_and_skips_unchanged_ones() {
+        let state = state();
+        let changed = compare_windows(&state, vec![window(None)], 4);
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].1.is_whole());
+        assert!(compare_windows(&state, vec![window(None)], 4).is_empty());
+    }
+
+    #[test]
+    fn skips_changes_under_the_threshold() {
+        let state = state();
+        let before = window(None);
+        let recorded = before.perceptual_hash;
+        compare_windows(&state, vec![before], 4);
+
+        // A stray pixel makes for a different JPEG, but looks the same
+        let mut image = RgbImage::new(1024, 768);
+        image.put_pixel(500, 400, Rgb([40; 3]));
+        let after = window_of(image);
+        assert_ne!(after.jpeg_metrohash, window(None).jpeg_metrohash);
+        assert!(compare_windows(&state, vec![after], 4).is_empty());
+        assert_eq!(state.lock().unwrap().windows[&1].perceptual_hash, recorded);
+    }
+
+    #[test]
+    fn small_changes_in_big_windows_count() {
+        let state = state();
+        let before = window(None);
+        let after = window(Some((600, 300)));
+        // As far as the whole window goes, it's hardly changed at all
+        assert!(hamming_distance(before.perceptual_hash, after.perceptual_hash) <= 4);
+        compare_windows(&state, vec![before], 4);
+
+        let changed = compare_windows(&state, vec![after], 4);
+        assert_eq!(changed.len(), 1);
+        let change = &changed[0].1;
+        assert_eq!(change.tiles, [2 * 8 + 4]);
+        assert_eq!((change.region.x, change.region.y), (512, 256));
+    }
+
+    #[test]
+    fn tiles_have_to_pass_the_threshold_too() {
+        let before = window(None);
+        let after = window(Some((600, 300)));
+        let tile = 2 * 8 + 4;
+        let moved = hamming_distance(before.tiles.hashes[tile], after.tiles.hashes[tile]);
+
+        let lenient = state();
+        compare_windows(&lenient, vec![before.clone()], moved);
+        assert!(compare_windows(&lenient, vec![after.clone()], moved).is_empty());
+
+        let strict = state();
+        compare_windows(&strict, vec![before], moved - 1);
+        assert_eq!(compare_windows(&strict, vec![after], moved - 1).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn indexes_the_text_of_retried_frames_already_in_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        data_dir.create().unwrap();
+        let queue = Arc::new(Queue::open(&data_dir).unwrap());
+        let ocr = vec![OcrLine {
+            text: "quarterly report".into(),
+            confidence: 1.0,
+            bounds: BoundingBox {
+                x: 0.0,
+                y: 0.0,
+                width: 1.0,
+                height: 1.0,
+            },
+        }];
+        let retried = || {
+            let window = window(None);
+            let change = ChangeReport::whole(&window.tiles);
+            let mut job = Job::new(1, queue.push(7, window, change).unwrap(), true);
+            job.ocr = ocr.clone();
+            job.embedding = vec![1.0, 0.0];
+            job
+        };
+
+        // As if it was added last time, but its text never got committed
+        let job = retried();
+        let frame = Frame {
+            id: job.frame_id,
+            image_path: BlobStore::path(&job.image_hash),
+            image_hash: job.image_hash.clone(),
+            cropped: false,
+            window: job.window.clone(),
+            embedding: job.embedding.clone(),
+            ocr: job.ocr.clone(),
+            change: job.change.clone(),
+        };
+        let schema = frames::schema(2);
+        let batch = frames::to_batch(&schema, 2, "fixed", &[frame], None).unwrap();
+        let db = lancedb::connect(&data_dir.database_uri().unwrap())
+            .execute()
+            .await
+            .unwrap();
+        let table = db
+            .create_table(
+                frames::TABLE,
+                Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)),
+            )
+            .execute()
+            .await
+            .unwrap();
+        let index = TextIndex::open(&data_dir.root().join("text"), None).unwrap();
+        let mut persist = Persist::new(
+            table.clone(),
+            2,
+            data_dir,
+            index.writer().unwrap(),
+            queue.clone(),
+        );
+
+        persist.add("fixed", vec![job]).await.unwrap();
+        // And once more, which mustn't leave it in the index twice
+        persist.add("fixed", vec![retried()]).await.unwrap();
+
+        let (hits, _) = index.search("quarterly", 10).unwrap();
+        let ids: Vec<u64> = hits.iter().map(|h| h.frame_id).collect();
+        assert_eq!(ids, [7]);
+        assert_eq!(table.count_rows(None).await.unwrap(), 1);
+        assert_eq!(queue.stats().depth, 0);
+    }
+}
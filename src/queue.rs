@@ -0,0 +1,453 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::blobs::BlobStore;
+use crate::crypto::{self, Cipher};
+use crate::data_dir::DataDir;
+use crate::diff::ChangeReport;
+use crate::types::Window;
+
+/// Failures a frame can rack up while others are getting through before it's given up on. While
+/// nothing's getting through, say because the embedding service is down, frames wait for ever.
+const MAX_STRIKES: u32 = 5;
+
+/// Frames that changed but aren't in the table yet, kept on disk so they survive OCR or embedding
+/// failing, or elephant stopping, before they're done. Each is a JSON file named after its frame
+/// id, with its screenshot in the blob store:
+///
+/// ```text
+/// queue/12345678901234567890.json
+/// ```
+///
+/// Frames are handed out one at a time, go back to waiting if they fail, and are removed once
+/// they're in the table.
+pub struct Queue {
+    dir: PathBuf,
+    blobs: BlobStore,
+    cipher: Option<Cipher>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// When everything queued was captured, by frame id.
+    pending: HashMap<u64, SystemTime>,
+    /// Handed out and not back yet.
+    in_flight: HashSet<u64>,
+    /// Goes up whenever a frame makes it.
+    progress: u64,
+    /// Strikes against frames that have failed, along with `progress` as of the last one.
+    strikes: HashMap<u64, (u32, u64)>,
+}
+
+/// A queued frame.
+#[derive(Deserialize, Serialize)]
+pub struct Entry {
+    pub frame_id: u64,
+    pub image_hash: String,
+    /// Its title is sealed on disk, if there's a key.
+    pub window: Window,
+    pub change: ChangeReport,
+}
+
+/// How much is waiting.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub depth: usize,
+    /// When the frame that's been waiting longest was captured.
+    pub oldest: Option<SystemTime>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.oldest {
+            Some(oldest) => write!(
+                f,
+                "{} frames waiting to be recorded, the oldest for {}",
+                self.depth,
+                age(oldest)
+            ),
+            None => write!(f, "Nothing waiting to be recorded"),
+        }
+    }
+}
+
+impl Queue {
+    /// Picks up whatever was left queued last time.
+    pub fn open(data_dir: &DataDir) -> Result<Self> {
+        let dir = data_dir.queue();
+        std::fs::create_dir_all(&dir)?;
+        let mut pending = HashMap::new();
+        for path in entries(&dir)? {
+            match read(&path) {
+                Ok(entry) => {
+                    pending.insert(entry.frame_id, entry.window.captured_at);
+                }
+                Err(e) => eprintln!("Ignoring {}: {:#}", path.display(), e),
+            }
+        }
+        Ok(Self {
+            dir,
+            blobs: data_dir.blob_store(),
+            cipher: data_dir.cipher().cloned(),
+            inner: Mutex::new(Inner {
+                pending,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Saves a frame that's about to be worked on, and hands it out.
+    pub fn push(&self, frame_id: u64, mut window: Window, change: ChangeReport) -> Result<Entry> {
+        let title = window.title;
+        window.title = crypto::seal_text(self.cipher.as_ref(), &title);
+        let mut entry = Entry {
+            frame_id,
+            image_hash: BlobStore::hash(&window.jpeg),
+            window,
+            change,
+        };
+        // Written before the screenshot, so gc never sees it without a reference
+        let path = self.path(frame_id);
+        let stored = self
+            .blobs
+            .put_referenced(&entry.window.jpeg, || write(&path, &entry));
+        if let Err(e) = stored {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+        entry.window.title = title;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.insert(frame_id, entry.window.captured_at);
+        inner.in_flight.insert(frame_id);
+        Ok(entry)
+    }
+
+    /// Hands out up to `limit` frames that are waiting, oldest first. Any that can't be read back
+    /// are dropped.
+    pub fn take(&self, limit: usize) -> Vec<Entry> {
+        let waiting = {
+            let mut inner = self.inner.lock().unwrap();
+            let mut waiting: Vec<(SystemTime, u64)> = inner
+                .pending
+                .iter()
+                .filter(|(frame_id, _)| !inner.in_flight.contains(frame_id))
+                .map(|(frame_id, captured_at)| (*captured_at, *frame_id))
+                .collect();
+            waiting.sort();
+            waiting.truncate(limit);
+            for (_, frame_id) in &waiting {
+                inner.in_flight.insert(*frame_id);
+            }
+            waiting
+        };
+
+        let mut entries = Vec::new();
+        for (_, frame_id) in waiting {
+            match self.load(frame_id) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    eprintln!("Dropping queued frame {}: {:#}", frame_id, e);
+                    if let Err(e) = self.remove(frame_id) {
+                        eprintln!("{:#}", e);
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    /// Forgets a frame, once it's in the table.
+    pub fn remove(&self, frame_id: u64) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.pending.remove(&frame_id);
+            inner.in_flight.remove(&frame_id);
+            inner.strikes.remove(&frame_id);
+            inner.progress += 1;
+        }
+        let path = self.path(frame_id);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(anyhow!("Unable to remove {}: {}", path.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Puts a frame that failed back to wait for another go, unless it's failed too often while
+    /// others made it. Returns whether it was given up on.
+    pub fn release(&self, frame_id: u64) -> Result<bool> {
+        let gave_up = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.in_flight.remove(&frame_id);
+            if !inner.pending.contains_key(&frame_id) {
+                return Ok(false);
+            }
+            let progress = inner.progress;
+            let (strikes, seen) = inner.strikes.entry(frame_id).or_insert((0, progress));
+            if *strikes == 0 || *seen != progress {
+                *strikes += 1;
+                *seen = progress;
+            }
+            *strikes >= MAX_STRIKES
+        };
+        if gave_up {
+            self.remove(frame_id)?;
+        }
+        Ok(gave_up)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.lock().unwrap();
+        Stats {
+            depth: inner.pending.len(),
+            oldest: inner.pending.values().min().copied(),
+        }
+    }
+
+    fn load(&self, frame_id: u64) -> Result<Entry> {
+        let mut entry = read(&self.path(frame_id))?;
+        entry.window.title = crypto::open_text(self.cipher.as_ref(), &entry.window.title)?;
+        entry.window.jpeg = self.blobs.get(&entry.image_hash)?;
+        Ok(entry)
+    }
+
+    fn path(&self, frame_id: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", frame_id))
+    }
+}
+
+/// The screenshots queued frames use, which mustn't be collected.
+pub fn image_hashes(data_dir: &DataDir) -> Result<Vec<String>> {
+    let dir = data_dir.queue();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    entries(&dir)?
+        .iter()
+        .map(|path| Ok(read(path)?.image_hash))
+        .collect()
+}
+
+/// Rewrites every queued frame's title with `reseal`, returning how many there were.
+pub fn reseal_titles(
+    data_dir: &DataDir,
+    mut reseal: impl FnMut(&str) -> Result<String>,
+) -> Result<usize> {
+    let dir = data_dir.queue();
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let paths = entries(&dir)?;
+    for path in &paths {
+        let mut entry = read(path)?;
+        entry.window.title = reseal(&entry.window.title)?;
+        write(path, &entry)?;
+    }
+    Ok(paths.len())
+}
+
+fn entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("json")) {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+fn read(path: &Path) -> Result<Entry> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&raw).map_err(|e| anyhow!("{} is invalid: {}", path.display(), e))
+}
+
+/// Written to a temporary file first, so a crash never leaves half of one behind.
+fn write(path: &Path, entry: &Entry) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_vec(entry)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Roughly how long ago `since` was, like `3h`.
+fn age(since: SystemTime) -> String {
+    let seconds = SystemTime::now()
+        .duration_since(since)
+        .map_or(0, |d| d.as_secs());
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{jpeg_metrohash, rgb_to_jpeg};
+    use crate::diff::fingerprint;
+    use crate::types::WindowBounds;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// A window captured `seconds` after the epoch, with a screenshot as grey as `shade`.
+    fn window(seconds: u64, shade: u8) -> (Window, ChangeReport) {
+        let jpeg = rgb_to_jpeg(&[shade; 64 * 48 * 3], 64, 48).unwrap();
+        let fingerprint = fingerprint(&jpeg).unwrap();
+        let window = Window {
+            id: 1,
+            title: format!("Window {}", seconds),
+            app: None,
+            bounds: WindowBounds {
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 48,
+            },
+            display_id: None,
+            captured_at: UNIX_EPOCH + Duration::from_secs(seconds),
+            jpeg_metrohash: jpeg_metrohash(&jpeg),
+            jpeg,
+            perceptual_hash: fingerprint.hash,
+            tiles: fingerprint.tiles.clone(),
+            z: 0,
+        };
+        (window, ChangeReport::whole(&fingerprint.tiles))
+    }
+
+    /// An empty queue in a data directory of its own, which lasts as long as the `TempDir`.
+    fn open() -> (tempfile::TempDir, DataDir, Queue) {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        data_dir.create().unwrap();
+        let queue = Queue::open(&data_dir).unwrap();
+        (dir, data_dir, queue)
+    }
+
+    fn push(queue: &Queue, frame_id: u64, seconds: u64) {
+        let (window, change) = window(seconds, (frame_id * 40 % 256) as u8);
+        queue.push(frame_id, window, change).unwrap();
+    }
+
+    fn ids(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|e| e.frame_id).collect()
+    }
+
+    #[test]
+    fn hands_out_the_oldest_waiting_frames_first() {
+        let (_dir, data_dir, queue) = open();
+        push(&queue, 1, 300);
+        push(&queue, 2, 100);
+        push(&queue, 3, 200);
+        // Pushing hands them out, so there's nothing left to take
+        assert!(queue.take(10).is_empty());
+
+        for frame_id in [1, 2, 3] {
+            assert!(!queue.release(frame_id).unwrap());
+        }
+        assert_eq!(ids(&queue.take(2)), [2, 3]);
+        assert_eq!(ids(&queue.take(2)), [1]);
+        assert!(queue.take(2).is_empty());
+
+        // What's on disk is picked back up, in flight or not
+        queue.remove(2).unwrap();
+        let reopened = Queue::open(&data_dir).unwrap();
+        let entries = reopened.take(10);
+        assert_eq!(ids(&entries), [3, 1]);
+        assert_eq!(entries[0].window.title, "Window 200");
+        assert_eq!(entries[0].window.jpeg, window(200, 120).0.jpeg);
+        assert_eq!(reopened.stats().depth, 2);
+        assert_eq!(
+            reopened.stats().oldest,
+            Some(UNIX_EPOCH + Duration::from_secs(200))
+        );
+    }
+
+    #[test]
+    fn drops_frames_it_cant_read_back() {
+        let (_dir, data_dir, queue) = open();
+        push(&queue, 1, 100);
+        push(&queue, 2, 200);
+        queue.release(1).unwrap();
+        queue.release(2).unwrap();
+        let hash = BlobStore::hash(&window(100, 40).0.jpeg);
+        std::fs::remove_file(data_dir.resolve(BlobStore::path(&hash))).unwrap();
+
+        assert_eq!(ids(&queue.take(10)), [2]);
+        assert_eq!(queue.stats().depth, 1);
+        assert!(!data_dir.queue().join("1.json").exists());
+    }
+
+    #[test]
+    fn only_counts_failures_while_other_frames_make_it() {
+        let (_dir, data_dir, queue) = open();
+        push(&queue, 1, 100);
+
+        // Nothing else is getting through either, so it waits however often it fails
+        for _ in 0..MAX_STRIKES * 2 {
+            assert!(!queue.release(1).unwrap());
+            assert_eq!(ids(&queue.take(1)), [1]);
+        }
+
+        // The first of those was a strike, and each one after something else makes it is another
+        for strike in 2..=MAX_STRIKES {
+            push(&queue, 100 + strike as u64, 200);
+            queue.remove(100 + strike as u64).unwrap();
+            let gave_up = queue.release(1).unwrap();
+            assert_eq!(gave_up, strike == MAX_STRIKES);
+            queue.take(1);
+        }
+        assert_eq!(queue.stats().depth, 0);
+        assert!(!data_dir.queue().join("1.json").exists());
+    }
+
+    #[test]
+    fn releasing_a_removed_frame_does_nothing() {
+        let (_dir, _, queue) = open();
+        push(&queue, 1, 100);
+        queue.remove(1).unwrap();
+
+        assert!(!queue.release(1).unwrap());
+        assert!(queue.take(10).is_empty());
+        assert_eq!(queue.stats().depth, 0);
+    }
+
+    #[test]
+    fn keeps_screenshots_pushed_while_collecting() {
+        let (_dir, data_dir, queue) = open();
+        let blobs = data_dir.blob_store();
+        std::thread::scope(|scope| {
+            let pushing = scope.spawn(|| {
+                for frame_id in 1..=200 {
+                    let (mut window, change) = window(frame_id, 0);
+                    window.jpeg = frame_id.to_le_bytes().to_vec();
+                    queue.push(frame_id, window, change).unwrap();
+                }
+            });
+            while !pushing.is_finished() {
+                blobs.take_stock();
+                let mut references = HashMap::new();
+                for hash in image_hashes(&data_dir).unwrap() {
+                    *references.entry(hash).or_insert(0) += 1;
+                }
+                blobs.gc(&references, false).unwrap();
+            }
+        });
+
+        for frame_id in 1..=200u64 {
+            let hash = BlobStore::hash(&frame_id.to_le_bytes());
+            assert!(data_dir.resolve(BlobStore::path(&hash)).is_file());
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Result};
+use image::ImageFormat;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::capture::{jpeg_metrohash, rgb_to_jpeg, CaptureBackend};
+use crate::diff::fingerprint;
+use crate::types::{Window, WindowBounds};
+
+const MANIFEST: &str = "manifest.json";
+
+/// Plays back frames recorded in a directory instead of looking at the screen.
+///
+/// The directory holds JPEG or PNG screenshots alongside a `manifest.json` like
+///
+/// ```json
+/// [
+///   {"timestamp": 1710000000000, "file": "1710000000000-17.png", "id": 17, "title": "Terminal", "z": 0},
+///   {"timestamp": 1710000010000, "file": "1710000010000-17.png", "id": 17, "title": "Terminal", "z": 0}
+/// ]
+/// ```
+///
+/// where `timestamp` is in milliseconds since the epoch. `app` and `display_id` can be given too.
+/// Frames that share a timestamp are returned together as one tick, in timestamp order, and keep
+/// their recorded timestamps.
+pub struct ReplayBackend {
+    directory: PathBuf,
+    ticks: VecDeque<Vec<ManifestFrame>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestFrame {
+    timestamp: u64,
+    file: String,
+    id: u32,
+    title: String,
+    z: usize,
+    #[serde(default)]
+    app: Option<String>,
+    #[serde(default)]
+    display_id: Option<u32>,
+}
+
+impl ReplayBackend {
+    pub fn open(directory: &Path) -> Result<Self> {
+        let raw = std::fs::read(directory.join(MANIFEST))
+            .map_err(|e| anyhow!("Unable to read {}: {}", MANIFEST, e))?;
+        let mut frames = serde_json::from_slice::<Vec<ManifestFrame>>(&raw)?;
+        frames.sort_by_key(|f| f.timestamp);
+
+        let mut ticks: VecDeque<Vec<ManifestFrame>> = VecDeque::new();
+        for frame in frames {
+            match ticks.back_mut() {
+                Some(tick) if tick[0].timestamp == frame.timestamp => tick.push(frame),
+                _ => ticks.push_back(vec![frame]),
+            }
+        }
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            ticks,
+        })
+    }
+
+    /// Returns the frame as a JPEG along with its size.
+    fn load_jpeg(&self, file: &str) -> Result<(Vec<u8>, u32, u32)> {
+        let bytes = std::fs::read(self.directory.join(file))?;
+        if image::guess_format(&bytes)? == ImageFormat::Jpeg {
+            let (width, height) = image::io::Reader::new(Cursor::new(&bytes))
+                .with_guessed_format()?
+                .into_dimensions()?;
+            return Ok((bytes, width, height));
+        }
+
+        let rgb = image::load_from_memory(&bytes)?.to_rgb8();
+        let jpeg = rgb_to_jpeg(rgb.as_raw(), rgb.width(), rgb.height())?;
+        Ok((jpeg, rgb.width(), rgb.height()))
+    }
+}
+
+impl CaptureBackend for ReplayBackend {
+    fn get_windows(&mut self) -> Result<Vec<Window>> {
+        let tick = self.ticks.pop_front().unwrap_or_default();
+        let mut windows = Vec::new();
+        for frame in tick {
+            let (jpeg, width, height) = self.load_jpeg(&frame.file)?;
+            let hash = jpeg_metrohash(&jpeg);
+            let fingerprint = fingerprint(&jpeg)?;
+            windows.push(Window {
+                id: frame.id,
+                title: frame.title,
+                app: frame.app,
+                // Recordings don't say where windows were, so pretend they filled the screen
+                bounds: WindowBounds {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                },
+                display_id: frame.display_id,
+                captured_at: UNIX_EPOCH + Duration::from_millis(frame.timestamp),
+                jpeg,
+                jpeg_metrohash: hash,
+                perceptual_hash: fingerprint.hash,
+                tiles: fingerprint.tiles,
+                z: frame.z,
+            });
+        }
+        Ok(windows)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::data_dir::DataDir;
+    use crate::embedder::Embedder;
+    use crate::frames;
+    use crate::types::{Health, State, Status};
+    use crate::worker;
+    use async_trait::async_trait;
+    use image::{Rgb, RgbImage};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::watch;
+    use tokio_util::sync::CancellationToken;
+
+    /// Two ticks, out of order. Window 1 changes between them and window 2 doesn't.
+    const FIXTURE_MANIFEST: &str = r#"[
+        {"timestamp": 2000, "file": "1-after.png", "id": 1, "title": "Terminal", "z": 0},
+        {"timestamp": 1000, "file": "1-before.png", "id": 1, "title": "Terminal", "z": 0},
+        {"timestamp": 1000, "file": "2.jpg", "id": 2, "title": "Docs", "app": "Firefox", "z": 1},
+        {"timestamp": 2000, "file": "2.jpg", "id": 2, "title": "Docs", "app": "Firefox", "z": 1}
+    ]"#;
+
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        halves(true).save(dir.path().join("1-before.png")).unwrap();
+        halves(false).save(dir.path().join("1-after.png")).unwrap();
+        let gradient = RgbImage::from_fn(64, 48, |x, _| Rgb([x as u8 * 4; 3]));
+        let jpeg = rgb_to_jpeg(gradient.as_raw(), 64, 48).unwrap();
+        std::fs::write(dir.path().join("2.jpg"), jpeg).unwrap();
+        std::fs::write(dir.path().join(MANIFEST), FIXTURE_MANIFEST).unwrap();
+        dir
+    }
+
+    /// Black on one side and white on the other, split down the middle or across it.
+    fn halves(down: bool) -> RgbImage {
+        RgbImage::from_fn(64, 48, |x, y| {
+            let white = if down { x >= 32 } else { y >= 24 };
+            Rgb([if white { 255 } else { 0 }; 3])
+        })
+    }
+
+    struct FakeEmbedder;
+
+    #[async_trait]
+    impl Embedder for FakeEmbedder {
+        async fn embed_image(&self, jpeg: &[u8]) -> Result<Vec<f32>> {
+            Ok(vec![jpeg.len() as f32, 1.0])
+        }
+
+        async fn embed_text(&self, _: &str) -> Result<Vec<f32>> {
+            Ok(vec![0.0, 1.0])
+        }
+
+        fn dimension(&self) -> usize {
+            2
+        }
+
+        fn model_id(&self) -> &str {
+            "fake"
+        }
+    }
+
+    #[test]
+    fn plays_frames_back_a_tick_at_a_time() {
+        let dir = fixture();
+        let mut backend = ReplayBackend::open(dir.path()).unwrap();
+        assert!(!backend.is_realtime());
+
+        let first = backend.get_windows().unwrap();
+        assert_eq!(first.iter().map(|w| w.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(
+            first[0].captured_at,
+            UNIX_EPOCH + Duration::from_millis(1000)
+        );
+        assert_eq!((first[0].bounds.width, first[0].bounds.height), (64, 48));
+        // PNGs come out as JPEGs like everything else
+        assert_eq!(
+            image::guess_format(&first[0].jpeg).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(first[1].app.as_deref(), Some("Firefox"));
+
+        let second = backend.get_windows().unwrap();
+        assert_eq!(
+            second[0].captured_at,
+            UNIX_EPOCH + Duration::from_millis(2000)
+        );
+        assert_ne!(second[0].perceptual_hash, first[0].perceptual_hash);
+        assert_eq!(second[1].jpeg_metrohash, first[1].jpeg_metrohash);
+        assert!(backend.is_exhausted());
+        assert!(backend.get_windows().unwrap().is_empty());
+    }
+
+    #[test]
+    fn records_changed_windows_until_the_replay_runs_out() {
+        let fixture = fixture();
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        data_dir.create().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            windows: HashMap::new(),
+            #[cfg(target_os = "macos")]
+            window_open: false,
+            health: Health::default(),
+        }));
+        let (_config, receiver) = watch::channel(Config::default());
+        // Otherwise it would start over forever if recording broke
+        let cancel = CancellationToken::new();
+        let give_up = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(60));
+            give_up.cancel();
+        });
+
+        worker::record_state_loop(
+            state.clone(),
+            data_dir.clone(),
+            receiver,
+            || Ok(Box::new(ReplayBackend::open(fixture.path())?)),
+            Box::new(FakeEmbedder),
+            None,
+            cancel,
+        )
+        .unwrap();
+        assert_eq!(state.lock().unwrap().health.status, Status::Stopped);
+
+        // Both windows the first time, then only the one that changed
+        let rows = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let db = lancedb::connect(&data_dir.database_uri().unwrap())
+                .execute()
+                .await
+                .unwrap();
+            let table = db.open_table(frames::TABLE).execute().await.unwrap();
+            (
+                table.count_rows(None).await.unwrap(),
+                table
+                    .count_rows(Some("window_id = 1".into()))
+                    .await
+                    .unwrap(),
+            )
+        });
+        assert_eq!(rows, (3, 2));
+    }
+}
@@ -0,0 +1,465 @@
+use anyhow::{anyhow, Result};
+use arrow_array::{Array, RecordBatch, StringArray, TimestampMillisecondArray, UInt64Array};
+use chrono::{TimeDelta, Utc};
+use futures::TryStreamExt;
+use lancedb::table::OptimizeAction;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+use crate::blobs::{self, BlobStore};
+use crate::capture::rgb_to_jpeg;
+use crate::config::RetentionConfig;
+use crate::data_dir::DataDir;
+use crate::frames::{self, TIER_FULL, TIER_NONE, TIER_THUMBNAIL};
+use crate::fts::TextIndexWriter;
+
+/// How many of the oldest frames are deleted at a time while over quota.
+const EVICTION_BATCH: usize = 500;
+
+/// What retention did, or would do on a dry run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Frames whose screenshots were shrunk to thumbnails.
+    pub thumbnailed: usize,
+    /// Frames whose screenshots were deleted.
+    pub dropped: usize,
+    /// Frames deleted outright to get under the quota.
+    pub evicted: usize,
+    pub blobs_removed: usize,
+    pub blob_bytes_removed: u64,
+    /// How much the data directory takes up afterwards.
+    pub usage: u64,
+}
+
+impl Report {
+    pub fn changed_anything(&self) -> bool {
+        self.thumbnailed + self.dropped + self.evicted + self.blobs_removed > 0
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} screenshots shrunk, {} dropped, {} frames deleted over quota, {} blobs ({:.1} MB) \
+             removed, {:.1} MB in use",
+            self.thumbnailed,
+            self.dropped,
+            self.evicted,
+            self.blobs_removed,
+            self.blob_bytes_removed as f64 / 1_000_000.0,
+            self.usage as f64 / 1_000_000.0
+        )
+    }
+}
+
+/// Applies `config` to the `screenshots` table and the blobs it uses. Pinned frames are left
+/// alone. Like `BlobStore::gc`, nothing else should be recording while this runs.
+pub async fn apply(
+    table: &lancedb::Table,
+    data_dir: &DataDir,
+    mut text_writer: Option<&mut TextIndexWriter>,
+    config: &RetentionConfig,
+    dry_run: bool,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let older_than = |days: u32| {
+        TimeDelta::try_days(days.into())
+            .and_then(|age| Utc::now().checked_sub_signed(age))
+            .map(frames::timestamp)
+            .ok_or_else(|| anyhow!("{} days ago is further back than time goes", days))
+    };
+
+    // Dropping goes first so nothing is shrunk only to be deleted straight after
+    if let Some(days) = config.image_days {
+        let predicate = format!(
+            "image_tier != {} AND NOT pinned AND captured_at < {}",
+            frames::quote(TIER_NONE),
+            older_than(days)?
+        );
+        report.dropped = table.count_rows(Some(predicate.clone())).await?;
+        if !dry_run && report.dropped > 0 {
+            frames::update(
+                table,
+                &predicate,
+                &[
+                    ("image_hash", "NULL".into()),
+                    ("image_path", "''".into()),
+                    ("image_tier", frames::quote(TIER_NONE)),
+                    ("image_bytes", "0".into()),
+                ],
+            )
+            .await?;
+        }
+    }
+
+    if let Some(days) = config.full_days {
+        let mut predicate = format!(
+            "image_tier = {} AND NOT pinned AND captured_at < {}",
+            frames::quote(TIER_FULL),
+            older_than(days)?
+        );
+        // A dry run hasn't dropped anything, so leave out what it would have
+        if let Some(days) = config.image_days {
+            predicate += &format!(" AND captured_at >= {}", older_than(days)?);
+        }
+        report.thumbnailed = table.count_rows(Some(predicate.clone())).await?;
+        if !dry_run && report.thumbnailed > 0 {
+            shrink(table, data_dir, &predicate, config.thumbnail_size).await?;
+        }
+    }
+
+    if !dry_run {
+        reclaim(table, data_dir, &mut report).await?;
+    }
+    report.usage = usage(data_dir)?;
+
+    let Some(quota) = config.quota_gb.map(|gb| (gb * 1e9) as u64) else {
+        return Ok(report);
+    };
+    if dry_run || report.usage <= quota {
+        return Ok(report);
+    }
+    let oldest = oldest_unpinned(table).await?;
+    for batch in oldest.chunks(EVICTION_BATCH) {
+        let ids: Vec<String> = batch.iter().map(|id| id.to_string()).collect();
+        table
+            .delete(&format!("frame_id IN ({})", ids.join(", ")))
+            .await?;
+        if let Some(text_writer) = text_writer.as_deref_mut() {
+            for frame_id in batch {
+                text_writer.delete(*frame_id);
+            }
+            text_writer.commit()?;
+        }
+        report.evicted += batch.len();
+
+        let before = report.usage;
+        reclaim(table, data_dir, &mut report).await?;
+        report.usage = usage(data_dir)?;
+        if report.usage <= quota {
+            return Ok(report);
+        }
+        if report.usage >= before {
+            // Better to stay over quota than to delete everything chasing space that won't free up
+            eprintln!("Deleting frames isn't freeing any space, giving up on the quota for now");
+            return Ok(report);
+        }
+    }
+    eprintln!("Still over quota, but everything left is pinned");
+    Ok(report)
+}
+
+/// Replaces the screenshot of every frame matching `predicate` with a thumbnail. Frames sharing a
+/// screenshot share the thumbnail too.
+async fn shrink(
+    table: &lancedb::Table,
+    data_dir: &DataDir,
+    predicate: &str,
+    size: u32,
+) -> Result<()> {
+    let store = data_dir.blob_store();
+    let mut hashes = BTreeSet::new();
+    for batch in select(table, predicate, &["image_hash"]).await? {
+        hashes.extend(
+            column::<StringArray>(&batch, "image_hash")?
+                .iter()
+                .flatten()
+                .map(String::from),
+        );
+    }
+    for hash in hashes {
+        let jpeg = match store.get(&hash) {
+            Ok(jpeg) => jpeg,
+            Err(e) => {
+                eprintln!("Unable to shrink a screenshot: {:#}", e);
+                continue;
+            }
+        };
+        let thumbnail = match image::load_from_memory(&jpeg) {
+            Ok(image) => image.thumbnail(size, size).to_rgb8(),
+            Err(e) => {
+                eprintln!("Unable to shrink screenshot {}: {}", hash, e);
+                continue;
+            }
+        };
+        let thumbnail_jpeg =
+            rgb_to_jpeg(thumbnail.as_raw(), thumbnail.width(), thumbnail.height())?;
+        let thumbnail_hash = store.put(&thumbnail_jpeg)?;
+        frames::update(
+            table,
+            &format!("({}) AND image_hash = {}", predicate, frames::quote(&hash)),
+            &[
+                ("image_hash", frames::quote(&thumbnail_hash)),
+                (
+                    "image_path",
+                    frames::quote(&BlobStore::path(&thumbnail_hash).to_string_lossy()),
+                ),
+                ("image_tier", frames::quote(TIER_THUMBNAIL)),
+                ("image_width", thumbnail.width().to_string()),
+                ("image_height", thumbnail.height().to_string()),
+                ("image_bytes", thumbnail_jpeg.len().to_string()),
+            ],
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Deletes blobs nothing uses any more and has lancedb let go of deleted rows.
+async fn reclaim(table: &lancedb::Table, data_dir: &DataDir, report: &mut Report) -> Result<()> {
+    let references = blobs::references(table, data_dir).await?;
+    let gc = data_dir.blob_store().gc(&references, false)?;
+    report.blobs_removed += gc.removed;
+    report.blob_bytes_removed += gc.removed_bytes;
+    table.optimize(OptimizeAction::All).await?;
+    // Old versions still hold on to everything that was deleted
+    table
+        .optimize(OptimizeAction::Prune {
+            older_than: TimeDelta::zero(),
+            delete_unverified: None,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Every unpinned frame, oldest first.
+async fn oldest_unpinned(table: &lancedb::Table) -> Result<Vec<u64>> {
+    let mut frames = Vec::new();
+    for batch in select(table, "NOT pinned", &["frame_id", "captured_at"]).await? {
+        let ids = column::<UInt64Array>(&batch, "frame_id")?;
+        let times = column::<TimestampMillisecondArray>(&batch, "captured_at")?;
+        frames.extend(
+            times
+                .values()
+                .iter()
+                .copied()
+                .zip(ids.values().iter().copied()),
+        );
+    }
+    frames.sort_unstable();
+    Ok(frames.into_iter().map(|(_, id)| id).collect())
+}
+
+async fn select(
+    table: &lancedb::Table,
+    predicate: &str,
+    columns: &[&str],
+) -> Result<Vec<RecordBatch>> {
+    Ok(table
+        .query()
+        .filter(predicate)
+        .select(columns)
+        .execute_stream()
+        .await?
+        .try_collect()
+        .await?)
+}
+
+fn column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<T>())
+        .ok_or_else(|| anyhow!("{} is missing the {} column", frames::TABLE, name))
+}
+
+/// Bytes taken up by everything retention can do something about. Backups aren't counted.
+fn usage(data_dir: &DataDir) -> Result<u64> {
+    let mut total = 0;
+    for directory in [
+        data_dir.database(),
+        data_dir.text_index(),
+        data_dir.resolve(blobs::BLOBS),
+    ] {
+        total += directory_size(&directory)?;
+    }
+    Ok(total)
+}
+
+fn directory_size(path: &Path) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        total += match metadata.is_dir() {
+            true => directory_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::jpeg_metrohash;
+    use crate::diff::{fingerprint, ChangeReport};
+    use crate::frames::Frame;
+    use crate::types::{Window, WindowBounds};
+    use arrow_array::RecordBatchIterator;
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    /// Frames captured `days` ago, each with a screenshot of its own.
+    async fn table(data_dir: &DataDir, days: &[u32]) -> lancedb::Table {
+        data_dir.create().unwrap();
+        let store = data_dir.blob_store();
+        let frames: Vec<Frame> = days
+            .iter()
+            .enumerate()
+            .map(|(i, days)| {
+                let size = 400 + i as u32;
+                let jpeg = rgb_to_jpeg(&vec![128; (size * size * 3) as usize], size, size).unwrap();
+                let fingerprint = fingerprint(&jpeg).unwrap();
+                let image_hash = store.put(&jpeg).unwrap();
+                Frame {
+                    id: i as u64,
+                    window: Window {
+                        id: 1,
+                        title: "Terminal".into(),
+                        app: None,
+                        bounds: WindowBounds {
+                            x: 0,
+                            y: 0,
+                            width: size,
+                            height: size,
+                        },
+                        display_id: None,
+                        captured_at: SystemTime::now()
+                            - std::time::Duration::from_secs(*days as u64 * 24 * 60 * 60),
+                        jpeg_metrohash: jpeg_metrohash(&jpeg),
+                        jpeg,
+                        perceptual_hash: fingerprint.hash,
+                        tiles: fingerprint.tiles.clone(),
+                        z: 0,
+                    },
+                    embedding: vec![0.0, 1.0],
+                    ocr: Vec::new(),
+                    image_path: BlobStore::path(&image_hash),
+                    image_hash,
+                    change: ChangeReport::whole(&fingerprint.tiles),
+                    cropped: false,
+                }
+            })
+            .collect();
+        let schema = frames::schema(2);
+        let batch = frames::to_batch(&schema, 2, "test", &frames, None).unwrap();
+        let db = lancedb::connect(&data_dir.database_uri().unwrap())
+            .execute()
+            .await
+            .unwrap();
+        db.create_table(
+            frames::TABLE,
+            Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)),
+        )
+        .execute()
+        .await
+        .unwrap()
+    }
+
+    /// Each frame's tier and image width, by frame id.
+    async fn tiers(table: &lancedb::Table) -> HashMap<u64, (String, u32)> {
+        let mut tiers = HashMap::new();
+        for batch in select(table, "true", &["frame_id", "image_tier", "image_width"])
+            .await
+            .unwrap()
+        {
+            let ids = column::<UInt64Array>(&batch, "frame_id").unwrap();
+            let names = column::<StringArray>(&batch, "image_tier").unwrap();
+            let widths = column::<arrow_array::UInt32Array>(&batch, "image_width").unwrap();
+            for i in 0..batch.num_rows() {
+                tiers.insert(ids.value(i), (names.value(i).to_string(), widths.value(i)));
+            }
+        }
+        tiers
+    }
+
+    fn config() -> RetentionConfig {
+        RetentionConfig {
+            full_days: Some(7),
+            image_days: Some(30),
+            thumbnail_size: 100,
+            quota_gb: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn shrinks_then_drops_screenshots_as_they_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let table = table(&data_dir, &[1, 10, 40, 40]).await;
+        frames::update(&table, "frame_id = 3", &[("pinned", "true".into())])
+            .await
+            .unwrap();
+
+        let report = apply(&table, &data_dir, None, &config(), true)
+            .await
+            .unwrap();
+        assert_eq!((report.thumbnailed, report.dropped), (1, 1));
+        assert_eq!(tiers(&table).await[&1].0, TIER_FULL);
+
+        let report = apply(&table, &data_dir, None, &config(), false)
+            .await
+            .unwrap();
+        assert_eq!((report.thumbnailed, report.dropped), (1, 1));
+        assert_eq!(report.blobs_removed, 2);
+        let tiers = tiers(&table).await;
+        assert_eq!(tiers[&0], (TIER_FULL.into(), 400));
+        assert_eq!(tiers[&1], (TIER_THUMBNAIL.into(), 100));
+        assert_eq!(tiers[&2].0, TIER_NONE);
+        // Pinned frames are left as they are however old they get
+        assert_eq!(tiers[&3], (TIER_FULL.into(), 403));
+    }
+
+    #[tokio::test]
+    async fn skips_screenshots_it_cant_decode() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let table = table(&data_dir, &[10, 10]).await;
+        let mut hashes = Vec::new();
+        for batch in select(&table, "frame_id = 0", &["image_hash"])
+            .await
+            .unwrap()
+        {
+            hashes.extend(
+                column::<StringArray>(&batch, "image_hash")
+                    .unwrap()
+                    .iter()
+                    .flatten()
+                    .map(String::from),
+            );
+        }
+        std::fs::write(data_dir.resolve(BlobStore::path(&hashes[0])), b"not a jpeg").unwrap();
+
+        apply(&table, &data_dir, None, &config(), false)
+            .await
+            .unwrap();
+        let tiers = tiers(&table).await;
+        assert_eq!(tiers[&0].0, TIER_FULL);
+        assert_eq!(tiers[&1].0, TIER_THUMBNAIL);
+    }
+
+    #[tokio::test]
+    async fn deletes_the_oldest_unpinned_frames_over_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let table = table(&data_dir, &[1, 2, 3]).await;
+        frames::update(&table, "frame_id = 2", &[("pinned", "true".into())])
+            .await
+            .unwrap();
+        let config = RetentionConfig {
+            quota_gb: Some(1e-9),
+            ..RetentionConfig::default()
+        };
+
+        let report = apply(&table, &data_dir, None, &config, false)
+            .await
+            .unwrap();
+        assert_eq!(report.evicted, 2);
+        assert_eq!(tiers(&table).await.into_keys().collect::<Vec<_>>(), [2]);
+    }
+}
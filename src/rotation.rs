@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use arrow_array::{Array, ListArray, RecordBatch, StringArray, StructArray, UInt64Array};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::blobs::{self, BlobStore};
+use crate::config::KeySource;
+use crate::crypto::{self, Cipher};
+use crate::data_dir::DataDir;
+use crate::frames;
+use crate::fts::TextIndex;
+use crate::migrations;
+use crate::queue;
+
+/// What a rotation got through.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub blobs: usize,
+    pub frames: usize,
+    /// Copies of the table from earlier migrations, which are still under whatever they were
+    /// under before.
+    pub stale_backups: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Re-encrypted {} screenshots and {} frames under the new key",
+            self.blobs, self.frames
+        )?;
+        if self.stale_backups > 0 {
+            write!(
+                f,
+                ". {} backups from earlier upgrades aren't, so delete them once you're happy",
+                self.stale_backups
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Seals every screenshot and every frame's text under a new key from `source`, whether they
+/// were under the old key or not encrypted at all, then makes it the current key. The text index
+/// is rebuilt from scratch, its words hashed under the new key, though which frames share a word
+/// and how often each comes up still show. Nothing else can be using the data directory
+/// meanwhile.
+///
+/// If it's interrupted, running it again picks up where it left off, since until it finishes
+/// things can be under either key.
+pub async fn rotate(
+    db: &lancedb::connection::Connection,
+    table: &lancedb::Table,
+    data_dir: &DataDir,
+    source: KeySource,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let (previous, next) = crypto::start_rotation(data_dir.root(), source)?;
+
+    // Anything nothing refers to would be left under the old key, and be unreadable after
+    let references = blobs::references(table, data_dir).await?;
+    data_dir.blob_store().gc(&references, false)?;
+    let store = BlobStore::new(data_dir.root().to_path_buf(), Some(next.clone()));
+    for hash in references.keys() {
+        if store.reseal(hash, previous.as_ref())? {
+            report.blobs += 1;
+        }
+    }
+
+    let backup = data_dir.backups().join(format!(
+        "{}-rotation-{}.lance",
+        frames::TABLE,
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    ));
+    let schema = table.schema().await?;
+    let mut texts = Vec::new();
+    migrations::rewrite_table(db, data_dir, &backup, &schema, |batch| {
+        report.frames += batch.num_rows();
+        reseal_batch(batch, previous.as_ref(), &next, &mut texts)
+    })
+    .await?;
+    queue::reseal_titles(data_dir, |title| reseal(previous.as_ref(), &next, title))?;
+
+    rebuild_text_index(data_dir, &texts, &next)?;
+    crypto::finish_rotation(data_dir.root())?;
+    std::fs::remove_dir_all(&backup)?;
+    report.stale_backups = std::fs::read_dir(data_dir.backups()).map_or(0, |d| d.count());
+    Ok(report)
+}
+
+/// Reseals the text columns of `batch`, collecting each frame's OCR text in the clear for the
+/// text index.
+fn reseal_batch(
+    batch: RecordBatch,
+    previous: Option<&Cipher>,
+    next: &Cipher,
+    texts: &mut Vec<(u64, String)>,
+) -> Result<RecordBatch> {
+    let frame_ids = column::<UInt64Array>(&batch, "frame_id")?;
+    let ocr_text = column::<StringArray>(&batch, "ocr_text")?;
+    for (i, frame_id) in frame_ids.values().iter().enumerate() {
+        if ocr_text.is_valid(i) {
+            let text = open(previous, next, ocr_text.value(i))?;
+            if !text.is_empty() {
+                texts.push((*frame_id, text));
+            }
+        }
+    }
+
+    let title = reseal_strings(column(&batch, "title")?, previous, next)?;
+    let ocr_text = reseal_strings(ocr_text, previous, next)?;
+    let ocr_lines = column::<ListArray>(&batch, "ocr_lines")?;
+    let lines = ocr_lines
+        .values()
+        .as_any()
+        .downcast_ref::<StructArray>()
+        .ok_or_else(|| anyhow!("ocr_lines isn't a list of structs"))?;
+    let (fields, mut columns, nulls) = lines.clone().into_parts();
+    let text_index = fields
+        .find("text")
+        .ok_or_else(|| anyhow!("ocr_lines is missing its text"))?
+        .0;
+    let line_texts = columns[text_index]
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| anyhow!("ocr_lines text isn't a string"))?;
+    columns[text_index] = Arc::new(reseal_strings(line_texts, previous, next)?);
+    let ocr_lines = ListArray::new(
+        Arc::new(frames::ocr_line_field()),
+        ocr_lines.offsets().clone(),
+        Arc::new(StructArray::new(fields, columns, nulls)),
+        ocr_lines.nulls().cloned(),
+    );
+
+    let batch = migrations::replace(batch, "title", Arc::new(title))?;
+    let batch = migrations::replace(batch, "ocr_text", Arc::new(ocr_text))?;
+    migrations::replace(batch, "ocr_lines", Arc::new(ocr_lines))
+}
+
+fn reseal_strings(
+    strings: &StringArray,
+    previous: Option<&Cipher>,
+    next: &Cipher,
+) -> Result<StringArray> {
+    strings
+        .iter()
+        .map(|s| s.map(|s| reseal(previous, next, s)).transpose())
+        .collect()
+}
+
+fn reseal(previous: Option<&Cipher>, next: &Cipher, text: &str) -> Result<String> {
+    if crypto::is_sealed_text(text) && next.open_text(text).is_ok() {
+        return Ok(text.to_string());
+    }
+    Ok(next.seal_text(&open(previous, next, text)?))
+}
+
+/// Opens text under either key, or passes it through if it isn't sealed.
+fn open(previous: Option<&Cipher>, next: &Cipher, text: &str) -> Result<String> {
+    next.open_text(text)
+        .or_else(|_| crypto::open_text(previous, text))
+}
+
+/// Replaces the text index with a fresh one keyed by `cipher`, so nothing is left of the text older
+/// versions stored in it, or of the words themselves.
+fn rebuild_text_index(data_dir: &DataDir, texts: &[(u64, String)], cipher: &Cipher) -> Result<()> {
+    let current = data_dir.text_index();
+    let next = current.with_extension("next");
+    if next.exists() {
+        std::fs::remove_dir_all(&next)?;
+    }
+    {
+        let mut writer = TextIndex::open(&next, Some(cipher))?.writer()?;
+        for (frame_id, text) in texts {
+            writer.add(*frame_id, text)?;
+        }
+        writer.commit()?;
+    }
+    if current.exists() {
+        std::fs::remove_dir_all(&current)?;
+    }
+    std::fs::rename(&next, &current)?;
+    Ok(())
+}
+
+fn column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<T>())
+        .ok_or_else(|| anyhow!("{} is missing the {} column", frames::TABLE, name))
+}
@@ -1,18 +1,16 @@
 use anyhow::{anyhow, Result};
 use cocoa::base::nil;
-use cocoa::foundation::{NSArray, NSAutoreleasePool, NSData, NSString};
+use cocoa::foundation::{NSAutoreleasePool, NSData};
 use core_foundation::array::{CFArrayGetCount, CFArrayGetValueAtIndex};
-use core_foundation::base::{CFRelease, FromVoid, ToVoid};
+use core_foundation::base::{CFRelease, FromVoid, TCFType, ToVoid};
 use core_foundation::boolean::CFBooleanRef;
-use core_foundation::dictionary::{CFDictionaryGetValue, CFDictionaryRef};
+use core_foundation::dictionary::{CFDictionary, CFDictionaryGetValue, CFDictionaryRef};
 use core_foundation::number::{kCFNumberIntType, CFBooleanGetValue, CFNumberGetValue, CFNumberRef};
 use core_foundation::string::CFString;
 use core_graphics::image::CGImage;
 use foreign_types_shared::ForeignType;
-use metrohash::MetroHash64;
-use std::ffi::CStr;
-use std::hash::{Hash, Hasher};
 use std::os::raw::c_void;
+use std::time::SystemTime;
 
 use core_graphics::base::{
     kCGBitmapByteOrder32Little, kCGImageAlphaLast, kCGImageAlphaPremultipliedLast,
@@ -20,26 +18,36 @@ use core_graphics::base::{
 use core_graphics::context::{CGContext, CGInterpolationQuality};
 use core_graphics::display::{
     kCGNullWindowID, kCGWindowImageDefault, kCGWindowListExcludeDesktopElements,
-    kCGWindowListOptionIncludingWindow, kCGWindowListOptionOnScreenOnly, CGRectNull,
+    kCGWindowListOptionIncludingWindow, kCGWindowListOptionOnScreenOnly, CGDisplay, CGRectNull,
 };
 use core_graphics::geometry::{CGPoint, CGRect, CGSize};
 use core_graphics::window::{
-    create_image, kCGWindowIsOnscreen, kCGWindowLayer, kCGWindowName, kCGWindowNumber,
-    kCGWindowSharingNone, kCGWindowSharingState, CGWindowListCopyWindowInfo,
+    create_image, kCGWindowBounds, kCGWindowIsOnscreen, kCGWindowLayer, kCGWindowName,
+    kCGWindowNumber, kCGWindowOwnerName, kCGWindowSharingNone, kCGWindowSharingState,
+    CGWindowListCopyWindowInfo,
 };
 
-use crate::objc_ffi::{
-    NSBitmapImageFileType, NSBitmapImageRep, VNImageRequestHandler, VNRecognizeTextRequest,
-    VNRecognizedText, VNRecognizedTextObservation,
-};
-use crate::types::Window;
+use crate::capture::{jpeg_metrohash, CaptureBackend};
+use crate::diff::fingerprint;
+use crate::objc_ffi::{NSBitmapImageFileType, NSBitmapImageRep};
+use crate::types::{Window, WindowBounds};
 
 struct WindowHandle {
     id: u32,
     title: String,
+    app: Option<String>,
+    bounds: CGRect,
+}
+
+pub struct CoreGraphicsBackend;
+
+impl CaptureBackend for CoreGraphicsBackend {
+    fn get_windows(&mut self) -> Result<Vec<Window>> {
+        get_windows()
+    }
 }
 
-pub fn get_windows() -> Result<Vec<Window>> {
+fn get_windows() -> Result<Vec<Window>> {
     unsafe {
         let pool = NSAutoreleasePool::new(nil);
 
@@ -102,13 +110,29 @@ pub fn get_windows() -> Result<Vec<Window>> {
                 continue;
             }
 
+            let raw_app = CFDictionaryGetValue(info, kCGWindowOwnerName.to_void());
+            let app = if raw_app.is_null() {
+                None
+            } else {
+                Some(CFString::from_void(raw_app).to_string())
+            };
+
+            let raw_bounds = CFDictionaryGetValue(info, kCGWindowBounds.to_void());
+            let bounds = CGRect::from_dict_representation(&CFDictionary::wrap_under_get_rule(
+                raw_bounds as CFDictionaryRef,
+            ))
+            .unwrap_or(CGRectNull);
+
             windows.push(WindowHandle {
-                id: id,
-                title: title,
+                id,
+                title,
+                app,
+                bounds,
             });
         }
         CFRelease(window_infos as *const c_void);
 
+        let displays = CGDisplay::active_displays().unwrap_or_default();
         let mut screenshots = Vec::new();
         for i in 0..windows.len() {
             let window = &windows[i];
@@ -122,15 +146,30 @@ pub fn get_windows() -> Result<Vec<Window>> {
             .ok_or_else(|| anyhow!("Unable to take screenshot"))?;
             //let small = resize_cgimage(&image, image.width() / 8, image.height() / 8)?;
             let jpeg = cgimage_to_jpeg(image.clone())?;
-            let mut hasher = MetroHash64::new();
-            jpeg.hash(&mut hasher);
-            let hash = hasher.finish();
+            let hash = jpeg_metrohash(&jpeg);
+            let fingerprint = fingerprint(&jpeg)?;
+            // Whichever display the window's top left corner is on
+            let display_id = displays
+                .iter()
+                .copied()
+                .find(|&d| CGDisplay::new(d).bounds().contains(&window.bounds.origin));
             screenshots.push(Window {
                 id: window.id,
                 title: window.title.clone(),
+                app: window.app.clone(),
+                bounds: WindowBounds {
+                    x: window.bounds.origin.x as i32,
+                    y: window.bounds.origin.y as i32,
+                    width: window.bounds.size.width as u32,
+                    height: window.bounds.size.height as u32,
+                },
+                display_id,
+                captured_at: SystemTime::now(),
                 jpeg: jpeg,
                 //jpeg_small: cgimage_to_jpeg(small.clone())?,
                 jpeg_metrohash: hash,
+                perceptual_hash: fingerprint.hash,
+                tiles: fingerprint.tiles,
                 z: z,
             });
         }
@@ -149,26 +188,6 @@ unsafe fn cgimage_to_jpeg(image: CGImage) -> Result<Vec<u8>> {
     Ok(Vec::from(slice))
 }
 
-//unsafe fn ocr_cgimage(image: &CGImage) -> Result<()> {
-//    let handler =
-//        VNImageRequestHandler::alloc(nil)
-//        .initWithCGImage(image.as_ptr())
-//        .autorelease();
-//    let request = VNRecognizeTextRequest::alloc(nil);
-//    VNRecognizeTextRequest::init(request).autorelease();
-//    handler.performRequests(&[request], None);
-//    let results = request.results();
-//    for i in 0..results.count() {
-//        let candidates = results.objectAtIndex(i).topCandidates(1);
-//        for j in 0..candidates.count() {
-//            let raw_string = candidates.objectAtIndex(j).string();
-//            println!("{}", CStr::from_ptr(raw_string.UTF8String()).to_str()?);
-//        }
-//    }
-//
-//    Ok(())
-//}
-
 fn resize_cgimage(image: &CGImage, width: usize, height: usize) -> Result<CGImage> {
     let context = CGContext::create_bitmap_context(
         None,
@@ -0,0 +1,546 @@
+use anyhow::{anyhow, Result};
+use arrow_array::{
+    Array, Float32Array, RecordBatch, StringArray, TimestampMillisecondArray, UInt64Array,
+};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::crypto::{self, Cipher};
+use crate::embedder::Embedder;
+use crate::fts::{Highlighter, Snippet, TextIndex};
+
+/// The usual constant from the RRF paper. Larger values flatten the difference between ranks.
+const RRF_K: f32 = 60.0;
+
+/// How many more candidates than requested to pull from each side before fusing, to begin with.
+const CANDIDATE_MULTIPLIER: usize = 4;
+
+pub struct SearchOptions {
+    pub limit: usize,
+    /// How much each ranking counts towards the fused score. Zero skips that search entirely.
+    pub vector_weight: f32,
+    pub text_weight: f32,
+    /// A SQL predicate over the `screenshots` table that every hit has to satisfy.
+    pub filter: Option<String>,
+    /// Only keeps hits whose title contains this. It's checked after decrypting, so unlike
+    /// `filter` it works on sealed titles.
+    pub title: Option<String>,
+    /// IVF partitions to look in, once there's a vector index.
+    pub nprobes: usize,
+    /// Re-ranks this many times the candidates by exact distance. 0 skips that.
+    pub refine_factor: u32,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            limit: 10,
+            vector_weight: 1.0,
+            text_weight: 1.0,
+            filter: None,
+            title: None,
+            nprobes: 20,
+            refine_factor: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub frame_id: u64,
+    pub metrohash: u64,
+    pub captured_at: DateTime<Utc>,
+    pub title: String,
+    pub app: Option<String>,
+    pub image_path: String,
+    /// Weighted reciprocal rank fusion of the two rankings below. Higher is better.
+    pub score: f32,
+    pub vector: Option<VectorMatch>,
+    pub text: Option<TextMatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VectorMatch {
+    /// Zero based.
+    pub rank: usize,
+    pub distance: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextMatch {
+    /// Zero based.
+    pub rank: usize,
+    pub score: f32,
+    pub snippet: Snippet,
+}
+
+/// Searches frames by how closely their screenshot matches `query` and by the words in their OCR
+/// text, and fuses the two rankings. `cipher` opens whatever was sealed.
+pub async fn search(
+    table: &lancedb::Table,
+    text_index: &TextIndex,
+    embedder: &dyn Embedder,
+    cipher: Option<&Cipher>,
+    query: &str,
+    options: &SearchOptions,
+) -> Result<Vec<SearchHit>> {
+    let embedding = match options.vector_weight > 0.0 {
+        true => Some(embedder.embed_text(query).await?),
+        false => None,
+    };
+    // Text matches and titles are only filtered once the candidates are picked, so keep pulling
+    // in more until enough are left or neither side has any more to give
+    let mut candidates = options.limit * CANDIDATE_MULTIPLIER;
+    loop {
+        let (mut hits, exhausted) = fuse(
+            table,
+            text_index,
+            embedding.as_deref(),
+            cipher,
+            query,
+            candidates,
+            options,
+        )
+        .await?;
+        if hits.len() >= options.limit || exhausted {
+            // Ties, like a frame first on one side and second on the other against one the other
+            // way around, go to the more recent frame
+            hits.sort_by(|a, b| {
+                b.score
+                    .total_cmp(&a.score)
+                    .then(b.captured_at.cmp(&a.captured_at))
+            });
+            hits.truncate(options.limit);
+            return Ok(hits);
+        }
+        candidates *= 2;
+    }
+}
+
+/// Fuses the top `candidates` from each side, and says whether either had fewer than that to give
+/// alongside them.
+async fn fuse(
+    table: &lancedb::Table,
+    text_index: &TextIndex,
+    embedding: Option<&[f32]>,
+    cipher: Option<&Cipher>,
+    query: &str,
+    candidates: usize,
+    options: &SearchOptions,
+) -> Result<(Vec<SearchHit>, bool)> {
+    let mut hits: HashMap<u64, SearchHit> = HashMap::new();
+    let mut exhausted = true;
+
+    if let Some(embedding) = embedding {
+        let mut vector_query = table
+            .search(embedding)
+            .select(&["frame_id"])
+            .limit(candidates)
+            .nprobes(options.nprobes);
+        if options.refine_factor > 0 {
+            vector_query = vector_query.refine_factor(options.refine_factor);
+        }
+        if let Some(filter) = &options.filter {
+            // Filtering first means coming back short is only ever for want of matches
+            vector_query = vector_query.filter(filter).prefilter(true);
+        }
+        let batches = vector_query
+            .execute_stream()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut rank = 0;
+        for batch in &batches {
+            let frame_ids = column::<UInt64Array>(batch, "frame_id")?;
+            let distances = column::<Float32Array>(batch, "_distance")?;
+            for (frame_id, distance) in frame_ids.values().iter().zip(distances.values()) {
+                let hit = hits
+                    .entry(*frame_id)
+                    .or_insert_with(|| SearchHit::new(*frame_id));
+                hit.score += options.vector_weight / (RRF_K + rank as f32 + 1.0);
+                hit.vector = Some(VectorMatch {
+                    rank,
+                    distance: *distance,
+                });
+                rank += 1;
+            }
+        }
+        exhausted &= rank < candidates;
+    }
+
+    let mut highlighter = None;
+    if options.text_weight > 0.0 {
+        let (text_hits, text_highlighter) = text_index.search(query, candidates)?;
+        highlighter = Some(text_highlighter);
+        exhausted &= text_hits.len() < candidates;
+        for (rank, text_hit) in text_hits.into_iter().enumerate() {
+            let hit = hits
+                .entry(text_hit.frame_id)
+                .or_insert_with(|| SearchHit::new(text_hit.frame_id));
+            hit.score += options.text_weight / (RRF_K + rank as f32 + 1.0);
+            hit.text = Some(TextMatch {
+                rank,
+                score: text_hit.score,
+                snippet: Snippet::default(),
+            });
+        }
+    }
+
+    let mut hits: Vec<SearchHit> = hits.into_values().collect();
+    fill_metadata(table, &mut hits, cipher, highlighter.as_ref(), options).await?;
+    Ok((hits, exhausted))
+}
+
+impl SearchHit {
+    fn new(frame_id: u64) -> Self {
+        Self {
+            frame_id,
+            metrohash: 0,
+            captured_at: DateTime::UNIX_EPOCH,
+            title: String::new(),
+            app: None,
+            image_path: String::new(),
+            score: 0.0,
+            vector: None,
+            text: None,
+        }
+    }
+}
+
+/// Text matches only know their frame id, so look up the rest from the table, along with the
+/// text to make their snippets from. Frames the table no longer has, or that don't pass the
+/// filters in `options`, are dropped.
+async fn fill_metadata(
+    table: &lancedb::Table,
+    hits: &mut Vec<SearchHit>,
+    cipher: Option<&Cipher>,
+    highlighter: Option<&Highlighter>,
+    options: &SearchOptions,
+) -> Result<()> {
+    if hits.is_empty() {
+        return Ok(());
+    }
+
+    let ids: Vec<String> = hits.iter().map(|h| h.frame_id.to_string()).collect();
+    let mut predicate = format!("frame_id IN ({})", ids.join(", "));
+    if let Some(filter) = &options.filter {
+        predicate = format!("({}) AND ({})", predicate, filter);
+    }
+    let batches = table
+        .query()
+        .filter(predicate)
+        .select(&[
+            "frame_id",
+            "metrohash",
+            "captured_at",
+            "title",
+            "app",
+            "image_path",
+            "ocr_text",
+        ])
+        .limit(hits.len())
+        .execute_stream()
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    let mut rows = HashMap::new();
+    for batch in &batches {
+        let frame_ids = column::<UInt64Array>(batch, "frame_id")?;
+        for (row, frame_id) in frame_ids.values().iter().enumerate() {
+            rows.insert(*frame_id, (batch, row));
+        }
+    }
+
+    let mut found = Vec::new();
+    for mut hit in hits.drain(..) {
+        let Some(&(batch, row)) = rows.get(&hit.frame_id) else {
+            continue;
+        };
+        let apps = column::<StringArray>(batch, "app")?;
+        hit.metrohash = column::<UInt64Array>(batch, "metrohash")?.value(row);
+        hit.captured_at = DateTime::from_timestamp_millis(
+            column::<TimestampMillisecondArray>(batch, "captured_at")?.value(row),
+        )
+        .unwrap_or_default();
+        hit.title = crypto::open_text(cipher, column::<StringArray>(batch, "title")?.value(row))?;
+        if let Some(title) = &options.title {
+            if !hit.title.contains(title.as_str()) {
+                continue;
+            }
+        }
+        hit.app = (!apps.is_null(row)).then(|| apps.value(row).to_string());
+        hit.image_path = column::<StringArray>(batch, "image_path")?
+            .value(row)
+            .to_string();
+        if let (Some(text), Some(highlighter)) = (&mut hit.text, highlighter) {
+            let ocr_text = column::<StringArray>(batch, "ocr_text")?;
+            if !ocr_text.is_null(row) {
+                text.snippet =
+                    highlighter.snippet(&crypto::open_text(cipher, ocr_text.value(row))?);
+            }
+        }
+        found.push(hit);
+    }
+    *hits = found;
+    Ok(())
+}
+
+fn column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<T>())
+        .ok_or_else(|| anyhow!("Search results are missing the {} column", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{jpeg_metrohash, rgb_to_jpeg};
+    use crate::data_dir::DataDir;
+    use crate::diff::{fingerprint, ChangeReport};
+    use crate::frames::{self, Frame};
+    use crate::ocr::{BoundingBox, OcrLine};
+    use crate::types::{Window, WindowBounds};
+    use arrow_array::RecordBatchIterator;
+    use async_trait::async_trait;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    /// Embeds every query as the same vector.
+    struct Fixed(Vec<f32>);
+
+    #[async_trait]
+    impl Embedder for Fixed {
+        async fn embed_image(&self, _jpeg: &[u8]) -> Result<Vec<f32>> {
+            Err(anyhow!("Only queries get embedded"))
+        }
+
+        async fn embed_text(&self, _text: &str) -> Result<Vec<f32>> {
+            Ok(self.0.clone())
+        }
+
+        fn dimension(&self) -> usize {
+            self.0.len()
+        }
+
+        fn model_id(&self) -> &str {
+            "fixed"
+        }
+    }
+
+    struct Row {
+        frame_id: u64,
+        title: &'static str,
+        embedding: [f32; 2],
+        text: &'static str,
+        /// Seconds after the epoch.
+        captured_at: u64,
+    }
+
+    /// A table of `rows`, each with its text in the index too.
+    async fn frames(data_dir: &DataDir, rows: &[Row]) -> (lancedb::Table, TextIndex) {
+        data_dir.create().unwrap();
+        let jpeg = rgb_to_jpeg(&[128; 32 * 32 * 3], 32, 32).unwrap();
+        let fingerprint = fingerprint(&jpeg).unwrap();
+        let index = TextIndex::open(&data_dir.root().join("text"), None).unwrap();
+        let mut writer = index.writer().unwrap();
+        let mut frames = Vec::new();
+        for row in rows {
+            writer.add(row.frame_id, row.text).unwrap();
+            frames.push(Frame {
+                id: row.frame_id,
+                window: Window {
+                    id: 1,
+                    title: row.title.into(),
+                    app: None,
+                    bounds: WindowBounds::default(),
+                    display_id: None,
+                    captured_at: SystemTime::UNIX_EPOCH + Duration::from_secs(row.captured_at),
+                    jpeg: jpeg.clone(),
+                    jpeg_metrohash: jpeg_metrohash(&jpeg),
+                    perceptual_hash: fingerprint.hash,
+                    tiles: fingerprint.tiles.clone(),
+                    z: 0,
+                },
+                embedding: row.embedding.to_vec(),
+                ocr: vec![OcrLine {
+                    text: row.text.into(),
+                    confidence: 1.0,
+                    bounds: BoundingBox {
+                        x: 0.0,
+                        y: 0.0,
+                        width: 1.0,
+                        height: 1.0,
+                    },
+                }],
+                image_hash: String::new(),
+                image_path: PathBuf::new(),
+                change: ChangeReport::whole(&fingerprint.tiles),
+                cropped: false,
+            });
+        }
+        writer.commit().unwrap();
+
+        let schema = frames::schema(2);
+        let batch = frames::to_batch(&schema, 2, "fixed", &frames, None).unwrap();
+        let db = lancedb::connect(&data_dir.database_uri().unwrap())
+            .execute()
+            .await
+            .unwrap();
+        let table = db
+            .create_table(
+                frames::TABLE,
+                Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)),
+            )
+            .execute()
+            .await
+            .unwrap();
+        (table, index)
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<u64> {
+        hits.iter().map(|h| h.frame_id).collect()
+    }
+
+    #[tokio::test]
+    async fn ranks_frames_on_both_sides_above_either_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let rows = [
+            Row {
+                frame_id: 1,
+                title: "Mail",
+                embedding: [1.0, 0.0],
+                text: "nothing to see here",
+                captured_at: 1,
+            },
+            Row {
+                frame_id: 2,
+                title: "Mail",
+                embedding: [0.9, 0.1],
+                text: "invoice",
+                captured_at: 2,
+            },
+            Row {
+                frame_id: 3,
+                title: "Mail",
+                embedding: [0.0, 1.0],
+                text: "nothing here either",
+                captured_at: 3,
+            },
+        ];
+        let (table, index) = frames(&data_dir, &rows).await;
+        let embedder = Fixed(vec![1.0, 0.0]);
+
+        let options = SearchOptions::default();
+        let hits = search(&table, &index, &embedder, None, "invoice", &options)
+            .await
+            .unwrap();
+        assert_eq!(ids(&hits), [2, 1, 3]);
+        assert_eq!(hits[0].vector.as_ref().unwrap().rank, 1);
+        assert_eq!(hits[0].text.as_ref().unwrap().rank, 0);
+        assert_eq!(
+            hits[0].text.as_ref().unwrap().snippet.highlighted("[", "]"),
+            "[invoice]"
+        );
+        assert!(hits[1].text.is_none());
+        assert!(hits[0].score > hits[1].score && hits[1].score > hits[2].score);
+
+        // Without the vector side, only text matches are left
+        let options = SearchOptions {
+            vector_weight: 0.0,
+            ..SearchOptions::default()
+        };
+        let hits = search(&table, &index, &embedder, None, "invoice", &options)
+            .await
+            .unwrap();
+        assert_eq!(ids(&hits), [2]);
+        assert!(hits[0].vector.is_none());
+    }
+
+    #[tokio::test]
+    async fn breaks_ties_with_the_newer_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        // First and second on opposite sides, so their scores come out the same
+        let rows = [
+            Row {
+                frame_id: 1,
+                title: "Mail",
+                embedding: [0.9, 0.1],
+                text: "invoice",
+                captured_at: 10,
+            },
+            Row {
+                frame_id: 2,
+                title: "Mail",
+                embedding: [1.0, 0.0],
+                text: "invoice paid in full, thanks for your business",
+                captured_at: 20,
+            },
+        ];
+        let (table, index) = frames(&data_dir, &rows).await;
+
+        let options = SearchOptions::default();
+        let hits = search(
+            &table,
+            &index,
+            &Fixed(vec![1.0, 0.0]),
+            None,
+            "invoice",
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(hits[0].score, hits[1].score);
+        assert_eq!(ids(&hits), [2, 1]);
+    }
+
+    #[tokio::test]
+    async fn pulls_in_more_candidates_when_filters_drop_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        let mut rows: Vec<Row> = (1..=10)
+            .map(|frame_id| Row {
+                frame_id,
+                title: "Mail",
+                embedding: [1.0, 0.0],
+                text: "invoice",
+                captured_at: frame_id,
+            })
+            .collect();
+        // Longer, so it comes last for the word, and the only one the title filter keeps
+        rows.push(Row {
+            frame_id: 11,
+            title: "Budget",
+            embedding: [0.0, 1.0],
+            text: "the invoice from last month that still needs paying",
+            captured_at: 11,
+        });
+        let (table, index) = frames(&data_dir, &rows).await;
+        let embedder = Fixed(vec![1.0, 0.0]);
+
+        // A limit of 1 starts with 4 candidates from each side, none of which are the budget
+        let options = SearchOptions {
+            limit: 1,
+            title: Some("Budget".into()),
+            ..SearchOptions::default()
+        };
+        let hits = search(&table, &index, &embedder, None, "invoice", &options)
+            .await
+            .unwrap();
+        assert_eq!(ids(&hits), [11]);
+
+        // Ends once both sides run dry, even with nothing left after filtering
+        let options = SearchOptions {
+            title: Some("Calendar".into()),
+            ..SearchOptions::default()
+        };
+        let hits = search(&table, &index, &embedder, None, "invoice", &options)
+            .await
+            .unwrap();
+        assert!(hits.is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::ocr::{BoundingBox, OcrEngine, OcrLine};
+
+/// Shells out to the `tesseract` command line tool, for when Vision isn't around.
+pub struct TesseractOcr {
+    languages: Vec<String>,
+    page_segmentation_mode: u32,
+}
+
+/// A line as tesseract describes it in pixels, before it gets normalized.
+#[derive(Default)]
+struct PixelLine {
+    words: Vec<String>,
+    confidences: Vec<f32>,
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+}
+
+impl TesseractOcr {
+    /// `languages` are tesseract language codes like `eng`, and `page_segmentation_mode` is
+    /// tesseract's `--psm` (3 being fully automatic, 11 being sparse text).
+    pub fn new(languages: Vec<String>, page_segmentation_mode: u32) -> Result<Self> {
+        let version = Command::new("tesseract").arg("--version").output();
+        if !version.map(|o| o.status.success()).unwrap_or(false) {
+            return Err(anyhow!("Unable to run tesseract, is it installed?"));
+        }
+
+        Ok(Self {
+            languages,
+            page_segmentation_mode,
+        })
+    }
+}
+
+impl OcrEngine for TesseractOcr {
+    fn recognize(&self, jpeg: &[u8]) -> Result<Vec<OcrLine>> {
+        let mut child = Command::new("tesseract")
+            .args(["stdin", "stdout", "-l", &self.languages.join("+")])
+            .args(["--psm", &self.page_segmentation_mode.to_string(), "tsv"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("Unable to write to tesseract"))?
+            .write_all(jpeg)?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "tesseract failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        parse_tsv(&String::from_utf8(output.stdout)?)
+    }
+}
+
+/// Groups tesseract's word-level TSV into lines with Vision-style normalized bounding boxes.
+fn parse_tsv(tsv: &str) -> Result<Vec<OcrLine>> {
+    let (mut page_width, mut page_height) = (0, 0);
+    // Keyed by (page, block, paragraph, line), which sorts into reading order
+    let mut lines: BTreeMap<(u32, u32, u32, u32), PixelLine> = BTreeMap::new();
+    for row in tsv.lines().skip(1) {
+        let columns: Vec<&str> = row.splitn(12, '\t').collect();
+        if columns.len() < 11 {
+            continue;
+        }
+        let numbers = columns[..10]
+            .iter()
+            .map(|c| c.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let (level, left, top, width, height) =
+            (numbers[0], numbers[6], numbers[7], numbers[8], numbers[9]);
+
+        if level == 1 {
+            (page_width, page_height) = (width, height);
+            continue;
+        }
+
+        let confidence: f32 = columns[10].parse()?;
+        let text = columns.get(11).map(|t| t.trim()).unwrap_or("");
+        if level != 5 || confidence < 0.0 || text.is_empty() {
+            continue;
+        }
+
+        let line = lines
+            .entry((numbers[1], numbers[2], numbers[3], numbers[4]))
+            .or_insert_with(|| PixelLine {
+                left: u32::MAX,
+                top: u32::MAX,
+                ..Default::default()
+            });
+        line.words.push(text.to_string());
+        line.confidences.push(confidence / 100.0);
+        line.left = line.left.min(left);
+        line.top = line.top.min(top);
+        line.right = line.right.max(left + width);
+        line.bottom = line.bottom.max(top + height);
+    }
+
+    if page_width == 0 || page_height == 0 {
+        return Ok(Vec::new());
+    }
+    let (page_width, page_height) = (page_width as f32, page_height as f32);
+    Ok(lines
+        .into_values()
+        .map(|line| OcrLine {
+            text: line.words.join(" "),
+            confidence: line.confidences.iter().sum::<f32>() / line.confidences.len() as f32,
+            bounds: BoundingBox {
+                x: line.left as f32 / page_width,
+                // Tesseract measures from the top, Vision from the bottom
+                y: 1.0 - line.bottom as f32 / page_height,
+                width: (line.right - line.left) as f32 / page_width,
+                height: (line.bottom - line.top) as f32 / page_height,
+            },
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `tesseract stdin stdout tsv` says about a 200 by 100 image with two lines in one
+    /// block and a word off in another, listed first.
+    const TSV: &str = "\
+level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext
+1\t1\t0\t0\t0\t0\t0\t0\t200\t100\t-1\t
+2\t1\t2\t0\t0\t0\t150\t80\t40\t10\t-1\t
+5\t1\t2\t1\t1\t1\t150\t80\t40\t10\t70\tsidebar
+2\t1\t1\t0\t0\t0\t10\t10\t100\t60\t-1\t
+3\t1\t1\t1\t0\t0\t10\t10\t100\t60\t-1\t
+4\t1\t1\t1\t1\t0\t10\t10\t100\t20\t-1\t
+5\t1\t1\t1\t1\t1\t10\t10\t40\t20\t95\tHello
+5\t1\t1\t1\t1\t2\t60\t12\t50\t18\t85\tworld
+5\t1\t1\t1\t1\t3\t115\t10\t5\t20\t-1\t 
+4\t1\t1\t1\t2\t0\t10\t50\t60\t20\t-1\t
+5\t1\t1\t1\t2\t1\t10\t50\t60\t20\t90\tsecond
+";
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} isn't {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn groups_words_into_lines_in_reading_order() {
+        let lines = parse_tsv(TSV).unwrap();
+        let texts: Vec<_> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["Hello world", "second", "sidebar"]);
+        assert_close(lines[0].confidence, 0.9);
+        assert_close(lines[2].confidence, 0.7);
+    }
+
+    #[test]
+    fn measures_lines_from_the_bottom_of_the_page() {
+        let lines = parse_tsv(TSV).unwrap();
+        // Both words of the first line, from 10 to 110 across and 10 to 30 down
+        let first = lines[0].bounds;
+        assert_close(first.x, 0.05);
+        assert_close(first.y, 0.7);
+        assert_close(first.width, 0.5);
+        assert_close(first.height, 0.2);
+        let second = lines[1].bounds;
+        assert_close(second.y, 0.3);
+        assert_close(second.height, 0.2);
+    }
+
+    #[test]
+    fn finds_nothing_without_a_page_and_rejects_garbage() {
+        assert!(parse_tsv("").unwrap().is_empty());
+        let header = TSV.lines().next().unwrap();
+        assert!(parse_tsv(header).unwrap().is_empty());
+        assert!(parse_tsv(&format!("{}\n1\t1\tx\t0\t0\t0\t0\t0\t1\t1\t-1\t", header)).is_err());
+    }
+}
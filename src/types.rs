@@ -1,15 +1,123 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::diff::Tiles;
+use crate::queue;
 
 pub struct State {
     pub windows: HashMap<u32, Window>,
+    #[cfg(target_os = "macos")]
     pub window_open: bool,
+    pub health: Health,
+}
+
+/// How recording is going, kept up to date by the worker for whatever wants to show it.
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    pub status: Status,
+    /// When a tick last went through.
+    pub recorded_at: Option<SystemTime>,
+    /// The most recent failure, even once recording has recovered from it.
+    pub last_error: Option<Failure>,
+    /// Ticks that have failed in a row.
+    pub failures: u32,
+    /// How many times the worker has been started over.
+    pub restarts: u32,
+    /// Frames waiting to make it into the table.
+    pub queue: queue::Stats,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Status {
+    #[default]
+    Starting,
+    Recording,
+    /// A tick failed, and the next one waits a while.
+    Retrying,
+    /// Too many ticks failed, or the worker couldn't start, so it's being set up from scratch.
+    Restarting,
+    Stopped,
+}
+
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub at: SystemTime,
+    pub message: String,
+}
+
+impl Health {
+    pub fn recorded(&mut self) {
+        self.status = Status::Recording;
+        self.recorded_at = Some(SystemTime::now());
+        self.failures = 0;
+    }
+
+    pub fn failed(&mut self, status: Status, error: &anyhow::Error) {
+        self.status = status;
+        self.last_error = Some(Failure {
+            at: SystemTime::now(),
+            message: format!("{:#}", error),
+        });
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Status::Starting => write!(f, "Starting")?,
+            Status::Recording => write!(f, "Recording")?,
+            Status::Retrying => write!(f, "Retrying after {} failed attempts", self.failures)?,
+            Status::Restarting => write!(f, "Restarting")?,
+            Status::Stopped => write!(f, "Stopped")?,
+        }
+        if self.restarts > 0 {
+            write!(f, ", restarted {} times", self.restarts)?;
+        }
+        if let Some(at) = self.recorded_at {
+            write!(f, "\nLast recorded at {}", time(at))?;
+        }
+        if let Some(error) = &self.last_error {
+            write!(f, "\nLast error at {}: {}", time(error.at), error.message)?;
+        }
+        if self.queue.depth > 0 {
+            write!(f, "\n{}", self.queue)?;
+        }
+        Ok(())
+    }
 }
 
+fn time(at: SystemTime) -> impl fmt::Display {
+    DateTime::<Local>::from(at).format("%Y-%m-%d %H:%M:%S")
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Window {
     pub id: u32,
     pub title: String,
+    /// The application that owns the window, when the backend can tell.
+    pub app: Option<String>,
+    pub bounds: WindowBounds,
+    pub display_id: Option<u32>,
+    pub captured_at: SystemTime,
+    /// Kept in the blob store rather than alongside the rest when queued.
+    #[serde(skip)]
     pub jpeg: Vec<u8>,
     pub jpeg_metrohash: u64,
+    /// From `diff::fingerprint`, for telling whether and where the window really changed.
+    pub perceptual_hash: u64,
+    pub tiles: Tiles,
     //pub jpeg_small: Vec<u8>,
     pub z: usize,
 }
+
+/// Where a window sits on screen. Points on macOS, pixels everywhere else.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct WindowBounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use lancedb::index::MetricType;
+use std::fmt;
+
+use crate::config::IndexConfig;
+
+const COLUMN: &str = "embedding";
+/// IVF-PQ trains on the rows it indexes, and can't with fewer than this.
+pub const MIN_ROWS: usize = 256;
+
+/// How far along the vector index is.
+pub struct IndexStatus {
+    pub rows: usize,
+    /// How many rows are and aren't covered, if there's an index at all.
+    pub indexed: Option<(usize, usize)>,
+}
+
+impl fmt::Display for IndexStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.indexed {
+            Some((indexed, unindexed)) => write!(
+                f,
+                "{} rows, {} in the IVF-PQ index and {} searched by brute force",
+                self.rows, indexed, unindexed
+            ),
+            None => write!(f, "{} rows, no index yet", self.rows),
+        }
+    }
+}
+
+pub async fn status(table: &lancedb::Table) -> Result<IndexStatus> {
+    let rows = table.count_rows(None).await?;
+    let native = table
+        .as_native()
+        .ok_or_else(|| anyhow!("{} isn't a local table", table.name()))?;
+    let index = native
+        .load_indices()
+        .await?
+        .into_iter()
+        .find(|index| index.columns == [COLUMN]);
+    let indexed = match index {
+        Some(index) => native
+            .count_indexed_rows(&index.index_uuid)
+            .await?
+            .zip(native.count_unindexed_rows(&index.index_uuid).await?),
+        None => None,
+    };
+    Ok(IndexStatus { rows, indexed })
+}
+
+/// Builds the index once there are enough rows for one to help, and rebuilds it once too many
+/// rows have been added since. Returns whether anything was built.
+pub async fn maintain(table: &lancedb::Table, config: &IndexConfig) -> Result<bool> {
+    let status = status(table).await?;
+    let due = match status.indexed {
+        None => status.rows >= config.min_rows,
+        Some((_, unindexed)) => unindexed >= config.rebuild_after,
+    };
+    if due {
+        build(table, config, status.rows).await?;
+    }
+    Ok(due)
+}
+
+/// Builds the index from scratch, replacing any that's there.
+pub async fn build(table: &lancedb::Table, config: &IndexConfig, rows: usize) -> Result<()> {
+    if rows < MIN_ROWS {
+        return Err(anyhow!(
+            "There are only {} frames, and an index needs at least {} to train on",
+            rows,
+            MIN_ROWS
+        ));
+    }
+    let dimension = embedding_dimension(table).await?;
+    // The usual rules of thumb: about sqrt(rows) partitions, and sub-vectors of 16 dimensions
+    let partitions = config
+        .num_partitions
+        .unwrap_or_else(|| ((rows as f64).sqrt() as u32).clamp(1, 4096));
+    let sub_vectors = config
+        .num_sub_vectors
+        .unwrap_or_else(|| divisor_near(dimension, dimension / 16));
+    if !dimension.is_multiple_of(sub_vectors) {
+        return Err(anyhow!(
+            "index.num_sub_vectors must divide the embedding dimension {}",
+            dimension
+        ));
+    }
+    table
+        .create_index(&[COLUMN])
+        .ivf_pq()
+        .num_partitions(partitions)
+        .num_sub_vectors(sub_vectors)
+        // Searches don't say, so they measure L2 distances too
+        .metric_type(MetricType::L2)
+        .replace(true)
+        .build()
+        .await?;
+    Ok(())
+}
+
+async fn embedding_dimension(table: &lancedb::Table) -> Result<u32> {
+    let schema = table.schema().await?;
+    match schema.field_with_name(COLUMN)?.data_type() {
+        arrow_schema::DataType::FixedSizeList(_, size) => Ok(*size as u32),
+        other => Err(anyhow!("Unexpected embedding type {}", other)),
+    }
+}
+
+/// PQ needs the number of sub-vectors to divide the dimension evenly.
+fn divisor_near(dimension: u32, target: u32) -> u32 {
+    (1..=target.max(1))
+        .rev()
+        .find(|d| dimension.is_multiple_of(*d))
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames;
+
+    #[tokio::test]
+    async fn wont_build_from_too_few_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = lancedb::connect(dir.path().to_str().unwrap())
+            .execute()
+            .await
+            .unwrap();
+        let table = db
+            .create_empty_table(frames::TABLE, frames::schema(32))
+            .execute()
+            .await
+            .unwrap();
+
+        let before = status(&table).await.unwrap();
+        let error = build(&table, &IndexConfig::default(), before.rows)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("at least 256"), "{}", error);
+        assert_eq!(status(&table).await.unwrap().indexed, None);
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use reqwest::header::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+
+use crate::config::VertexConfig;
+use crate::embedder::Embedder;
+use crate::google_auth::{self, TokenProvider};
+
+#[derive(Debug, Deserialize, Serialize)]
+struct EmbeddingRequest {
+    instances: Vec<EmbeddingRequestInstance>,
+    parameters: EmbeddingRequestParameters,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct EmbeddingRequestParameters {
+    dimension: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct EmbeddingRequestInstance {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<EmbeddingRequestInstanceImage>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct EmbeddingRequestInstanceImage {
+    bytesBase64Encoded: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct EmbeddingResponse {
+    predictions: Vec<EmbeddingResponsePrediction>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct EmbeddingResponsePrediction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imageEmbedding: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    textEmbedding: Option<Vec<f32>>,
+}
+
+/// Google's Vertex AI multimodal embedding model.
+pub struct VertexEmbedder {
+    client: reqwest::Client,
+    tokens: Box<dyn TokenProvider>,
+    url: String,
+    model: String,
+    dimension: usize,
+    prompt: String,
+}
+
+impl VertexEmbedder {
+    pub fn new(config: &VertexConfig) -> Result<Self> {
+        let VertexConfig {
+            project,
+            location,
+            model,
+            ..
+        } = config;
+        Ok(Self {
+            client: reqwest::Client::new(),
+            tokens: google_auth::from_config(
+                config.credentials.as_deref(),
+                config.token_uri.as_deref(),
+            )?,
+            url: format!(
+                "https://{location}-aiplatform.googleapis.com/v1/projects/{project}/\
+                    locations/{location}/publishers/google/models/{model}:predict",
+            ),
+            model: model.clone(),
+            dimension: config.dimension,
+            prompt: config.prompt.clone(),
+        })
+    }
+
+    async fn predict(
+        &self,
+        instance: EmbeddingRequestInstance,
+    ) -> Result<EmbeddingResponsePrediction> {
+        let token = self.tokens.token().await?;
+        let response = self
+            .client
+            .post(&self.url)
+            .header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+            .json(&EmbeddingRequest {
+                instances: vec![instance],
+                parameters: EmbeddingRequestParameters {
+                    dimension: self.dimension,
+                },
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<EmbeddingResponse>()
+            .await?;
+        response
+            .predictions
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Vertex returned no predictions"))
+    }
+}
+
+#[async_trait]
+impl Embedder for VertexEmbedder {
+    async fn embed_image(&self, jpeg: &[u8]) -> Result<Vec<f32>> {
+        self.predict(EmbeddingRequestInstance {
+            text: Some(self.prompt.clone()),
+            image: Some(EmbeddingRequestInstanceImage {
+                bytesBase64Encoded: STANDARD.encode(jpeg),
+            }),
+        })
+        .await?
+        .imageEmbedding
+        .ok_or_else(|| anyhow!("Vertex returned no image embedding"))
+    }
+
+    async fn embed_text(&self, text: &str) -> Result<Vec<f32>> {
+        self.predict(EmbeddingRequestInstance {
+            text: Some(text.into()),
+            image: None,
+        })
+        .await?
+        .textEmbedding
+        .ok_or_else(|| anyhow!("Vertex returned no text embedding"))
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// An embedder that gets its tokens from, and sends its predictions to, `server`.
+    async fn embedder(server: &MockServer, dir: &std::path::Path) -> VertexEmbedder {
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "token",
+                "expires_in": 3600,
+                "token_type": "Bearer",
+            })))
+            .mount(server)
+            .await;
+        let credentials = dir.join("credentials.json");
+        std::fs::write(
+            &credentials,
+            json!({
+                "type": "authorized_user",
+                "client_id": "client",
+                "client_secret": "secret",
+                "refresh_token": "refresh",
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let mut embedder = VertexEmbedder::new(&VertexConfig {
+            dimension: 128,
+            prompt: "a screenshot".into(),
+            credentials: Some(credentials),
+            token_uri: Some(format!("{}/token", server.uri())),
+            ..VertexConfig::default()
+        })
+        .unwrap();
+        embedder.url = format!("{}/predict", server.uri());
+        embedder
+    }
+
+    fn prediction(prediction: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({ "predictions": [prediction] }))
+    }
+
+    #[tokio::test]
+    async fn embeds_screenshots_along_with_the_prompt() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/predict"))
+            .and(header("authorization", "Bearer token"))
+            .and(body_partial_json(json!({
+                "instances": [{
+                    "text": "a screenshot",
+                    "image": { "bytesBase64Encoded": "/9g=" },
+                }],
+                "parameters": { "dimension": 128 },
+            })))
+            .respond_with(prediction(
+                json!({ "imageEmbedding": [0.5], "textEmbedding": [0.25] }),
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let embedder: Box<dyn Embedder> = Box::new(embedder(&server, dir.path()).await);
+        assert_eq!(
+            embedder.embed_image(&[0xff, 0xd8]).await.unwrap(),
+            vec![0.5]
+        );
+        assert_eq!(embedder.dimension(), 128);
+        assert_eq!(embedder.model_id(), "multimodalembedding@001");
+    }
+
+    #[tokio::test]
+    async fn embeds_text_on_its_own() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/predict"))
+            .and(body_partial_json(
+                json!({ "instances": [{ "text": "hello" }] }),
+            ))
+            .respond_with(prediction(json!({ "textEmbedding": [0.25] })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let embedder = embedder(&server, dir.path()).await;
+        assert_eq!(embedder.embed_text("hello").await.unwrap(), vec![0.25]);
+        let request = &server.received_requests().await.unwrap()[1];
+        let body: serde_json::Value = request.body_json().unwrap();
+        assert!(body["instances"][0].get("image").is_none());
+    }
+
+    #[tokio::test]
+    async fn says_when_vertex_leaves_out_the_embedding() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/predict"))
+            .respond_with(prediction(json!({ "textEmbedding": [0.25] })))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let embedder = embedder(&server, dir.path()).await;
+        let error = embedder.embed_image(&[0xff, 0xd8]).await.unwrap_err();
+        assert_eq!(error.to_string(), "Vertex returned no image embedding");
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use cocoa::base::{id, nil, NO};
+use cocoa::foundation::{NSArray, NSAutoreleasePool, NSData, NSRange, NSString, NSUInteger};
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::objc_ffi::{
+    VNImageRequestHandler, VNRecognizeTextRequest, VNRecognizedText, VNRecognizedTextObservation,
+    VNRectangleObservation,
+};
+use crate::ocr::{BoundingBox, OcrEngine, OcrLine};
+
+/// Apple's Vision framework, which is very good and entirely on-device.
+pub struct VisionOcr;
+
+impl OcrEngine for VisionOcr {
+    fn recognize(&self, jpeg: &[u8]) -> Result<Vec<OcrLine>> {
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let lines = recognize(jpeg);
+            pool.drain();
+            lines
+        }
+    }
+}
+
+unsafe fn recognize(jpeg: &[u8]) -> Result<Vec<OcrLine>> {
+    let data = NSData::dataWithBytes_length_(nil, jpeg.as_ptr() as *mut c_void, jpeg.len() as u64);
+    let handler = VNImageRequestHandler::alloc(nil)
+        .initWithData(data)
+        .autorelease();
+    let request = VNRecognizeTextRequest::alloc(nil).init().autorelease();
+    if handler.performRequests(&[request], None) == NO {
+        return Err(anyhow!("Vision was unable to recognize text"));
+    }
+
+    let mut lines = Vec::new();
+    let results = request.results();
+    for i in 0..results.count() {
+        let candidates = results.objectAtIndex(i).topCandidates(1);
+        if candidates.count() == 0 {
+            continue;
+        }
+
+        let candidate: id = candidates.objectAtIndex(0);
+        let raw_string = candidate.string();
+        // NSRange counts UTF-16 code units, not bytes
+        let length: NSUInteger = msg_send![raw_string, length];
+        let range = NSRange::new(0, length);
+        let rect = candidate.boundingBoxForRange(&range, None);
+        if rect == nil {
+            continue;
+        }
+        let bounds = rect.boundingBox();
+
+        lines.push(OcrLine {
+            text: CStr::from_ptr(raw_string.UTF8String()).to_str()?.to_string(),
+            confidence: candidate.confidence(),
+            bounds: BoundingBox {
+                x: bounds.origin.x as f32,
+                y: bounds.origin.y as f32,
+                width: bounds.size.width as f32,
+                height: bounds.size.height as f32,
+            },
+        });
+    }
+    Ok(lines)
+}
@@ -1,228 +1,654 @@
-use anyhow::Result;
-use arrow_array::types::Float32Type;
-use arrow_array::{FixedSizeListArray, RecordBatch, RecordBatchIterator, UInt64Array};
-use arrow_schema::{DataType, Field, Schema};
-use base64::{engine::general_purpose::STANDARD, Engine as _};
-use futures::future;
-use futures::TryStreamExt;
-use reqwest::header::AUTHORIZATION;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::process::Command;
+use anyhow::{anyhow, Result};
+use arrow_schema::DataType;
+use std::future::Future;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 
-use crate::screenshots::get_windows;
-use crate::types::{State, Window};
+use crate::capture::CaptureBackend;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::embedder::{embedder_from_config, Embedder};
+use crate::frames;
+use crate::fts::TextIndex;
+use crate::migrations;
+use crate::ocr::{engine_from_config, OcrEngine};
+use crate::pipeline::{Engines, Persist, Pipeline, Report};
+use crate::queue::Queue;
+use crate::types::{State, Status};
 
-#[derive(Debug, Deserialize, Serialize)]
-struct EmbeddingRequest {
-    instances: Vec<EmbeddingRequestInstance>,
-}
+/// How long whatever's in flight gets to finish once recording is cancelled.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Debug, Deserialize, Serialize)]
-struct EmbeddingRequestInstance {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    text: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    image: Option<EmbeddingRequestInstanceImage>,
-}
+/// How long to wait after a tick that failed, doubling from the first up to the second.
+const RETRY_BACKOFF: (Duration, Duration) = (Duration::from_secs(5), Duration::from_secs(5 * 60));
 
-#[derive(Debug, Deserialize, Serialize)]
-struct EmbeddingRequestInstanceImage {
-    bytesBase64Encoded: String,
-}
+/// After this many failed ticks in a row the worker is started over.
+const MAX_FAILURES: u32 = 5;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct EmbeddingResponse {
-    predictions: Vec<EmbeddingResponsePrediction>,
-}
+/// Likewise between restarts.
+const RESTART_BACKOFF: (Duration, Duration) =
+    (Duration::from_secs(10), Duration::from_secs(30 * 60));
 
-#[derive(Debug, Deserialize, Serialize)]
-struct EmbeddingResponsePrediction {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    imageEmbedding: Option<Vec<f32>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    textEmbedding: Option<Vec<f32>>,
+/// `embedder` and `ocr` are expected to have been built from the current `config`, and get
+/// rebuilt whenever it changes. Records until the backend runs out or `cancel` fires, at which
+/// point the frames being worked on are still written out, as long as that takes less than
+/// `DRAIN_TIMEOUT`.
+///
+/// Nothing short of that stops it. Failed ticks are retried, and if they keep failing, or the
+/// worker can't get going in the first place, it's set up again from scratch with a fresh
+/// embedder and OCR engine, and a fresh backend from `new_backend` in case it was the one that
+/// broke. Only the first backend failing to start stops it. How it's going is kept in
+/// `State::health`.
+#[tokio::main]
+pub async fn record_state_loop(
+    state_mutex: Arc<Mutex<State>>,
+    data_dir: DataDir,
+    mut config: watch::Receiver<Config>,
+    new_backend: impl Fn() -> Result<Box<dyn CaptureBackend>>,
+    embedder: Box<dyn Embedder>,
+    ocr: Option<Box<dyn OcrEngine>>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    // Not being able to capture at all is more likely a setup problem than a passing one
+    let mut backend =
+        Some(new_backend().map_err(|e| anyhow!("Unable to start capturing windows: {:#}", e))?);
+    let mut engines = Some((embedder, ocr));
+    let mut restarts = Restarts::new();
+    loop {
+        let result = async {
+            let mut backend = match backend.take() {
+                Some(backend) => backend,
+                None => new_backend()?,
+            };
+            let (embedder, ocr) = match engines.take() {
+                Some(engines) => engines,
+                None => {
+                    let current = config.borrow().clone();
+                    (
+                        embedder_from_config(&current.embedder)?,
+                        engine_from_config(&current.ocr)?,
+                    )
+                }
+            };
+            run(
+                &state_mutex,
+                &data_dir,
+                &mut config,
+                backend.as_mut(),
+                embedder,
+                ocr,
+                &cancel,
+            )
+            .await
+        }
+        .await;
+        let e = match result {
+            Ok(()) => break,
+            // Quitting comes first
+            Err(e) if cancel.is_cancelled() => {
+                state_mutex
+                    .lock()
+                    .unwrap()
+                    .health
+                    .failed(Status::Stopped, &e);
+                return Err(e);
+            }
+            Err(e) => e,
+        };
+        let delay = restarts.delay(state_mutex.lock().unwrap().health.recorded_at);
+        eprintln!(
+            "Recording stopped working, starting over in {}s: {:#}",
+            delay.as_secs(),
+            e
+        );
+        {
+            let mut state = state_mutex.lock().unwrap();
+            state.health.failed(Status::Restarting, &e);
+            state.health.restarts += 1;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => break,
+        }
+    }
+    state_mutex.lock().unwrap().health.status = Status::Stopped;
+    if cancel.is_cancelled() {
+        println!("Stopped recording");
+    }
+    Ok(())
 }
 
-const LOOP_DURATION: Duration = Duration::from_secs(10);
-
-const VECTOR_TABLE: &str = "screenshots";
+/// Records until the backend runs out or `cancel` fires. Fails when it can't get started or
+/// ticks keep failing.
+async fn run(
+    state_mutex: &Arc<Mutex<State>>,
+    data_dir: &DataDir,
+    config: &mut watch::Receiver<Config>,
+    backend: &mut dyn CaptureBackend,
+    embedder: Box<dyn Embedder>,
+    ocr: Option<Box<dyn OcrEngine>>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    {
+        let mut state = state_mutex.lock().unwrap();
+        state.health.status = Status::Starting;
+        state.health.failures = 0;
+        // Start from what's on screen rather than what was last queued
+        state.windows.clear();
+    }
+    let queue = Arc::new(Queue::open(data_dir)?);
+    let waiting = queue.stats();
+    if waiting.depth > 0 {
+        println!("Picking up where it left off: {}", waiting);
+    }
+    state_mutex.lock().unwrap().health.queue = waiting;
+    let db = lancedb::connect(&data_dir.database_uri()?)
+        .execute()
+        .await?;
+    let mut text_writer = TextIndex::open(&data_dir.text_index(), data_dir.cipher())?.writer()?;
+    if let Some(plan) = migrations::migrate(&db, data_dir, Some(&mut text_writer), false).await? {
+        print!("Migrated {}", plan);
+    }
 
-#[tokio::main]
-pub async fn record_state_loop(state_mutex: Arc<Mutex<State>>) -> Result<()> {
-    let db = lancedb::connect("data-ldb").execute().await?;
-
-    let vector_schema = Arc::new(Schema::new(vec![
-        Field::new("metrohash", DataType::UInt64, false),
-        Field::new(
-            "embedding",
-            DataType::FixedSizeList(Arc::new(Field::new("item", DataType::Float32, true)), 1408),
-            true,
-        ),
-    ]));
     let table = db
-        .create_empty_table(VECTOR_TABLE, vector_schema.clone())
+        .create_empty_table(frames::TABLE, frames::schema(embedder.dimension()))
         .mode(lancedb::connection::CreateTableMode::ExistOk(Box::new(
             |t| t,
         )))
         .execute()
         .await?;
+    check_embedder(&table, embedder.as_ref()).await?;
 
-    let google_key = String::from_utf8(
-        Command::new("gcloud")
-            .args(["auth", "print-access-token"])
-            .output()
-            .expect("Unable to get Google token")
-            .stdout,
-    )?;
-    let client = reqwest::Client::new();
-    let response = client
-        .post(
-            "https://us-west1-aiplatform.googleapis.com/v1/projects/1012868746574/\
-                locations/us-west1/publishers/google/models/\
-                multimodalembedding@001:predict",
-        )
-        .header(AUTHORIZATION, format!("Bearer {}", google_key.trim()))
-        .json(&EmbeddingRequest {
-            instances: vec![EmbeddingRequestInstance {
-                text: Some("A screenshot about mass production of coffee".into()),
-                image: None,
-            }],
-        })
-        .send()
-        .await?
-        .json::<EmbeddingResponse>()
-        .await?;
-    let results = table
-        .search(response.predictions[0].textEmbedding.as_ref().unwrap())
-        .select(&["metrohash"])
-        .limit(10)
-        .execute_stream()
-        .await?
-        .try_collect::<Vec<_>>()
-        .await?;
-    println!("{:?}", results);
+    let persist = Persist::new(
+        table.clone(),
+        embedder.dimension(),
+        data_dir.clone(),
+        text_writer,
+        queue.clone(),
+    );
+    let (engines, engines_receiver) = watch::channel(Engines {
+        embedder: embedder.into(),
+        ocr: ocr.map(Arc::from),
+    });
+    let mut pipeline = Pipeline::start(
+        state_mutex.clone(),
+        config.clone(),
+        engines_receiver,
+        queue.clone(),
+        persist,
+    );
+    let mut recorder = Recorder {
+        state_mutex: state_mutex.clone(),
+        table,
+        engines,
+        queue,
+        config: config.borrow_and_update().clone(),
+        failed_tick: None,
+    };
 
-    // TODO(april): Need better termination handling
-    loop {
+    let mut tick = 0;
+    let mut backoff = Backoff::new(RETRY_BACKOFF);
+    while !backend.is_exhausted() && !cancel.is_cancelled() {
         let start = Instant::now();
-        record_state(&table, &vector_schema, &state_mutex).await?;
-        let end = Instant::now();
-        thread::sleep(start + LOOP_DURATION - end);
+        // The sender goes away when there's no config file to watch
+        if config.has_changed().unwrap_or(false) {
+            let current = config.borrow_and_update().clone();
+            recorder.apply_config(current).await;
+        }
+        tick += 1;
+        match tokio::task::block_in_place(|| backend.get_windows()) {
+            // Replays wait their turn rather than skip frames, since there's no hurry
+            Ok(windows) => {
+                if !pipeline
+                    .capture(tick, windows, !backend.is_realtime())
+                    .await?
+                {
+                    eprintln!("Falling behind, skipping a capture");
+                }
+            }
+            Err(e) => recorder.failed(tick, e)?,
+        }
+        recorder.update_queue();
+
+        let pause = match recorder.is_failing() {
+            true => backoff.next(),
+            false if backend.is_realtime() => {
+                backoff.reset();
+                recorder.config.capture.interval()
+            }
+            false => Duration::ZERO,
+        };
+        let until = tokio::time::Instant::from_std(start + pause);
+        loop {
+            tokio::select! {
+                biased;
+                Some(report) = pipeline.report() => recorder.handle(report)?,
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep_until(until) => break,
+            }
+        }
     }
+
+    // Whatever has been captured is still written out
+    let Some(finished) = drain(cancel, pipeline.finish()).await else {
+        return Err(match pipeline.is_housekeeping() {
+            true => anyhow!(
+                "Gave up on housekeeping {}s after being asked to stop, it'll run again next time",
+                DRAIN_TIMEOUT.as_secs()
+            ),
+            false => anyhow!(
+                "Gave up on frames still in flight {}s after being asked to stop",
+                DRAIN_TIMEOUT.as_secs()
+            ),
+        });
+    };
+    finished?;
+    while let Some(report) = pipeline.try_report() {
+        // Too late to try again
+        let _ = recorder.handle(report);
+    }
+    Ok(())
 }
 
-async fn record_state(
-    table: &lancedb::Table,
-    schema: &Arc<Schema>,
-    state_mutex: &Arc<Mutex<State>>,
-) -> Result<()> {
-    let (changed, unchanged) = get_and_compare_windows(&state_mutex)?;
-
-    let google_key = String::from_utf8(
-        Command::new("gcloud")
-            .args(["auth", "print-access-token"])
-            .output()
-            .expect("Unable to get Google token")
-            .stdout,
-    )?;
-
-    let client = reqwest::Client::new();
-    let mut responses = Vec::new();
-    for window in &changed {
-        let request = EmbeddingRequest {
-            instances: vec![EmbeddingRequestInstance {
-                text: Some(
-                    "Provide a full description of this screenshot. Be as accurate and detailed \
-                    as possible."
-                        .into(),
-                ),
-                image: Some(EmbeddingRequestInstanceImage {
-                    bytesBase64Encoded: STANDARD.encode(&window.jpeg),
-                }),
-            }],
+/// Exponential backoff between `min` and `max`.
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    fn new((min, max): (Duration, Duration)) -> Self {
+        Self {
+            min,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    fn next(&mut self) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Spaces out restarts, only backing off further while they keep coming without anything being
+/// recorded in between.
+struct Restarts {
+    backoff: Backoff,
+    recorded_at: Option<SystemTime>,
+}
+
+impl Restarts {
+    fn new() -> Self {
+        Self {
+            backoff: Backoff::new(RESTART_BACKOFF),
+            recorded_at: None,
+        }
+    }
+
+    /// How long to wait before starting over, given when a tick last went through.
+    fn delay(&mut self, recorded_at: Option<SystemTime>) -> Duration {
+        if recorded_at != self.recorded_at {
+            self.recorded_at = recorded_at;
+            self.backoff.reset();
+        }
+        self.backoff.next()
+    }
+}
+
+/// Runs `work` to the end, unless it's still going `DRAIN_TIMEOUT` after `cancel` fires, in
+/// which case it's given up on.
+async fn drain<T>(cancel: &CancellationToken, work: impl Future<Output = T>) -> Option<T> {
+    tokio::pin!(work);
+    tokio::select! {
+        output = &mut work => return Some(output),
+        _ = cancel.cancelled() => {}
+    }
+    tokio::time::timeout(DRAIN_TIMEOUT, work).await.ok()
+}
+
+/// Keeps the pipeline in step with the config, and health in step with the pipeline.
+struct Recorder {
+    state_mutex: Arc<Mutex<State>>,
+    table: lancedb::Table,
+    engines: watch::Sender<Engines>,
+    queue: Arc<Queue>,
+    /// What the engines were built from.
+    config: Config,
+    /// The last tick anything failed on.
+    failed_tick: Option<u64>,
+}
+
+impl Recorder {
+    /// Swaps in whatever changed. Anything that fails to start leaves the old one running.
+    async fn apply_config(&mut self, current: Config) {
+        if current.embedder != self.config.embedder {
+            let replacement = match embedder_from_config(&current.embedder) {
+                Ok(replacement) => check_embedder(&self.table, replacement.as_ref())
+                    .await
+                    .map(|_| replacement),
+                Err(e) => Err(e),
+            };
+            match replacement {
+                Ok(replacement) => {
+                    println!("Now embedding with {}", replacement.model_id());
+                    self.engines
+                        .send_modify(|engines| engines.embedder = replacement.into());
+                }
+                Err(e) => eprintln!("Keeping the previous embedder: {:#}", e),
+            }
+        }
+        if current.ocr != self.config.ocr {
+            match engine_from_config(&current.ocr) {
+                Ok(replacement) => self
+                    .engines
+                    .send_modify(|engines| engines.ocr = replacement.map(Arc::from)),
+                Err(e) => eprintln!("Keeping the previous OCR engine: {:#}", e),
+            }
+        }
+        if current.pipeline != self.config.pipeline {
+            println!("Pipeline settings apply the next time elephant starts");
+        }
+        if current.data_dir != self.config.data_dir {
+            println!("The data directory changes the next time elephant starts");
+        }
+        if current.encryption != self.config.encryption {
+            println!("Encryption settings apply the next time elephant starts");
+        }
+        self.config = current;
+    }
+
+    /// Keeps track of how frames fared. Fails once ticks have failed so many times in a row that
+    /// starting over might help.
+    fn handle(&mut self, report: Report) -> Result<()> {
+        let result = match report {
+            Report::Recorded => {
+                self.state_mutex.lock().unwrap().health.recorded();
+                Ok(())
+            }
+            Report::Failed {
+                tick,
+                frame_id,
+                error,
+            } => {
+                match self.queue.release(frame_id) {
+                    Ok(true) => eprintln!("Giving up on frame {}, it keeps failing", frame_id),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("{:#}", e),
+                }
+                self.failed(tick, error)
+            }
         };
-        responses.push(
-            client
-                .post(
-                    "https://us-west1-aiplatform.googleapis.com/v1/projects/1012868746574/\
-                    locations/us-west1/publishers/google/models/\
-                    multimodalembedding@001:predict",
-                )
-                .header(AUTHORIZATION, format!("Bearer {}", google_key.trim()))
-                .json(&request)
-                .send(),
-        );
+        self.update_queue();
+        result
     }
 
-    let mut embeddings = Vec::new();
-    for response in future::join_all(responses).await {
-        embeddings.push(
-            response?.json::<EmbeddingResponse>().await?.predictions[0]
-                .imageEmbedding
-                .clone()
-                .unwrap(),
-        );
+    fn failed(&mut self, tick: u64, error: anyhow::Error) -> Result<()> {
+        eprintln!("Unable to record, trying again: {:#}", error);
+        let mut state = self.state_mutex.lock().unwrap();
+        // Windows from the same tick tend to fail together, and only count once
+        if self.failed_tick != Some(tick) {
+            self.failed_tick = Some(tick);
+            state.health.failures += 1;
+        }
+        if state.health.failures >= MAX_FAILURES {
+            return Err(error.context(format!("{} ticks failed in a row", MAX_FAILURES)));
+        }
+        state.health.failed(Status::Retrying, &error);
+        Ok(())
+    }
+
+    fn update_queue(&self) {
+        self.state_mutex.lock().unwrap().health.queue = self.queue.stats();
+    }
+
+    fn is_failing(&self) -> bool {
+        self.state_mutex.lock().unwrap().health.failures > 0
+    }
+}
+
+/// Vectors from a model with a different output size can't go into an existing table, and ones
+/// from a different model of the same size would go in but be meaningless next to the others.
+pub async fn check_embedder(table: &lancedb::Table, embedder: &dyn Embedder) -> Result<()> {
+    let schema = table.schema().await?;
+    if let DataType::FixedSizeList(_, size) = schema.field_with_name("embedding")?.data_type() {
+        if *size as usize != embedder.dimension() {
+            return Err(anyhow!(
+                "The {} table holds {}-dimensional embeddings but {} produces {}",
+                frames::TABLE,
+                size,
+                embedder.model_id(),
+                embedder.dimension()
+            ));
+        }
+    }
+    // Frames from before models were recorded could have come from anything
+    let others = table
+        .count_rows(Some(format!(
+            "model_id NOT IN ({}, {})",
+            frames::quote(embedder.model_id()),
+            frames::quote(frames::UNKNOWN_MODEL)
+        )))
+        .await?;
+    if others > 0 {
+        return Err(anyhow!(
+            "The {} table holds {} frames embedded by a model other than {}, which can't be \
+             searched alongside its embeddings",
+            frames::TABLE,
+            others,
+            embedder.model_id()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::types::Float32Type;
+    use arrow_array::{FixedSizeListArray, RecordBatch, RecordBatchIterator, StringArray};
+    use arrow_schema::{Field, Schema};
+    use async_trait::async_trait;
+
+    struct Model(&'static str, usize);
+
+    #[async_trait]
+    impl Embedder for Model {
+        async fn embed_image(&self, _: &[u8]) -> Result<Vec<f32>> {
+            Err(anyhow!("{} only gets checked, not used", self.0))
+        }
+
+        async fn embed_text(&self, _: &str) -> Result<Vec<f32>> {
+            Err(anyhow!("{} only gets checked, not used", self.0))
+        }
+
+        fn dimension(&self) -> usize {
+            self.1
+        }
+
+        fn model_id(&self) -> &str {
+            self.0
+        }
     }
 
-    let new_batches = RecordBatchIterator::new(
-        vec![RecordBatch::try_new(
+    /// Just the columns `check_embedder` looks at, with a row per model.
+    async fn table(dir: &std::path::Path, models: &[&str]) -> lancedb::Table {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("model_id", DataType::Utf8, false),
+            Field::new(
+                "embedding",
+                DataType::FixedSizeList(Arc::new(Field::new("item", DataType::Float32, true)), 2),
+                true,
+            ),
+        ]));
+        let db = lancedb::connect(dir.to_str().unwrap())
+            .execute()
+            .await
+            .unwrap();
+        if models.is_empty() {
+            return db
+                .create_empty_table(frames::TABLE, schema)
+                .execute()
+                .await
+                .unwrap();
+        }
+        let batch = RecordBatch::try_new(
             schema.clone(),
             vec![
-                Arc::new(UInt64Array::from_iter_values(
-                    // TODO
-                    changed.iter().map(|w| w.jpeg_metrohash),
-                )),
+                Arc::new(StringArray::from(models.to_vec())),
                 Arc::new(
                     FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
-                        embeddings
-                            .iter()
-                            .map(|e| Some(e.iter().map(|i| Some(i.clone())))),
-                        1408,
+                        models.iter().map(|_| Some(vec![Some(1.0), Some(0.0)])),
+                        2,
                     ),
                 ),
             ],
         )
-        .unwrap()]
-        .into_iter()
-        .map(Ok),
-        schema.clone(),
-    );
-    table.add(Box::new(new_batches)).execute().await?;
+        .unwrap();
+        db.create_table(
+            frames::TABLE,
+            Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)),
+        )
+        .execute()
+        .await
+        .unwrap()
+    }
 
-    let mut mapped = HashMap::new();
-    for window in changed {
-        std::fs::write(format!("out/{}.jpg", window.jpeg_metrohash), &window.jpeg)?;
-        mapped.insert(window.id, window);
+    #[tokio::test]
+    async fn refuses_embedders_that_dont_match_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = table(dir.path(), &["a", frames::UNKNOWN_MODEL]).await;
+
+        assert!(check_embedder(&table, &Model("a", 2)).await.is_ok());
+        let other = check_embedder(&table, &Model("b", 2)).await.unwrap_err();
+        assert!(other
+            .to_string()
+            .contains("1 frames embedded by a model other than b"));
+        let wider = check_embedder(&table, &Model("a", 3)).await.unwrap_err();
+        assert!(wider.to_string().contains("2-dimensional"));
     }
-    for window in unchanged {
-        mapped.insert(window.id, window);
+
+    #[tokio::test]
+    async fn takes_any_model_of_the_right_size_into_an_empty_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = table(dir.path(), &[]).await;
+        assert!(check_embedder(&table, &Model("b", 2)).await.is_ok());
     }
-    let mut state = (*state_mutex).lock().unwrap();
-    state.windows = mapped;
 
-    Ok(())
-}
+    #[test]
+    fn backs_off_exponentially_up_to_the_max() {
+        let mut backoff = Backoff::new((Duration::from_secs(5), Duration::from_secs(60)));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next().as_secs()).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
 
-fn get_and_compare_windows(state_mutex: &Arc<Mutex<State>>) -> Result<(Vec<Window>, Vec<Window>)> {
-    let windows = get_windows()?;
-
-    let state = (*state_mutex).lock().unwrap();
-    let mut changed: Vec<Window> = Vec::new();
-    let mut unchanged: Vec<Window> = Vec::new();
-    for window in windows {
-        let last_window = state.windows.get(&window.id);
-        if let Some(last) = last_window {
-            if window.jpeg_metrohash == last.jpeg_metrohash {
-                unchanged.push(window);
-                continue;
-            }
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(5));
+
+        // Attempts well past where doubling overflows stay at the max
+        backoff.attempts = 100;
+        assert_eq!(backoff.next(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn starts_backing_off_again_after_recording_something() {
+        let (min, _) = RESTART_BACKOFF;
+        let mut restarts = Restarts::new();
+        assert_eq!(restarts.delay(None), min);
+        assert_eq!(restarts.delay(None), min * 2);
+        assert_eq!(restarts.delay(None), min * 4);
+
+        let recorded_at = Some(SystemTime::now());
+        assert_eq!(restarts.delay(recorded_at), min);
+        assert_eq!(restarts.delay(recorded_at), min * 2);
+    }
+
+    /// Never has anything on screen.
+    struct Empty;
+
+    impl CaptureBackend for Empty {
+        fn get_windows(&mut self) -> Result<Vec<crate::types::Window>> {
+            Ok(Vec::new())
         }
-        changed.push(window);
     }
 
-    Ok((changed, unchanged))
+    fn state() -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State {
+            windows: Default::default(),
+            #[cfg(target_os = "macos")]
+            window_open: false,
+            health: Default::default(),
+        }))
+    }
+
+    #[test]
+    fn gives_up_when_it_cant_capture_at_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_config, receiver) = watch::channel(Config::default());
+        let error = record_state_loop(
+            state(),
+            DataDir::new(dir.path().to_path_buf()),
+            receiver,
+            || Err(anyhow!("No display")),
+            Box::new(Model("a", 2)),
+            None,
+            CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to start capturing windows: No display"
+        );
+    }
+
+    #[test]
+    fn starts_over_when_the_worker_fails_until_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::new(dir.path().to_path_buf());
+        data_dir.create().unwrap();
+        // Somewhere the queue can't go
+        std::fs::remove_dir(data_dir.queue()).unwrap();
+        std::fs::write(data_dir.queue(), "").unwrap();
+        let (_config, receiver) = watch::channel(Config::default());
+        let state = state();
+        let cancel = CancellationToken::new();
+
+        let watching = std::thread::spawn({
+            let (state, cancel) = (state.clone(), cancel.clone());
+            move || {
+                while state.lock().unwrap().health.restarts == 0 {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                let health = state.lock().unwrap().health.clone();
+                cancel.cancel();
+                health
+            }
+        });
+        record_state_loop(
+            state.clone(),
+            data_dir,
+            receiver,
+            || Ok(Box::new(Empty)),
+            Box::new(Model("a", 2)),
+            None,
+            cancel,
+        )
+        .unwrap();
+
+        let restarting = watching.join().unwrap();
+        assert_eq!(restarting.status, Status::Restarting);
+        assert!(restarting.last_error.is_some());
+        assert_eq!(state.lock().unwrap().health.status, Status::Stopped);
+    }
 }
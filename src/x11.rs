@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use std::time::SystemTime;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ConnectionExt, ImageFormat, ImageOrder, MapState, Window as XWindow,
+};
+use x11rb::rust_connection::RustConnection;
+
+use crate::capture::{jpeg_metrohash, rgb_to_jpeg, CaptureBackend};
+use crate::diff::fingerprint;
+use crate::types::{Window, WindowBounds};
+
+/// Captures top-level windows from an EWMH-compliant X11 window manager.
+///
+/// Windows are captured directly with `GetImage`, so without a compositor any parts covered by
+/// other windows come back as whatever the X server has lying around.
+pub struct X11Backend {
+    connection: RustConnection,
+    screen: usize,
+    root: XWindow,
+    atoms: Atoms,
+}
+
+struct Atoms {
+    net_client_list: Atom,
+    net_client_list_stacking: Atom,
+    net_wm_name: Atom,
+    utf8_string: Atom,
+}
+
+struct WindowHandle {
+    id: XWindow,
+    title: String,
+}
+
+impl X11Backend {
+    /// Connects to the display named by `$DISPLAY`.
+    pub fn connect() -> Result<Self> {
+        let (connection, screen) = x11rb::connect(None)?;
+        let root = connection.setup().roots[screen].root;
+        let atoms = Atoms {
+            net_client_list: intern(&connection, b"_NET_CLIENT_LIST")?,
+            net_client_list_stacking: intern(&connection, b"_NET_CLIENT_LIST_STACKING")?,
+            net_wm_name: intern(&connection, b"_NET_WM_NAME")?,
+            utf8_string: intern(&connection, b"UTF8_STRING")?,
+        };
+        Ok(Self {
+            connection,
+            screen,
+            root,
+            atoms,
+        })
+    }
+
+    fn window_list(&self, atom: Atom) -> Result<Vec<XWindow>> {
+        let reply = self
+            .connection
+            .get_property(false, self.root, atom, AtomEnum::WINDOW, 0, u32::MAX)?
+            .reply()?;
+        Ok(reply
+            .value32()
+            .map(|values| values.collect())
+            .unwrap_or_default())
+    }
+
+    fn title(&self, window: XWindow) -> Result<String> {
+        let reply = self
+            .connection
+            .get_property(
+                false,
+                window,
+                self.atoms.net_wm_name,
+                self.atoms.utf8_string,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        if !reply.value.is_empty() {
+            return Ok(String::from_utf8_lossy(&reply.value).into_owned());
+        }
+
+        // Not everything sets the EWMH name, so fall back to ICCCM's Latin-1 one
+        let reply = self
+            .connection
+            .get_property(
+                false,
+                window,
+                AtomEnum::WM_NAME,
+                AtomEnum::STRING,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        Ok(reply.value.iter().map(|&c| c as char).collect())
+    }
+
+    /// The class half of `WM_CLASS`, which is the closest X has to an application name.
+    fn app(&self, window: XWindow) -> Result<Option<String>> {
+        let reply = self
+            .connection
+            .get_property(
+                false,
+                window,
+                AtomEnum::WM_CLASS,
+                AtomEnum::STRING,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        Ok(reply
+            .value
+            .split(|&c| c == 0)
+            .nth(1)
+            .filter(|class| !class.is_empty())
+            .map(|class| String::from_utf8_lossy(class).into_owned()))
+    }
+
+    fn bounds(&self, window: XWindow) -> Result<WindowBounds> {
+        let geometry = self.connection.get_geometry(window)?.reply()?;
+        // Geometry is relative to the parent, which is usually a window manager frame
+        let origin = self
+            .connection
+            .translate_coordinates(window, self.root, 0, 0)?
+            .reply()?;
+        Ok(WindowBounds {
+            x: origin.dst_x as i32,
+            y: origin.dst_y as i32,
+            width: geometry.width as u32,
+            height: geometry.height as u32,
+        })
+    }
+
+    fn is_viewable(&self, window: XWindow) -> Result<bool> {
+        let attributes = self.connection.get_window_attributes(window)?.reply()?;
+        Ok(attributes.map_state == MapState::VIEWABLE)
+    }
+
+    fn screenshot(&self, window: XWindow, bounds: &WindowBounds) -> Result<Vec<u8>> {
+        let image = self
+            .connection
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                window,
+                0,
+                0,
+                bounds.width as u16,
+                bounds.height as u16,
+                !0,
+            )?
+            .reply()?;
+
+        let setup = self.connection.setup();
+        let bits_per_pixel = setup
+            .pixmap_formats
+            .iter()
+            .find(|f| f.depth == image.depth)
+            .map(|f| f.bits_per_pixel)
+            .ok_or_else(|| anyhow!("No pixmap format for depth {}", image.depth))?;
+        if bits_per_pixel != 32 {
+            return Err(anyhow!(
+                "Expected screenshot to be 32 bits per pixel, got {}",
+                bits_per_pixel
+            ));
+        }
+
+        let (width, height) = (bounds.width as usize, bounds.height as usize);
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for pixel in image.data.chunks_exact(4).take(width * height) {
+            if setup.image_byte_order == ImageOrder::LSB_FIRST {
+                rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            } else {
+                rgb.extend_from_slice(&[pixel[1], pixel[2], pixel[3]]);
+            }
+        }
+        rgb_to_jpeg(&rgb, width as u32, height as u32)
+    }
+}
+
+impl CaptureBackend for X11Backend {
+    fn get_windows(&mut self) -> Result<Vec<Window>> {
+        let clients = self.window_list(self.atoms.net_client_list)?;
+        // Bottom-to-top, which is exactly what we want for z. Some window managers don't bother
+        // with it though, in which case mapping order is the best we can do.
+        let mut stacking = self.window_list(self.atoms.net_client_list_stacking)?;
+        if stacking.is_empty() {
+            stacking = clients.clone();
+        }
+
+        let mut windows = Vec::new();
+        for id in stacking {
+            // Windows that close part way through are skipped, like below
+            if !clients.contains(&id) || !self.is_viewable(id).unwrap_or(false) {
+                continue;
+            }
+
+            let Ok(title) = self.title(id) else {
+                continue;
+            };
+            if title.is_empty() {
+                continue;
+            }
+
+            windows.push(WindowHandle { id, title });
+        }
+
+        let mut screenshots = Vec::new();
+        for (z, window) in windows.into_iter().enumerate() {
+            // The window can disappear between listing it and capturing it
+            let Ok(bounds) = self.bounds(window.id) else {
+                continue;
+            };
+            let Ok(jpeg) = self.screenshot(window.id, &bounds) else {
+                continue;
+            };
+            let hash = jpeg_metrohash(&jpeg);
+            let fingerprint = fingerprint(&jpeg)?;
+            screenshots.push(Window {
+                id: window.id,
+                title: window.title,
+                app: self.app(window.id).ok().flatten(),
+                bounds,
+                display_id: Some(self.screen as u32),
+                captured_at: SystemTime::now(),
+                jpeg,
+                jpeg_metrohash: hash,
+                perceptual_hash: fingerprint.hash,
+                tiles: fingerprint.tiles,
+                z,
+            });
+        }
+        Ok(screenshots)
+    }
+}
+
+fn intern(connection: &RustConnection, name: &[u8]) -> Result<Atom> {
+    Ok(connection.intern_atom(false, name)?.reply()?.atom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11rb::protocol::xproto::{CreateWindowAux, PropMode, WindowClass};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    // Needs an X server, e.g. `xvfb-run cargo test x11`. There's no window manager under plain
+    // Xvfb, so the test adds its window to the client list itself.
+    #[test]
+    fn captures_a_mapped_window() {
+        if std::env::var_os("DISPLAY").is_none() {
+            eprintln!("Skipping, DISPLAY isn't set");
+            return;
+        }
+
+        let (connection, screen) = x11rb::connect(None).unwrap();
+        let screen = &connection.setup().roots[screen];
+        let id = connection.generate_id().unwrap();
+        connection
+            .create_window(
+                x11rb::COPY_DEPTH_FROM_PARENT,
+                id,
+                screen.root,
+                10,
+                20,
+                64,
+                48,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                screen.root_visual,
+                &CreateWindowAux::new().background_pixel(screen.white_pixel),
+            )
+            .unwrap();
+        let net_wm_name = intern(&connection, b"_NET_WM_NAME").unwrap();
+        let utf8_string = intern(&connection, b"UTF8_STRING").unwrap();
+        let net_client_list = intern(&connection, b"_NET_CLIENT_LIST").unwrap();
+        connection
+            .change_property8(
+                PropMode::REPLACE,
+                id,
+                net_wm_name,
+                utf8_string,
+                "elephant test".as_bytes(),
+            )
+            .unwrap();
+        connection
+            .change_property8(
+                PropMode::REPLACE,
+                id,
+                AtomEnum::WM_CLASS,
+                AtomEnum::STRING,
+                b"elephant\0Elephant\0",
+            )
+            .unwrap();
+        connection.map_window(id).unwrap();
+        connection
+            .change_property32(
+                PropMode::APPEND,
+                screen.root,
+                net_client_list,
+                AtomEnum::WINDOW,
+                &[id],
+            )
+            .unwrap();
+        connection.sync().unwrap();
+
+        let windows = X11Backend::connect().unwrap().get_windows().unwrap();
+        connection.destroy_window(id).unwrap();
+        connection.sync().unwrap();
+
+        let window = windows.iter().find(|w| w.id == id).unwrap();
+        assert_eq!(window.title, "elephant test");
+        assert_eq!(window.app.as_deref(), Some("Elephant"));
+        assert_eq!(
+            (
+                window.bounds.x,
+                window.bounds.y,
+                window.bounds.width,
+                window.bounds.height
+            ),
+            (10, 20, 64, 48)
+        );
+        let image = image::load_from_memory(&window.jpeg).unwrap().to_luma8();
+        assert_eq!(image.dimensions(), (64, 48));
+        assert!(image.get_pixel(32, 24).0[0] > 200);
+    }
+}